@@ -0,0 +1,12 @@
+CREATE TABLE users (
+    user_uuid TEXT NOT NULL PRIMARY KEY,
+    user_email TEXT NOT NULL UNIQUE,
+    user_state TEXT NOT NULL,
+    last_login BIGINT NOT NULL,
+    user_claims TEXT NOT NULL
+);
+
+CREATE TABLE credentails (
+    user_uuid TEXT NOT NULL PRIMARY KEY REFERENCES users (user_uuid) ON DELETE CASCADE,
+    credentail TEXT NOT NULL
+);
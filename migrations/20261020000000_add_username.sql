@@ -0,0 +1,8 @@
+-- Emails are compared in canonical form from now on. SqlRepo::migrate
+-- rewrites them in Rust before this file runs, SQL LOWER can't do NFKC.
+
+ALTER TABLE users ADD COLUMN username TEXT;
+ALTER TABLE users ADD COLUMN username_skeleton TEXT;
+
+CREATE UNIQUE INDEX users_username ON users (username);
+CREATE UNIQUE INDEX users_username_skeleton ON users (username_skeleton);
@@ -0,0 +1,4 @@
+-- Verified phone numbers in E.164, used for SMS codes and phone login.
+ALTER TABLE users ADD COLUMN phone_number TEXT;
+
+CREATE UNIQUE INDEX users_phone_number ON users (phone_number);
@@ -0,0 +1,191 @@
+use crate::model::token::{AuthAssurance, Token, TokenAuthType, TokenClaims};
+use crate::model::claims::ClaimsUserType;
+
+use std::fmt;
+use actix_web::{
+    error::ResponseError,
+    HttpRequest,
+    HttpResponse,
+    http::{header, header::ContentType, StatusCode}
+};
+
+// Shared bearer token checks for handlers that act on a user's own data.
+// Each handler error enum converts from AuthError.
+
+pub enum AuthError {
+    NoToken,
+    MalformedRequest,
+    NotAuthorized,
+}
+
+// The token's authentication is too old, or too weak for a user with MFA,
+// for what was asked. A new one comes from /reauth. Handler errors that can
+// ask for a step up wrap it and answer with its response.
+#[derive(Debug)]
+pub struct StepUpRequired {
+    // seconds
+    pub max_age: i64,
+    pub needs_mfa: bool,
+}
+
+impl fmt::Display for StepUpRequired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StepUpRequired")
+    }
+}
+
+impl ResponseError for StepUpRequired {
+    fn error_response(&self) -> HttpResponse {
+        // RFC 9470
+        let acr_values = if self.needs_mfa { ", acr_values=\"aal2\"" } else { "" };
+
+        HttpResponse::build(self.status_code())
+        .insert_header((header::WWW_AUTHENTICATE, format!("Bearer error=\"insufficient_user_authentication\"{}, max_age={}", acr_values, self.max_age)))
+        .insert_header(ContentType::json())
+        .body(self.to_string())
+    }
+
+    fn status_code(&self) -> StatusCode {
+        return StatusCode::UNAUTHORIZED;
+    }
+}
+
+// Claims of a valid token that has finished login (Full), same checks as
+// get_hidden.
+pub fn full_token_claims(req: &HttpRequest) -> Result<TokenClaims, AuthError> {
+    return token_claims(req, &[TokenAuthType::Full]);
+}
+
+// Claims of a token still waiting for the second factor.
+pub fn mfa_token_claims(req: &HttpRequest) -> Result<TokenClaims, AuthError> {
+    return token_claims(req, &[TokenAuthType::RequiresMFA]);
+}
+
+// Either, for sending codes that serve both login and /reauth.
+pub fn mfa_or_full_token_claims(req: &HttpRequest) -> Result<TokenClaims, AuthError> {
+    return token_claims(req, &[TokenAuthType::RequiresMFA, TokenAuthType::Full]);
+}
+
+fn token_claims(req: &HttpRequest, auth_types: &[TokenAuthType]) -> Result<TokenClaims, AuthError> {
+
+    let auth_header = match req.headers().get(header::AUTHORIZATION) {
+        Some(auth_header) => auth_header,
+        None => return Err(AuthError::NoToken),
+    };
+
+    let auth_string = auth_header.to_str().map_err(|_| AuthError::MalformedRequest)?;
+
+    let mut token = Token::new_from_authorization(auth_string.to_string());
+
+    if token.validate_jwt_token().is_err() {
+        return Err(AuthError::NotAuthorized);
+    }
+
+    let claims = match token.claims {
+        Some(claims) => claims,
+        None => return Err(AuthError::MalformedRequest),
+    };
+
+    if !auth_types.contains(&claims.auth_type) {
+        return Err(AuthError::NotAuthorized);
+    }
+
+    return Ok(claims);
+}
+
+// Users may act on themselves, Admins on anyone.
+pub fn self_or_admin(claims: &TokenClaims, user_uuid: &str) -> Result<(), AuthError> {
+
+    if claims.sub == user_uuid || claims.user_claim.user_type == ClaimsUserType::Admin {
+        return Ok(());
+    }
+
+    return Err(AuthError::NotAuthorized);
+}
+
+// For sensitive operations: the user has to have authenticated, at login or
+// /reauth, within the last max_age_minutes, and with MFA if they have any.
+pub fn require_recent_auth(claims: &TokenClaims, has_mfa: bool, max_age_minutes: i64) -> Result<(), StepUpRequired> {
+
+    let age = chrono::Utc::now().timestamp() - claims.auth_time;
+
+    if (!has_mfa || claims.acr == AuthAssurance::Aal2) && age <= max_age_minutes * 60 {
+        return Ok(());
+    }
+
+    return Err(StepUpRequired { max_age: max_age_minutes * 60, needs_mfa: has_mfa });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::claims::Claims;
+
+    fn claims(acr: AuthAssurance, age_seconds: i64) -> TokenClaims {
+        let now = chrono::Utc::now().timestamp();
+
+        return TokenClaims {
+            sub: "5f0c7e52-3c1b-4a8e-9d55-0b8c1f1e2a10".to_owned(),
+            token_uuid: "b8a0f2d4-6c1e-4f3a-9e7d-2a5b8c0d1e3f".to_owned(),
+            user_claim: Claims {
+                user_type: ClaimsUserType::User,
+                user_uuid: "5f0c7e52-3c1b-4a8e-9d55-0b8c1f1e2a10".to_owned(),
+                user_name: "bob@example.org".to_owned(),
+                group_uuid: Vec::new(),
+            },
+            exp: now + 3600,
+            iat: now,
+            nbf: now,
+            auth_type: TokenAuthType::Full,
+            profile: Default::default(),
+            auth_time: now - age_seconds,
+            amr: Vec::new(),
+            acr,
+        };
+    }
+
+    #[test]
+    fn recent_mfa_passes() {
+        assert!(require_recent_auth(&claims(AuthAssurance::Aal2, 60), true, 10).is_ok());
+    }
+
+    #[test]
+    fn users_with_mfa_need_aal2() {
+        let step_up = require_recent_auth(&claims(AuthAssurance::Aal1, 60), true, 10).unwrap_err();
+
+        assert!(step_up.needs_mfa);
+        assert_eq!(step_up.max_age, 600);
+    }
+
+    #[test]
+    fn users_without_mfa_only_need_a_recent_login() {
+        assert!(require_recent_auth(&claims(AuthAssurance::Aal1, 60), false, 10).is_ok());
+
+        let step_up = require_recent_auth(&claims(AuthAssurance::Aal1, 601), false, 10).unwrap_err();
+        assert!(!step_up.needs_mfa);
+    }
+
+    #[test]
+    fn old_authentication_needs_a_step_up() {
+        assert!(require_recent_auth(&claims(AuthAssurance::Aal2, 601), true, 10).is_err());
+        // tokens from before auth_time was recorded
+        assert!(require_recent_auth(&claims(AuthAssurance::Aal2, chrono::Utc::now().timestamp()), true, 10).is_err());
+    }
+
+    #[test]
+    fn step_up_answers_with_rfc_9470() {
+        let response = StepUpRequired { max_age: 600, needs_mfa: true }.error_response();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            "Bearer error=\"insufficient_user_authentication\", acr_values=\"aal2\", max_age=600"
+        );
+
+        let response = StepUpRequired { max_age: 600, needs_mfa: false }.error_response();
+        assert_eq!(
+            response.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            "Bearer error=\"insufficient_user_authentication\", max_age=600"
+        );
+    }
+}
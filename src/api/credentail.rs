@@ -1,9 +1,12 @@
 use crate::model::user::UserState;
+use crate::model::username::LoginName;
 use crate::model::credentail::VarifyPasswordState;
-use crate::repo::database::mongodb::MongoRepo;
-use crate::repo::database::base::Database;
-use crate::model::token::{Token, TokenAuthType};
+use crate::repo::database::base::{Database, DatabaseError};
+use crate::model::token::{AuthMethod, Authentication, Token, TokenAuthType};
+use crate::config::Config;
+use crate::metrics::LOGIN_OUTCOMES;
 
+use bson::DateTime;
 use actix_web::{
     post,
     error::ResponseError,
@@ -26,14 +29,28 @@ pub enum PasswordError {
     ServerError,
     BadRequest,
     UserDoesntExist,
+    ServiceUnavailable,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct PasswordPost{
+    // the email or the username
     user_name: String,
     password: String,
 }
 
+impl From<DatabaseError> for PasswordError {
+    fn from(error: DatabaseError) -> PasswordError {
+        tracing::error!(?error, "database failure");
+
+        if error.is_transient() {
+            return PasswordError::ServiceUnavailable;
+        }
+
+        return PasswordError::ServerError;
+    }
+}
+
 impl ResponseError for PasswordError {
     fn error_response(&self) -> HttpResponse {
         HttpResponse::build(self.status_code())
@@ -48,15 +65,18 @@ impl ResponseError for PasswordError {
             PasswordError::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
             PasswordError::BadRequest => StatusCode::BAD_REQUEST,
             PasswordError::UserDoesntExist => StatusCode::NOT_FOUND,
+            PasswordError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
         }
     }
 
 }
 
 #[post("/password")]
+#[tracing::instrument(skip_all)]
 pub async fn varify_password (
     mut payload: Payload,
-    mongo_repo: Data<MongoRepo>,
+    database: Data<dyn Database>,
+    config: Data<Config>,
 ) -> Result<Json<Token>, PasswordError> {
 
     let mut body = BytesMut::new();
@@ -72,44 +92,63 @@ pub async fn varify_password (
 
     let request = obj_result.unwrap();
 
-    let user_option = mongo_repo.get_user_by_user_name(request.user_name.clone()).await;
+    let user_option = database.get_user_by_login_name(LoginName::parse(&request.user_name)).await?;
 
     if user_option.is_none() {
         return Err(PasswordError::UserDoesntExist);
     }
 
-    let mut user = user_option.unwrap();
+    let user = user_option.unwrap();
 
     if user.user_state == UserState::Disabled {
         return Err(PasswordError::AccountLocked);
     }
 
-    let credentail = mongo_repo.get_credentail(user.user_uuid.clone()).await;
+    let credentail = database.get_credentail(user.user_uuid.clone()).await?;
 
     if  credentail.is_none() {
         return Err(PasswordError::ServerError);
     }
 
-    let password_verifiaction = credentail.as_ref().unwrap().varify_password(request.password.clone());
+    let mut credentail = credentail.unwrap();
+
+    // the second factor is checked at /mfa/verify
+    let auth_type = if !credentail.has_mfa() {
+        TokenAuthType::Full
+    } else {
+        TokenAuthType::RequiresMFA
+    };
+
+    let password_verifiaction = credentail.varify_password(request.password.clone(), config.hashing.bcrypt_cost);
+
+    // the legacy hash still verifies, a failed write just retries on the next login
+    if password_verifiaction.upgraded {
+        if let Err(error) = database.update_credentail(credentail).await {
+            tracing::warn!(?error, "could not store the upgraded password hash");
+        }
+    }
+
+    LOGIN_OUTCOMES.with_label_values(&[&password_verifiaction.state.to_string()]).inc();
 
     if password_verifiaction.state == VarifyPasswordState::Failed || password_verifiaction.state == VarifyPasswordState::FailedPreviousPassword {
         return Err(PasswordError::IncorrectPassword);
     }
 
-    let token_res = Token::new(user.user_uuid.clone(), 180, user.user_claims.clone(), TokenAuthType::Full);
+    let token_res = Token::new(
+        user.user_uuid.clone(),
+        config.token.ttl_minutes,
+        user.user_claims.clone(),
+        auth_type,
+        user.profile.token_claims(&config.profile.token_claims),
+        Authentication::now(AuthMethod::Pwd),
+    );
 
     if token_res.as_ref().is_err() {
-        println!("{}", token_res.as_ref().unwrap_err());
+        tracing::error!(error = %token_res.as_ref().unwrap_err(), "could not sign token");
         return Err(PasswordError::ServerError);
     }
 
-    user.login();
-
-    let user_update = mongo_repo.update_user(user.clone()).await;
-
-    if user_update.is_err() {
-        return Err(PasswordError::ServerError);
-    }
+    database.set_last_login(user.user_uuid.clone(), DateTime::now()).await?;
 
     return Ok(Json(token_res.unwrap()));
 
@@ -0,0 +1,369 @@
+use crate::model::user::{canonical_email, valid_email, User};
+use crate::model::credentail::{CodeSlot, EmailChange, EmailRevert, UserCredentail, VarifyPasswordState};
+use crate::model::one_time_code::{CodeCheck, OneTimeCode};
+use crate::model::token::{Authentication, Token, TokenAuthType};
+use crate::repo::database::base::{Database, DatabaseError};
+use crate::api::auth::{self, AuthError, StepUpRequired};
+use crate::mailer::{MailMessage, Mailer};
+use crate::config::Config;
+
+use actix_web::{
+    post,
+    error::ResponseError,
+    web::Json,
+    web::Data,
+    HttpRequest,
+    HttpResponse,
+    http::{header::ContentType, StatusCode}
+};
+use serde::{Serialize, Deserialize};
+use strum_macros::Display;
+
+// Changing the login email. The new address has to prove it receives mail
+// before anything changes, and the old address gets a link that puts it back
+// in case the change wasn't the owner's doing.
+
+#[derive(Deserialize, Serialize)]
+pub struct EmailChangePost {
+    new_email: String,
+    password: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct EmailVerifyPost {
+    code: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct EmailRevertPost {
+    token: String,
+}
+
+#[derive(Debug, Display)]
+pub enum EmailError {
+    NoToken,
+    NotAuthorized,
+    StepUpRequired(StepUpRequired),
+    MalformedRequest,
+    IncorrectPassword,
+    InvalidEmail,
+    EmailUnchanged,
+    EmailExists,
+    NoPendingChange,
+    InvalidCode,
+    CodeExpired,
+    TooManyAttempts,
+    TooSoon(i64),
+    NotFound,
+    ServerFailure,
+    ServiceUnavailable,
+}
+
+impl From<AuthError> for EmailError {
+    fn from(error: AuthError) -> EmailError {
+        match error {
+            AuthError::NoToken => return EmailError::NoToken,
+            AuthError::NotAuthorized => return EmailError::NotAuthorized,
+            AuthError::MalformedRequest => return EmailError::MalformedRequest,
+        }
+    }
+}
+
+impl From<StepUpRequired> for EmailError {
+    fn from(error: StepUpRequired) -> EmailError {
+        return EmailError::StepUpRequired(error);
+    }
+}
+
+impl From<DatabaseError> for EmailError {
+    fn from(error: DatabaseError) -> EmailError {
+
+        match error {
+            DatabaseError::UserNameExists => return EmailError::EmailExists,
+            DatabaseError::UserDoesntExist => return EmailError::NotFound,
+            _ => (),
+        }
+
+        tracing::error!(?error, "database failure");
+
+        if error.is_transient() {
+            return EmailError::ServiceUnavailable;
+        }
+
+        return EmailError::ServerFailure;
+    }
+}
+
+impl ResponseError for EmailError {
+    fn error_response(&self) -> HttpResponse {
+        if let EmailError::StepUpRequired(step_up) = self {
+            return step_up.error_response();
+        }
+
+        let mut response = HttpResponse::build(self.status_code());
+
+        if let EmailError::TooSoon(seconds) = self {
+            response.insert_header(("Retry-After", seconds.to_string()));
+        }
+
+        response
+        .insert_header(ContentType::json())
+        .body(self.to_string())
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            EmailError::NoToken => StatusCode::UNAUTHORIZED,
+            EmailError::NotAuthorized => StatusCode::FORBIDDEN,
+            EmailError::StepUpRequired(step_up) => step_up.status_code(),
+            EmailError::MalformedRequest => StatusCode::BAD_REQUEST,
+            EmailError::IncorrectPassword => StatusCode::FORBIDDEN,
+            EmailError::InvalidEmail => StatusCode::UNPROCESSABLE_ENTITY,
+            EmailError::EmailUnchanged => StatusCode::UNPROCESSABLE_ENTITY,
+            EmailError::EmailExists => StatusCode::CONFLICT,
+            EmailError::NoPendingChange => StatusCode::NOT_FOUND,
+            EmailError::InvalidCode => StatusCode::FORBIDDEN,
+            EmailError::CodeExpired => StatusCode::GONE,
+            EmailError::TooManyAttempts => StatusCode::TOO_MANY_REQUESTS,
+            EmailError::TooSoon(_) => StatusCode::TOO_MANY_REQUESTS,
+            EmailError::NotFound => StatusCode::NOT_FOUND,
+            EmailError::ServerFailure => StatusCode::INTERNAL_SERVER_ERROR,
+            EmailError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+}
+
+async fn current_user_and_credentail(req: &HttpRequest, database: &Data<dyn Database>) -> Result<(User, UserCredentail), EmailError> {
+
+    let claims = auth::full_token_claims(req)?;
+
+    let user = database.get_user(claims.sub.clone()).await?;
+
+    if user.is_none() {
+        return Err(EmailError::NotFound);
+    }
+
+    let credentail = database.get_credentail(claims.sub).await?;
+
+    if credentail.is_none() {
+        return Err(EmailError::ServerFailure);
+    }
+
+    return Ok((user.unwrap(), credentail.unwrap()));
+}
+
+async fn send(mailer: &Data<dyn Mailer>, message: MailMessage) -> Result<(), EmailError> {
+
+    if let Err(error) = mailer.send(message).await {
+        tracing::error!(%error, "could not send mail");
+        return Err(EmailError::ServerFailure);
+    }
+
+    return Ok(());
+}
+
+// Starts a change: checks the password again, since a leaked token alone
+// shouldn't be enough to take the account, and mails a code to new_email.
+// Asking again replaces any change that was already pending, once the resend
+// cooldown is over.
+#[post("/me/email")]
+#[tracing::instrument(skip_all)]
+pub async fn request_email_change(
+    request: Json<EmailChangePost>,
+    database: Data<dyn Database>,
+    mailer: Data<dyn Mailer>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, EmailError> {
+
+    let request = request.into_inner();
+    let new_email = canonical_email(&request.new_email);
+
+    let claims = auth::full_token_claims(&req)?;
+
+    let (user, mut credentail) = current_user_and_credentail(&req, &database).await?;
+
+    auth::require_recent_auth(&claims, credentail.has_mfa(), config.token.step_up_max_age_minutes)?;
+
+    let password_verifiaction = credentail.varify_password(request.password, config.hashing.bcrypt_cost);
+
+    if password_verifiaction.state == VarifyPasswordState::Failed || password_verifiaction.state == VarifyPasswordState::FailedPreviousPassword {
+        return Err(EmailError::IncorrectPassword);
+    }
+
+    if !valid_email(&new_email) {
+        return Err(EmailError::InvalidEmail);
+    }
+
+    if new_email == user.user_email {
+        return Err(EmailError::EmailUnchanged);
+    }
+
+    if let Some(pending) = &credentail.email_change {
+        let wait = pending.code.seconds_until_resend(config.email.code_resend_cooldown_seconds);
+
+        if wait > 0 {
+            return Err(EmailError::TooSoon(wait));
+        }
+    }
+
+    // the unique index has the final say when the change is verified, this
+    // just avoids mailing a code that can never be used
+    if database.get_user_by_email(new_email.clone()).await?.is_some() {
+        return Err(EmailError::EmailExists);
+    }
+
+    let (code, stored) = OneTimeCode::numeric(6, config.email.code_ttl_minutes * 60);
+
+    credentail.email_change = Some(EmailChange { new_email: new_email.clone(), code: stored });
+
+    database.update_credentail(credentail).await?;
+
+    send(&mailer, MailMessage {
+        to: new_email,
+        subject: "Confirm your new email address".to_owned(),
+        body: format!(
+            "Enter this code to confirm the change of your login email:\n\n    {}\n\nIt expires in {} minutes. If you didn't ask for this you can ignore this message.",
+            code, config.email.code_ttl_minutes
+        ),
+    }).await?;
+
+    return Ok(HttpResponse::Accepted().finish());
+
+}
+
+// Finishes a change with the mailed code. The user and credentail are
+// written together so the email, user_claims and the revert token can't end
+// up out of step. Returns a new token since the old one names the old email.
+#[post("/me/email/verify")]
+#[tracing::instrument(skip_all)]
+pub async fn verify_email_change(
+    request: Json<EmailVerifyPost>,
+    database: Data<dyn Database>,
+    mailer: Data<dyn Mailer>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<Json<Token>, EmailError> {
+
+    let claims = auth::full_token_claims(&req)?;
+
+    let (mut user, mut credentail) = current_user_and_credentail(&req, &database).await?;
+
+    let change = match credentail.email_change.take() {
+        Some(change) => change,
+        None => return Err(EmailError::NoPendingChange),
+    };
+
+    let attempt_counted = database.take_code_attempt(
+        user.user_uuid.clone(),
+        CodeSlot::EmailChange,
+        change.code.issued_at,
+        config.email.code_max_attempts
+    ).await?;
+
+    // a failure leaves the pending change as it is, the attempt is counted
+    // already. An expired or used up one is replaced by asking again.
+    match change.code.check(&request.code, attempt_counted) {
+        CodeCheck::Valid => (),
+        CodeCheck::Invalid => return Err(EmailError::InvalidCode),
+        CodeCheck::Expired => return Err(EmailError::CodeExpired),
+        CodeCheck::TooManyAttempts => return Err(EmailError::TooManyAttempts),
+    }
+
+    // a parallel request with the same code got there first
+    if !database.spend_code(user.user_uuid.clone(), CodeSlot::EmailChange, change.code.issued_at).await? {
+        return Err(EmailError::NoPendingChange);
+    }
+
+    let old_email = user.user_email.clone();
+    let (revert_token, stored) = OneTimeCode::token(config.email.revert_ttl_hours * 3600);
+
+    user.set_email(change.new_email.clone());
+    credentail.email_revert = Some(EmailRevert { old_email: old_email.clone(), token: stored });
+
+    let user = database.update_user_with_credentail(user, credentail).await?;
+
+    send(&mailer, MailMessage {
+        to: old_email,
+        subject: "Your login email was changed".to_owned(),
+        body: format!(
+            "The login email of your account was changed to {}.\n\nIf this wasn't you, open this link to change it back:\n\n    {}/email/revert?token={}.{}\n\nThe link works for {} hours.",
+            change.new_email, config.mail.link_base_url, user.user_uuid, revert_token, config.email.revert_ttl_hours
+        ),
+    }).await?;
+
+    let token_res = Token::new(
+        user.user_uuid.clone(),
+        config.token.ttl_minutes,
+        user.user_claims.clone(),
+        TokenAuthType::Full,
+        user.profile.token_claims(&config.profile.token_claims),
+        // the user didn't authenticate again, the new token is only for the new email
+        Authentication::from_claims(&claims),
+    );
+
+    if token_res.as_ref().is_err() {
+        tracing::error!(error = %token_res.as_ref().unwrap_err(), "could not sign token");
+        return Err(EmailError::ServerFailure);
+    }
+
+    return Ok(Json(token_res.unwrap()));
+
+}
+
+// The link mailed to the old address. It carries the user uuid in front of
+// the secret so no token is needed, whoever changed the email may be the
+// one holding the account now.
+#[post("/email/revert")]
+#[tracing::instrument(skip_all)]
+pub async fn revert_email_change(
+    request: Json<EmailRevertPost>,
+    database: Data<dyn Database>,
+    config: Data<Config>,
+) -> Result<HttpResponse, EmailError> {
+
+    let (user_uuid, secret) = match request.token.split_once('.') {
+        Some(parts) => parts,
+        None => return Err(EmailError::MalformedRequest),
+    };
+
+    let user = database.get_user(user_uuid.to_owned()).await?;
+    let credentail = database.get_credentail(user_uuid.to_owned()).await?;
+
+    let (mut user, mut credentail) = match (user, credentail) {
+        (Some(user), Some(credentail)) => (user, credentail),
+        _ => return Err(EmailError::InvalidCode),
+    };
+
+    let revert = match credentail.email_revert.take() {
+        Some(revert) => revert,
+        None => return Err(EmailError::InvalidCode),
+    };
+
+    let attempt_counted = database.take_code_attempt(
+        user.user_uuid.clone(),
+        CodeSlot::EmailRevert,
+        revert.token.issued_at,
+        config.email.code_max_attempts
+    ).await?;
+
+    match revert.token.check(secret, attempt_counted) {
+        CodeCheck::Valid => (),
+        CodeCheck::Invalid => return Err(EmailError::InvalidCode),
+        CodeCheck::Expired => return Err(EmailError::CodeExpired),
+        CodeCheck::TooManyAttempts => return Err(EmailError::TooManyAttempts),
+    }
+
+    if !database.spend_code(user.user_uuid.clone(), CodeSlot::EmailRevert, revert.token.issued_at).await? {
+        return Err(EmailError::InvalidCode);
+    }
+
+    user.set_email(revert.old_email);
+    credentail.email_change = None;
+
+    database.update_user_with_credentail(user, credentail).await?;
+
+    return Ok(HttpResponse::NoContent().finish());
+
+}
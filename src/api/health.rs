@@ -0,0 +1,78 @@
+use crate::repo::database::base::Database;
+use crate::model::token::Token;
+
+use std::collections::BTreeMap;
+use actix_web::{
+    get,
+    web::Data,
+    HttpResponse,
+};
+use serde::{Serialize, Deserialize};
+use strum_macros::Display;
+
+#[derive(Debug, Display, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Up,
+    Down,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ComponentHealth {
+    status: HealthStatus,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HealthReport {
+    status: HealthStatus,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    components: BTreeMap<String, ComponentHealth>,
+}
+
+// Liveness only says the process is serving requests, it deliberately
+// doesn't touch the database so an outage doesn't get the pod restarted.
+#[get("/health/live")]
+#[tracing::instrument(skip_all)]
+pub async fn get_live() -> HttpResponse {
+    return HttpResponse::Ok().json(HealthReport {
+        status: HealthStatus::Up,
+        components: BTreeMap::new(),
+    });
+}
+
+#[get("/health/ready")]
+#[tracing::instrument(skip_all)]
+pub async fn get_ready(
+    database: Data<dyn Database>,
+) -> HttpResponse {
+
+    let mut components = BTreeMap::new();
+
+    // the endpoint is unauthenticated, failure details only go to the log
+    let database_health = match database.health_check().await {
+        Ok(_) => ComponentHealth { status: HealthStatus::Up },
+        Err(error) => {
+            tracing::warn!("database is not ready: {:?}", error);
+            ComponentHealth { status: HealthStatus::Down }
+        },
+    };
+
+    components.insert("database".to_owned(), database_health);
+
+    let keys_health = match Token::signing_keys_loaded() {
+        true => ComponentHealth { status: HealthStatus::Up },
+        false => {
+            tracing::warn!("signing keys could not be parsed");
+            ComponentHealth { status: HealthStatus::Down }
+        },
+    };
+
+    components.insert("signing_keys".to_owned(), keys_health);
+
+    if components.values().all(|component| component.status == HealthStatus::Up) {
+        return HttpResponse::Ok().json(HealthReport { status: HealthStatus::Up, components });
+    }
+
+    return HttpResponse::ServiceUnavailable().json(HealthReport { status: HealthStatus::Down, components });
+
+}
@@ -42,6 +42,7 @@ impl ResponseError for GetHiddenError {
 
 
 #[get("/hidden/{something}")]
+#[tracing::instrument(skip_all)]
 pub async fn get_hidden(
         something: Path<HiddenPath>,
         req: HttpRequest
@@ -0,0 +1,254 @@
+use crate::model::user::UserState;
+use crate::model::username::LoginName;
+use crate::model::credentail::{CodeSlot, MagicLink};
+use crate::model::one_time_code::{CodeCheck, OneTimeCode};
+use crate::model::token::{AuthMethod, Authentication, Token, TokenAuthType};
+use crate::repo::database::base::{Database, DatabaseError};
+use crate::mailer::{MailMessage, Mailer};
+use crate::config::Config;
+use crate::metrics::MAGIC_LINK_OUTCOMES;
+
+use bson::DateTime;
+use actix_web::{
+    post,
+    cookie::{Cookie, SameSite, time::Duration},
+    error::ResponseError,
+    web::Json,
+    web::Data,
+    HttpRequest,
+    HttpResponse,
+    http::{header::ContentType, StatusCode}
+};
+use serde::{Serialize, Deserialize};
+use strum_macros::Display;
+
+// Passwordless login. POST /login/magic mails a link and sets a cookie on
+// the browser that asked, the callback needs both. A link read off the wire
+// or out of a forwarded mail is useless without the cookie.
+
+const BINDING_COOKIE: &str = "magic_link_binding";
+
+#[derive(Deserialize, Serialize)]
+pub struct MagicLinkPost {
+    // the email or the username
+    user_name: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MagicLinkCallback {
+    token: String,
+}
+
+#[derive(Debug, Display)]
+pub enum MagicLinkError {
+    MalformedRequest,
+    InvalidLink,
+    LinkExpired,
+    TooManyAttempts,
+    WrongBrowser,
+    AccountLocked,
+    ServerFailure,
+    ServiceUnavailable,
+}
+
+impl From<DatabaseError> for MagicLinkError {
+    fn from(error: DatabaseError) -> MagicLinkError {
+        tracing::error!(?error, "database failure");
+
+        if error.is_transient() {
+            return MagicLinkError::ServiceUnavailable;
+        }
+
+        return MagicLinkError::ServerFailure;
+    }
+}
+
+impl ResponseError for MagicLinkError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+        .insert_header(ContentType::json())
+        .body(self.to_string())
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            MagicLinkError::MalformedRequest => StatusCode::BAD_REQUEST,
+            MagicLinkError::InvalidLink => StatusCode::FORBIDDEN,
+            MagicLinkError::LinkExpired => StatusCode::GONE,
+            MagicLinkError::TooManyAttempts => StatusCode::TOO_MANY_REQUESTS,
+            MagicLinkError::WrongBrowser => StatusCode::FORBIDDEN,
+            MagicLinkError::AccountLocked => StatusCode::LOCKED,
+            MagicLinkError::ServerFailure => StatusCode::INTERNAL_SERVER_ERROR,
+            MagicLinkError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+}
+
+// Answers 202 with a cookie whether or not the user exists, so the endpoint
+// can't be used to find out which accounts do. Asking again replaces the
+// previous link, unless it was sent less than resend_cooldown_seconds ago:
+// then nothing is mailed and the browser keeps the binding it has.
+#[post("/login/magic")]
+#[tracing::instrument(skip_all)]
+pub async fn request_magic_link(
+    request: Json<MagicLinkPost>,
+    database: Data<dyn Database>,
+    mailer: Data<dyn Mailer>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, MagicLinkError> {
+
+    let ttl_seconds = config.magic_link.ttl_minutes * 60;
+
+    let (token, token_code) = OneTimeCode::token(ttl_seconds);
+    let (mut binding, binding_code) = OneTimeCode::token(ttl_seconds);
+
+    let user = database.get_user_by_login_name(LoginName::parse(&request.user_name)).await?;
+
+    if let Some(user) = user.filter(|user| user.user_state != UserState::Disabled) {
+
+        let credentail = database.get_credentail(user.user_uuid.clone()).await?;
+
+        let cooling_down = credentail.as_ref()
+            .and_then(|credentail| credentail.magic_link.as_ref())
+            .is_some_and(|link| link.token.seconds_until_resend(config.magic_link.resend_cooldown_seconds) > 0);
+
+        if cooling_down {
+            if let Some(existing) = req.cookie(BINDING_COOKIE) {
+                binding = existing.value().to_owned();
+            }
+        }
+
+        if let Some(mut credentail) = credentail.filter(|_| !cooling_down) {
+
+            credentail.magic_link = Some(MagicLink { token: token_code, binding: binding_code });
+
+            database.update_credentail(credentail).await?;
+
+            let message = MailMessage {
+                to: user.user_email,
+                subject: "Your sign in link".to_owned(),
+                body: format!(
+                    "Open this link in the same browser you asked for it from to sign in:\n\n    {}/login/magic?token={}.{}\n\nIt works once and expires in {} minutes. If you didn't ask for this you can ignore this message.",
+                    config.mail.link_base_url, user.user_uuid, token, config.magic_link.ttl_minutes
+                ),
+            };
+
+            if let Err(error) = mailer.send(message).await {
+                tracing::error!(%error, "could not send mail");
+                return Err(MagicLinkError::ServerFailure);
+            }
+        }
+    }
+
+    let cookie = Cookie::build(BINDING_COOKIE, binding)
+        .path("/login/magic")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .max_age(Duration::seconds(ttl_seconds))
+        .finish();
+
+    return Ok(HttpResponse::Accepted().cookie(cookie).finish());
+
+}
+
+// Exchanges the link for a token. The link is used up by a successful
+// login, a wrong browser only counts as an attempt so the owner can still
+// open it in the right one. Users with MFA get a RequiresMFA token.
+#[post("/login/magic/callback")]
+#[tracing::instrument(skip_all)]
+pub async fn redeem_magic_link(
+    request: Json<MagicLinkCallback>,
+    database: Data<dyn Database>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, MagicLinkError> {
+
+    let (user_uuid, secret) = match request.token.split_once('.') {
+        Some(parts) => parts,
+        None => return Err(MagicLinkError::MalformedRequest),
+    };
+
+    let binding = req.cookie(BINDING_COOKIE).map(|cookie| cookie.value().to_owned()).unwrap_or_default();
+
+    let user = database.get_user(user_uuid.to_owned()).await?;
+    let credentail = database.get_credentail(user_uuid.to_owned()).await?;
+
+    let (user, mut credentail) = match (user, credentail) {
+        (Some(user), Some(credentail)) => (user, credentail),
+        _ => return Err(MagicLinkError::InvalidLink),
+    };
+
+    let link = match credentail.magic_link.take() {
+        Some(link) => link,
+        None => return Err(MagicLinkError::InvalidLink),
+    };
+
+    let attempt_counted = database.take_code_attempt(
+        user.user_uuid.clone(),
+        CodeSlot::MagicLink,
+        link.token.issued_at,
+        config.magic_link.max_attempts
+    ).await?;
+
+    let mut outcome = link.token.check(secret, attempt_counted);
+
+    // the binding rides on the attempt counted for the token
+    let wrong_browser = outcome == CodeCheck::Valid && link.binding.check(&binding, true) != CodeCheck::Valid;
+
+    if wrong_browser {
+        MAGIC_LINK_OUTCOMES.with_label_values(&["WrongBrowser"]).inc();
+        outcome = CodeCheck::Invalid;
+    } else {
+        MAGIC_LINK_OUTCOMES.with_label_values(&[&outcome.to_string()]).inc();
+    }
+
+    match outcome {
+        CodeCheck::Valid => (),
+        CodeCheck::Invalid if wrong_browser => return Err(MagicLinkError::WrongBrowser),
+        CodeCheck::Invalid => return Err(MagicLinkError::InvalidLink),
+        CodeCheck::Expired => return Err(MagicLinkError::LinkExpired),
+        CodeCheck::TooManyAttempts => return Err(MagicLinkError::TooManyAttempts),
+    }
+
+    if user.user_state == UserState::Disabled {
+        return Err(MagicLinkError::AccountLocked);
+    }
+
+    // only one of several requests with the same link logs in
+    if !database.spend_code(user.user_uuid.clone(), CodeSlot::MagicLink, link.token.issued_at).await? {
+        return Err(MagicLinkError::InvalidLink);
+    }
+
+    let auth_type = if !credentail.has_mfa() {
+        TokenAuthType::Full
+    } else {
+        TokenAuthType::RequiresMFA
+    };
+
+    let token_res = Token::new(
+        user.user_uuid.clone(),
+        config.token.ttl_minutes,
+        user.user_claims.clone(),
+        auth_type,
+        user.profile.token_claims(&config.profile.token_claims),
+        Authentication::now(AuthMethod::Email),
+    );
+
+    if token_res.as_ref().is_err() {
+        tracing::error!(error = %token_res.as_ref().unwrap_err(), "could not sign token");
+        return Err(MagicLinkError::ServerFailure);
+    }
+
+    database.update_credentail(credentail).await?;
+    database.set_last_login(user.user_uuid.clone(), DateTime::now()).await?;
+
+    // the binding is spent along with the link
+    let mut expired = Cookie::build(BINDING_COOKIE, "").path("/login/magic").finish();
+    expired.make_removal();
+
+    return Ok(HttpResponse::Ok().cookie(expired).json(token_res.unwrap()));
+
+}
@@ -0,0 +1,45 @@
+use actix_web::{
+    get,
+    error::ResponseError,
+    HttpResponse,
+    http::{header::ContentType, StatusCode}
+};
+use prometheus::{Encoder, TextEncoder};
+use strum_macros::Display;
+
+#[derive(Debug, Display)]
+pub enum MetricsError {
+    EncodingFailed,
+}
+
+impl ResponseError for MetricsError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+        .insert_header(ContentType::json())
+        .body(self.to_string())
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            MetricsError::EncodingFailed => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+}
+
+#[get("/metrics")]
+#[tracing::instrument(skip_all)]
+pub async fn get_metrics() -> Result<HttpResponse, MetricsError> {
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+
+    if encoder.encode(&prometheus::gather(), &mut buffer).is_err() {
+        return Err(MetricsError::EncodingFailed);
+    }
+
+    return Ok(HttpResponse::Ok()
+        .insert_header(("Content-Type", encoder.format_type()))
+        .body(buffer));
+
+}
@@ -0,0 +1,880 @@
+use crate::model::user::{User, UserState};
+use crate::model::credentail::{AddMfaError, CodeSlot, SecretSlot, IssueMfaCodeError, UserCredentail, UserMfaState, VarifyMfaState, VarifyMfaStateError, VarifyPasswordState};
+use crate::model::mfa_factor::{self, MfaFactorSummary};
+use crate::model::token::{AuthMethod, Authentication, Token, TokenAuthType};
+use crate::model::totp::{self, TotpAlgorithm};
+use crate::repo::database::base::{Database, DatabaseError};
+use crate::api::auth::{self, AuthError, StepUpRequired};
+use crate::mailer::{MailMessage, Mailer};
+use crate::sms::{SmsMessage, SmsProvider};
+use crate::secrets::SecretKeys;
+use crate::config::Config;
+
+use bson::DateTime;
+use actix_web::{
+    delete,
+    get,
+    patch,
+    post,
+    error::ResponseError,
+    web::Json,
+    web::Data,
+    web::Path,
+    HttpRequest,
+    HttpResponse,
+    http::{header::ContentType, StatusCode}
+};
+use serde::{Serialize, Deserialize};
+use strum_macros::Display;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The second step of login for users with MFA, and managing the factors a
+// user has enrolled: authenticator apps (OTP), Email and SMS. A RequiresMFA
+// token from /password, /login/magic/callback or /login/phone/verify is
+// traded for a Full one at /mfa/verify, with any one of the factors. /reauth
+// gives a Full token a fresh auth_time for operations that need a recent
+// login.
+
+// factor_id comes from GET /mfa/factors, the preferred factor is used
+// without it.
+#[derive(Deserialize, Serialize)]
+pub struct MfaVerifyPost {
+    code: String,
+    #[serde(default)]
+    factor_id: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MfaConfirmPost {
+    code: String,
+    // what the factor is listed as, defaults to its type
+    #[serde(default)]
+    label: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MfaFactorPatch {
+    #[serde(default)]
+    label: Option<String>,
+    // true makes it the factor login asks for first
+    #[serde(default)]
+    preferred: bool,
+}
+
+// code and factor_id as for /mfa/verify, code is needed when the user has
+// MFA.
+#[derive(Deserialize, Serialize)]
+pub struct ReauthPost {
+    password: String,
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    factor_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct FactorId {
+    factor_id: String,
+}
+
+// What an authenticator app needs, uri is for a QR code and the rest for
+// typing it in by hand.
+#[derive(Deserialize, Serialize)]
+pub struct TotpEnrollmentResponse {
+    secret: String,
+    uri: String,
+    algorithm: TotpAlgorithm,
+    digits: usize,
+    period: u64,
+}
+
+#[derive(Debug, Display)]
+pub enum MfaError {
+    NoToken,
+    NotAuthorized,
+    StepUpRequired(StepUpRequired),
+    MalformedRequest,
+    NotConfigured,
+    WrongMfaType,
+    AlreadyEnrolled,
+    FactorNotFound,
+    InvalidLabel,
+    NoPhoneNumber,
+    NoCodeIssued,
+    InvalidCode,
+    // OTP code whose time step was already used, wait for the next one
+    CodeAlreadyUsed,
+    // the factor is the same kind as the login, e.g. SMS after a phone login
+    MethodAlreadyUsed,
+    CodeExpired,
+    TooManyAttempts,
+    TooSoon(i64),
+    IncorrectPassword,
+    AccountLocked,
+    NotFound,
+    ServerFailure,
+    ServiceUnavailable,
+}
+
+impl From<AuthError> for MfaError {
+    fn from(error: AuthError) -> MfaError {
+        match error {
+            AuthError::NoToken => return MfaError::NoToken,
+            AuthError::NotAuthorized => return MfaError::NotAuthorized,
+            AuthError::MalformedRequest => return MfaError::MalformedRequest,
+        }
+    }
+}
+
+impl From<StepUpRequired> for MfaError {
+    fn from(error: StepUpRequired) -> MfaError {
+        return MfaError::StepUpRequired(error);
+    }
+}
+
+impl From<DatabaseError> for MfaError {
+    fn from(error: DatabaseError) -> MfaError {
+        tracing::error!(?error, "database failure");
+
+        if error.is_transient() {
+            return MfaError::ServiceUnavailable;
+        }
+
+        return MfaError::ServerFailure;
+    }
+}
+
+impl ResponseError for MfaError {
+    fn error_response(&self) -> HttpResponse {
+        if let MfaError::StepUpRequired(step_up) = self {
+            return step_up.error_response();
+        }
+
+        let mut response = HttpResponse::build(self.status_code());
+
+        if let MfaError::TooSoon(seconds) = self {
+            response.insert_header(("Retry-After", seconds.to_string()));
+        }
+
+        response
+        .insert_header(ContentType::json())
+        .body(self.to_string())
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            MfaError::NoToken => StatusCode::UNAUTHORIZED,
+            MfaError::NotAuthorized => StatusCode::FORBIDDEN,
+            MfaError::StepUpRequired(step_up) => step_up.status_code(),
+            MfaError::MalformedRequest => StatusCode::BAD_REQUEST,
+            MfaError::NotConfigured => StatusCode::CONFLICT,
+            MfaError::WrongMfaType => StatusCode::CONFLICT,
+            MfaError::AlreadyEnrolled => StatusCode::CONFLICT,
+            MfaError::FactorNotFound => StatusCode::NOT_FOUND,
+            MfaError::InvalidLabel => StatusCode::UNPROCESSABLE_ENTITY,
+            MfaError::NoPhoneNumber => StatusCode::CONFLICT,
+            MfaError::NoCodeIssued => StatusCode::CONFLICT,
+            MfaError::InvalidCode => StatusCode::FORBIDDEN,
+            MfaError::CodeAlreadyUsed => StatusCode::FORBIDDEN,
+            MfaError::MethodAlreadyUsed => StatusCode::FORBIDDEN,
+            MfaError::CodeExpired => StatusCode::GONE,
+            MfaError::TooManyAttempts => StatusCode::TOO_MANY_REQUESTS,
+            MfaError::TooSoon(_) => StatusCode::TOO_MANY_REQUESTS,
+            MfaError::IncorrectPassword => StatusCode::FORBIDDEN,
+            MfaError::AccountLocked => StatusCode::LOCKED,
+            MfaError::NotFound => StatusCode::NOT_FOUND,
+            MfaError::ServerFailure => StatusCode::INTERNAL_SERVER_ERROR,
+            MfaError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+}
+
+impl From<VarifyMfaState> for MfaError {
+    fn from(state: VarifyMfaState) -> MfaError {
+        match state {
+            VarifyMfaState::Failed => return MfaError::InvalidCode,
+            VarifyMfaState::NotConfigured => return MfaError::NotConfigured,
+            VarifyMfaState::NoCodeIssued => return MfaError::NoCodeIssued,
+            VarifyMfaState::Expired => return MfaError::CodeExpired,
+            VarifyMfaState::TooManyAttempts => return MfaError::TooManyAttempts,
+            VarifyMfaState::Replayed => return MfaError::CodeAlreadyUsed,
+            // callers handle Success before converting
+            VarifyMfaState::Success => return MfaError::ServerFailure,
+        }
+    }
+}
+
+impl From<AddMfaError> for MfaError {
+    fn from(error: AddMfaError) -> MfaError {
+        match error {
+            AddMfaError::AlreadyEnrolled => return MfaError::AlreadyEnrolled,
+            _ => return MfaError::ServerFailure,
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    return SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+}
+
+async fn user_and_credentail(user_uuid: String, database: &Data<dyn Database>) -> Result<(User, UserCredentail), MfaError> {
+
+    let user = database.get_user(user_uuid.clone()).await?;
+
+    if user.is_none() {
+        return Err(MfaError::NotFound);
+    }
+
+    let credentail = database.get_credentail(user_uuid).await?;
+
+    if credentail.is_none() {
+        return Err(MfaError::ServerFailure);
+    }
+
+    return Ok((user.unwrap(), credentail.unwrap()));
+}
+
+fn valid_label(label: Option<String>) -> Result<Option<String>, MfaError> {
+    match label {
+        Some(label) => return mfa_factor::valid_label(&label).map(Some).ok_or(MfaError::InvalidLabel),
+        None => return Ok(None),
+    }
+}
+
+// A factor of a kind the token already shows proves nothing new: a phone
+// login finished with the SMS factor would still only be the phone.
+fn require_new_method(factor_type: &UserMfaState, amr: &[AuthMethod]) -> Result<(), MfaError> {
+
+    if factor_type.auth_method().is_some_and(|method| amr.contains(&method)) {
+        return Err(MfaError::MethodAlreadyUsed);
+    }
+
+    return Ok(());
+}
+
+// Checks a login code for factor_id, or the preferred factor, and returns
+// what it adds to amr, the methods the user already authenticated with.
+// Attempts on a sent code or an authenticator app are counted in the
+// database before the check and a matching code is spent there, so parallel
+// requests can't get past code_max_attempts or use one code twice. Nothing
+// is saved on failure, the caller saves the credentail on success.
+async fn check_factor(
+    credentail: &mut UserCredentail,
+    factor_id: Option<&str>,
+    code: String,
+    amr: &[AuthMethod],
+    database: &Data<dyn Database>,
+    config: &Config,
+    keys: &SecretKeys,
+) -> Result<AuthMethod, MfaError> {
+
+    if let Some(factor) = credentail.resolve_factor(factor_id) {
+        require_new_method(&factor.factor_type, amr)?;
+    }
+
+    let sent = credentail.resolve_factor(factor_id)
+        .filter(|factor| factor.factor_type == UserMfaState::Email || factor.factor_type == UserMfaState::SMS)
+        .and_then(|factor| credentail.mfa_code_for(Some(&factor.factor_id)))
+        .map(|sent| sent.issued_at);
+
+    let totp_factor = credentail.resolve_factor(factor_id)
+        .filter(|factor| factor.factor_type == UserMfaState::OTP)
+        .map(|factor| factor.factor_id.clone());
+
+    let attempt_counted = match (sent, totp_factor) {
+        (Some(issued_at), _) => database.take_code_attempt(credentail.user_uuid.clone(), CodeSlot::MfaCode, issued_at, config.mfa.code_max_attempts).await?,
+        (None, Some(factor_id)) => database.take_totp_attempt(
+            credentail.user_uuid.clone(),
+            SecretSlot::Factor(factor_id),
+            totp_window_start(config),
+            config.mfa.code_max_attempts,
+        ).await?,
+        (None, None) => false,
+    };
+
+    let state = match credentail.check_mfa(factor_id, code, attempt_counted, now_secs(), &config.mfa, keys) {
+        Ok(state) => state,
+        Err(VarifyMfaStateError::UnknownFactor) => return Err(MfaError::FactorNotFound),
+        Err(VarifyMfaStateError::UndecryptableSecret(error)) => {
+            tracing::error!(%error, ?factor_id, "credentail can't check mfa");
+            return Err(MfaError::ServerFailure);
+        },
+        Err(_) => {
+            tracing::error!(?factor_id, "credentail can't check mfa");
+            return Err(MfaError::ServerFailure);
+        },
+    };
+
+    match state {
+        VarifyMfaState::Success => (),
+        state => return Err(MfaError::from(state)),
+    }
+
+    if let Some(issued_at) = sent {
+        if !database.spend_code(credentail.user_uuid.clone(), CodeSlot::MfaCode, issued_at).await? {
+            return Err(MfaError::NoCodeIssued);
+        }
+    }
+
+    let factor = credentail.resolve_factor(factor_id).ok_or(MfaError::ServerFailure)?;
+
+    // the check above only saw the step this request read, another one may
+    // have used the same code since
+    if let Some(step) = factor.totp_last_step().filter(|_| factor.factor_type == UserMfaState::OTP) {
+        if !database.claim_totp_step(credentail.user_uuid.clone(), factor.factor_id.clone(), step).await? {
+            return Err(MfaError::from(VarifyMfaState::Replayed));
+        }
+    }
+
+    let method = credentail.resolve_factor(factor_id).and_then(|factor| factor.factor_type.auth_method());
+
+    return method.ok_or(MfaError::ServerFailure);
+}
+
+fn totp_window_start(config: &Config) -> DateTime {
+    return DateTime::from_millis(DateTime::now().timestamp_millis() - config.mfa.totp_attempt_window_minutes * 60 * 1000);
+}
+
+// Issues a code for factor_id, None when enrolling Email, and saves its
+// hash. The caller sends the code.
+async fn issue_code(
+    mut credentail: UserCredentail,
+    factor_id: Option<String>,
+    database: &Data<dyn Database>,
+    config: &Config,
+) -> Result<String, MfaError> {
+
+    let code = match credentail.issue_mfa_code(factor_id, &config.mfa) {
+        Ok(code) => code,
+        Err(IssueMfaCodeError::TooSoon(seconds)) => return Err(MfaError::TooSoon(seconds)),
+    };
+
+    database.update_credentail(credentail).await?;
+
+    return Ok(code);
+}
+
+async fn mail_code(user: &User, code: &str, mailer: &Data<dyn Mailer>, config: &Config) -> Result<(), MfaError> {
+
+    let message = MailMessage {
+        to: user.user_email.clone(),
+        subject: "Your verification code".to_owned(),
+        body: format!(
+            "Your verification code is:\n\n    {}\n\nIt expires in {} minutes. If you didn't try to sign in, change your password.",
+            code, config.mfa.code_ttl_minutes
+        ),
+    };
+
+    if let Err(error) = mailer.send(message).await {
+        tracing::error!(%error, "could not send mail");
+        return Err(MfaError::ServerFailure);
+    }
+
+    return Ok(());
+}
+
+async fn text_code(user: &User, code: &str, sms: &Data<dyn SmsProvider>, config: &Config) -> Result<(), MfaError> {
+
+    let phone_number = match &user.phone_number {
+        Some(phone_number) => phone_number.clone(),
+        None => return Err(MfaError::NoPhoneNumber),
+    };
+
+    let message = SmsMessage::code(phone_number, code, config.mfa.code_ttl_minutes);
+
+    if let Err(error) = sms.send(message).await {
+        tracing::error!(%error, "could not send sms");
+        return Err(MfaError::ServerFailure);
+    }
+
+    return Ok(());
+}
+
+// Mails a login code to a user with Email MFA, during login or for
+// /reauth. Sending again replaces the earlier code once the resend cooldown
+// has passed.
+#[post("/mfa/email/send")]
+#[tracing::instrument(skip_all)]
+pub async fn send_mfa_code(
+    database: Data<dyn Database>,
+    mailer: Data<dyn Mailer>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, MfaError> {
+
+    let claims = auth::mfa_or_full_token_claims(&req)?;
+
+    let (user, credentail) = user_and_credentail(claims.sub, &database).await?;
+
+    let factor_id = match credentail.factor_of_type(&UserMfaState::Email) {
+        Some(factor) => factor.factor_id.clone(),
+        None => return Err(MfaError::WrongMfaType),
+    };
+
+    // /reauth starts over from the password, only login carries amr on
+    if claims.auth_type == TokenAuthType::RequiresMFA {
+        require_new_method(&UserMfaState::Email, &claims.amr)?;
+    }
+
+    let code = issue_code(credentail, Some(factor_id), &database, &config).await?;
+
+    mail_code(&user, &code, &mailer, &config).await?;
+
+    return Ok(HttpResponse::Accepted().finish());
+
+}
+
+// Texts a login code to the verified phone_number of a user with SMS MFA,
+// during login or for /reauth.
+#[post("/mfa/sms/send")]
+#[tracing::instrument(skip_all)]
+pub async fn send_mfa_sms(
+    database: Data<dyn Database>,
+    sms: Data<dyn SmsProvider>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, MfaError> {
+
+    let claims = auth::mfa_or_full_token_claims(&req)?;
+
+    let (user, credentail) = user_and_credentail(claims.sub, &database).await?;
+
+    let factor_id = match credentail.factor_of_type(&UserMfaState::SMS) {
+        Some(factor) => factor.factor_id.clone(),
+        None => return Err(MfaError::WrongMfaType),
+    };
+
+    if claims.auth_type == TokenAuthType::RequiresMFA {
+        require_new_method(&UserMfaState::SMS, &claims.amr)?;
+    }
+
+    if user.phone_number.is_none() {
+        return Err(MfaError::NoPhoneNumber);
+    }
+
+    let code = issue_code(credentail, Some(factor_id), &database, &config).await?;
+
+    text_code(&user, &code, &sms, &config).await?;
+
+    return Ok(HttpResponse::Accepted().finish());
+
+}
+
+// The factors a user in the middle of login can choose from, those of the
+// kind they logged in with don't count.
+#[get("/mfa/factors")]
+#[tracing::instrument(skip_all)]
+pub async fn list_login_factors(
+    database: Data<dyn Database>,
+    req: HttpRequest,
+) -> Result<Json<Vec<MfaFactorSummary>>, MfaError> {
+
+    let claims = auth::mfa_token_claims(&req)?;
+
+    let (_, credentail) = user_and_credentail(claims.sub, &database).await?;
+
+    let factors = credentail.factor_summaries().into_iter()
+        .filter(|factor| require_new_method(&factor.factor_type, &claims.amr).is_ok())
+        .collect();
+
+    return Ok(Json(factors));
+
+}
+
+// Finishes login with the chosen factor, or the preferred one.
+#[post("/mfa/verify")]
+#[tracing::instrument(skip_all)]
+pub async fn verify_mfa(
+    request: Json<MfaVerifyPost>,
+    database: Data<dyn Database>,
+    config: Data<Config>,
+    keys: Data<SecretKeys>,
+    req: HttpRequest,
+) -> Result<Json<Token>, MfaError> {
+
+    let claims = auth::mfa_token_claims(&req)?;
+
+    let (user, mut credentail) = user_and_credentail(claims.sub.clone(), &database).await?;
+
+    let method = check_factor(&mut credentail, request.factor_id.as_deref(), request.code.clone(), &claims.amr, &database, &config, &keys).await?;
+
+    if user.user_state == UserState::Disabled {
+        return Err(MfaError::AccountLocked);
+    }
+
+    let authentication = Authentication::from_claims(&claims).with(method);
+
+    let token_res = Token::new(
+        user.user_uuid.clone(),
+        config.token.ttl_minutes,
+        user.user_claims.clone(),
+        TokenAuthType::Full,
+        user.profile.token_claims(&config.profile.token_claims),
+        authentication,
+    );
+
+    if token_res.as_ref().is_err() {
+        tracing::error!(error = %token_res.as_ref().unwrap_err(), "could not sign token");
+        return Err(MfaError::ServerFailure);
+    }
+
+    database.update_credentail(credentail).await?;
+    database.set_last_login(user.user_uuid.clone(), DateTime::now()).await?;
+
+    return Ok(Json(token_res.unwrap()));
+
+}
+
+// Starts adding an Email factor by mailing a code to user_email, it is only
+// added once the code comes back so an address that doesn't receive mail
+// can't lock the user out.
+#[post("/me/mfa/email")]
+#[tracing::instrument(skip_all)]
+pub async fn request_email_mfa(
+    database: Data<dyn Database>,
+    mailer: Data<dyn Mailer>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, MfaError> {
+
+    let claims = auth::full_token_claims(&req)?;
+
+    let (user, credentail) = user_and_credentail(claims.sub.clone(), &database).await?;
+
+    auth::require_recent_auth(&claims, credentail.has_mfa(), config.token.step_up_max_age_minutes)?;
+
+    if credentail.factor_of_type(&UserMfaState::Email).is_some() {
+        return Err(MfaError::AlreadyEnrolled);
+    }
+
+    let code = issue_code(credentail, None, &database, &config).await?;
+
+    mail_code(&user, &code, &mailer, &config).await?;
+
+    return Ok(HttpResponse::Accepted().finish());
+
+}
+
+#[post("/me/mfa/email/confirm")]
+#[tracing::instrument(skip_all)]
+pub async fn confirm_email_mfa(
+    request: Json<MfaConfirmPost>,
+    database: Data<dyn Database>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, MfaError> {
+
+    let claims = auth::full_token_claims(&req)?;
+
+    let label = valid_label(request.label.clone())?;
+
+    let (_, mut credentail) = user_and_credentail(claims.sub.clone(), &database).await?;
+
+    auth::require_recent_auth(&claims, credentail.has_mfa(), config.token.step_up_max_age_minutes)?;
+
+    let issued_at = match credentail.mfa_code_for(None) {
+        Some(sent) => sent.issued_at,
+        None => return Err(MfaError::NoCodeIssued),
+    };
+
+    let attempt_counted = database.take_code_attempt(credentail.user_uuid.clone(), CodeSlot::MfaCode, issued_at, config.mfa.code_max_attempts).await?;
+
+    match credentail.check_mfa_code(None, &request.code, attempt_counted) {
+        VarifyMfaState::Success => (),
+        state => return Err(MfaError::from(state)),
+    }
+
+    if !database.spend_code(credentail.user_uuid.clone(), CodeSlot::MfaCode, issued_at).await? {
+        return Err(MfaError::NoCodeIssued);
+    }
+
+    credentail.add_mfa(UserMfaState::Email, label)?;
+
+    database.update_credentail(credentail).await?;
+
+    return Ok(HttpResponse::NoContent().finish());
+
+}
+
+// Adds an SMS factor straight away, the number was proven when it was
+// verified through /me/phone.
+#[post("/me/mfa/sms")]
+#[tracing::instrument(skip_all)]
+pub async fn enable_sms_mfa(
+    database: Data<dyn Database>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, MfaError> {
+
+    let claims = auth::full_token_claims(&req)?;
+
+    let (user, mut credentail) = user_and_credentail(claims.sub.clone(), &database).await?;
+
+    auth::require_recent_auth(&claims, credentail.has_mfa(), config.token.step_up_max_age_minutes)?;
+
+    if user.phone_number.is_none() {
+        return Err(MfaError::NoPhoneNumber);
+    }
+
+    credentail.add_mfa(UserMfaState::SMS, None)?;
+
+    database.update_credentail(credentail).await?;
+
+    return Ok(HttpResponse::NoContent().finish());
+
+}
+
+// Starts setting up an authenticator app with the [mfa] defaults. Asking
+// again replaces a setup that wasn't confirmed.
+#[post("/me/mfa/totp")]
+#[tracing::instrument(skip_all)]
+pub async fn begin_totp_mfa(
+    database: Data<dyn Database>,
+    config: Data<Config>,
+    keys: Data<SecretKeys>,
+    req: HttpRequest,
+) -> Result<Json<TotpEnrollmentResponse>, MfaError> {
+
+    let claims = auth::full_token_claims(&req)?;
+
+    let (user, mut credentail) = user_and_credentail(claims.sub.clone(), &database).await?;
+
+    auth::require_recent_auth(&claims, credentail.has_mfa(), config.token.step_up_max_age_minutes)?;
+
+    let (encoded, settings) = credentail.begin_totp(&config.mfa, &keys);
+
+    let secret = match totp::decode_secret(&encoded) {
+        Some(secret) => secret,
+        None => return Err(MfaError::ServerFailure),
+    };
+
+    let account_name = user.username.clone().unwrap_or(user.user_email.clone());
+    let uri = totp::provisioning_uri(&secret, &settings, &config.mfa.totp_issuer, &account_name);
+
+    database.update_credentail(credentail).await?;
+
+    return Ok(Json(TotpEnrollmentResponse {
+        secret: encoded,
+        uri,
+        algorithm: settings.algorithm,
+        digits: settings.digits,
+        period: settings.period,
+    }));
+
+}
+
+// Adds the app as an OTP factor once a code from it checks out.
+#[post("/me/mfa/totp/confirm")]
+#[tracing::instrument(skip_all)]
+pub async fn confirm_totp_mfa(
+    request: Json<MfaConfirmPost>,
+    database: Data<dyn Database>,
+    config: Data<Config>,
+    keys: Data<SecretKeys>,
+    req: HttpRequest,
+) -> Result<HttpResponse, MfaError> {
+
+    let claims = auth::full_token_claims(&req)?;
+
+    let label = valid_label(request.label.clone())?;
+
+    let (_, mut credentail) = user_and_credentail(claims.sub.clone(), &database).await?;
+
+    auth::require_recent_auth(&claims, credentail.has_mfa(), config.token.step_up_max_age_minutes)?;
+
+    let attempt_counted = credentail.totp_enrollment.is_some() && database.take_totp_attempt(
+        credentail.user_uuid.clone(),
+        SecretSlot::TotpEnrollment,
+        totp_window_start(&config),
+        config.mfa.code_max_attempts,
+    ).await?;
+
+    let state = credentail.confirm_totp(&request.code, label, attempt_counted, now_secs(), &config.mfa, &keys);
+
+    // a failed code changes nothing, saving would only undo the count above
+    if let VarifyMfaState::Success | VarifyMfaState::Expired = state {
+        database.update_credentail(credentail).await?;
+    }
+
+    match state {
+        VarifyMfaState::Success => return Ok(HttpResponse::NoContent().finish()),
+        state => return Err(MfaError::from(state)),
+    }
+
+}
+
+#[get("/me/mfa")]
+#[tracing::instrument(skip_all)]
+pub async fn list_mfa_factors(
+    database: Data<dyn Database>,
+    req: HttpRequest,
+) -> Result<Json<Vec<MfaFactorSummary>>, MfaError> {
+
+    let claims = auth::full_token_claims(&req)?;
+
+    let (_, credentail) = user_and_credentail(claims.sub, &database).await?;
+
+    return Ok(Json(credentail.factor_summaries()));
+
+}
+
+// Renames a factor and/or makes it the preferred one.
+#[patch("/me/mfa/{factor_id}")]
+#[tracing::instrument(skip_all)]
+pub async fn update_mfa_factor(
+    factor_id: Path<FactorId>,
+    request: Json<MfaFactorPatch>,
+    database: Data<dyn Database>,
+    req: HttpRequest,
+) -> Result<Json<MfaFactorSummary>, MfaError> {
+
+    let claims = auth::full_token_claims(&req)?;
+
+    let label = valid_label(request.label.clone())?;
+    let factor_id = factor_id.into_inner().factor_id;
+
+    let (_, mut credentail) = user_and_credentail(claims.sub, &database).await?;
+
+    if credentail.factor(&factor_id).is_none() {
+        return Err(MfaError::FactorNotFound);
+    }
+
+    if let Some(label) = label {
+        credentail.rename_factor(&factor_id, label);
+    }
+
+    if request.preferred {
+        credentail.set_preferred_factor(&factor_id);
+    }
+
+    let credentail = database.update_credentail(credentail).await?;
+
+    let summary = credentail.factor_summaries().into_iter().find(|factor| factor.factor_id == factor_id);
+
+    return summary.map(Json).ok_or(MfaError::ServerFailure);
+
+}
+
+// Removing the last factor turns MFA off for the user. Needs recent MFA so
+// a stolen token can't switch it off.
+#[delete("/me/mfa/{factor_id}")]
+#[tracing::instrument(skip_all)]
+pub async fn remove_mfa_factor(
+    factor_id: Path<FactorId>,
+    database: Data<dyn Database>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, MfaError> {
+
+    let claims = auth::full_token_claims(&req)?;
+
+    let (_, mut credentail) = user_and_credentail(claims.sub.clone(), &database).await?;
+
+    auth::require_recent_auth(&claims, credentail.has_mfa(), config.token.step_up_max_age_minutes)?;
+
+    if !credentail.remove_factor(&factor_id.into_inner().factor_id) {
+        return Err(MfaError::FactorNotFound);
+    }
+
+    database.update_credentail(credentail).await?;
+
+    return Ok(HttpResponse::NoContent().finish());
+
+}
+
+// Trades a Full token for one with a fresh auth_time, for operations that
+// use auth::require_recent_auth. Takes the password and, for users with MFA,
+// a code from one of their factors. Tries are limited per user by
+// token.reauth_max_attempts, a success starts the count over.
+#[post("/reauth")]
+#[tracing::instrument(skip_all)]
+pub async fn reauthenticate(
+    request: Json<ReauthPost>,
+    database: Data<dyn Database>,
+    config: Data<Config>,
+    keys: Data<SecretKeys>,
+    req: HttpRequest,
+) -> Result<Json<Token>, MfaError> {
+
+    let claims = auth::full_token_claims(&req)?;
+
+    let (user, mut credentail) = user_and_credentail(claims.sub, &database).await?;
+
+    if user.user_state == UserState::Disabled {
+        return Err(MfaError::AccountLocked);
+    }
+
+    let window_start = DateTime::from_millis(DateTime::now().timestamp_millis() - config.token.reauth_window_minutes * 60 * 1000);
+
+    if !database.take_reauth_attempt(user.user_uuid.clone(), window_start, config.token.reauth_max_attempts).await? {
+        return Err(MfaError::TooManyAttempts);
+    }
+
+    let password_verifiaction = credentail.varify_password(request.password.clone(), config.hashing.bcrypt_cost);
+
+    if password_verifiaction.state != VarifyPasswordState::Success {
+        return Err(MfaError::IncorrectPassword);
+    }
+
+    let mut authentication = Authentication::now(AuthMethod::Pwd);
+
+    if credentail.has_mfa() {
+
+        let code = match &request.code {
+            Some(code) => code.clone(),
+            None => return Err(MfaError::MalformedRequest),
+        };
+
+        let method = check_factor(&mut credentail, request.factor_id.as_deref(), code, &authentication.amr, &database, &config, &keys).await?;
+
+        authentication = authentication.with(method);
+
+    }
+
+    let token_res = Token::new(
+        user.user_uuid.clone(),
+        config.token.ttl_minutes,
+        user.user_claims.clone(),
+        TokenAuthType::Full,
+        user.profile.token_claims(&config.profile.token_claims),
+        authentication,
+    );
+
+    if token_res.as_ref().is_err() {
+        tracing::error!(error = %token_res.as_ref().unwrap_err(), "could not sign token");
+        return Err(MfaError::ServerFailure);
+    }
+
+    credentail.reauth_attempts = None;
+
+    database.update_credentail(credentail).await?;
+
+    return Ok(Json(token_res.unwrap()));
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sms_factor_cannot_finish_a_phone_login() {
+        let result = require_new_method(&UserMfaState::SMS, &[AuthMethod::Sms]);
+        assert!(matches!(result, Err(MfaError::MethodAlreadyUsed)));
+    }
+
+    #[test]
+    fn email_factor_cannot_finish_a_magic_link_login() {
+        let result = require_new_method(&UserMfaState::Email, &[AuthMethod::Email]);
+        assert!(matches!(result, Err(MfaError::MethodAlreadyUsed)));
+    }
+
+    #[test]
+    fn other_factors_can_finish_a_login() {
+        assert!(require_new_method(&UserMfaState::SMS, &[AuthMethod::Pwd]).is_ok());
+        assert!(require_new_method(&UserMfaState::Email, &[AuthMethod::Pwd]).is_ok());
+        assert!(require_new_method(&UserMfaState::Email, &[AuthMethod::Sms]).is_ok());
+        assert!(require_new_method(&UserMfaState::OTP, &[AuthMethod::Email]).is_ok());
+    }
+}
@@ -1,3 +1,12 @@
 pub mod user;
 pub mod credentail;
-pub mod hidden;
\ No newline at end of file
+pub mod hidden;
+pub mod health;
+pub mod metrics;
+pub mod auth;
+pub mod privacy;
+pub mod profile;
+pub mod email;
+pub mod magic_link;
+pub mod mfa;
+pub mod phone;
@@ -0,0 +1,493 @@
+use crate::model::user::{canonical_phone_number, valid_phone_number, User, UserState};
+use crate::model::credentail::{CodeSlot, PhoneChange, UserCredentail, UserMfaState};
+use crate::model::one_time_code::{CodeCheck, OneTimeCode};
+use crate::model::token::{AuthMethod, Authentication, Token, TokenAuthType};
+use crate::repo::database::base::{Database, DatabaseError};
+use crate::api::auth::{self, AuthError, StepUpRequired};
+use crate::sms::{SmsMessage, SmsProvider};
+use crate::config::Config;
+
+use bson::DateTime;
+use actix_web::{
+    delete,
+    post,
+    put,
+    error::ResponseError,
+    web::Json,
+    web::Data,
+    HttpRequest,
+    HttpResponse,
+    http::{header::ContentType, StatusCode}
+};
+use serde::{Serialize, Deserialize};
+use strum_macros::Display;
+
+// Verified phone numbers and logging in with one. A number only goes on the
+// User once the code texted to it comes back, from then on it's unique and
+// can receive login codes.
+
+#[derive(Deserialize, Serialize)]
+pub struct PhonePut {
+    phone_number: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PhoneVerifyPost {
+    code: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PhoneLoginPost {
+    phone_number: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PhoneLoginVerifyPost {
+    phone_number: String,
+    code: String,
+}
+
+#[derive(Debug, Display)]
+pub enum PhoneError {
+    NoToken,
+    NotAuthorized,
+    StepUpRequired(StepUpRequired),
+    MalformedRequest,
+    InvalidPhoneNumber,
+    PhoneNumberUnchanged,
+    PhoneNumberTaken,
+    NoPendingChange,
+    NoPhoneNumber,
+    // SMS MFA has to be switched off before the number can go
+    NeededForMfa,
+    InvalidCode,
+    CodeExpired,
+    TooManyAttempts,
+    TooSoon(i64),
+    AccountLocked,
+    NotFound,
+    ServerFailure,
+    ServiceUnavailable,
+}
+
+impl From<AuthError> for PhoneError {
+    fn from(error: AuthError) -> PhoneError {
+        match error {
+            AuthError::NoToken => return PhoneError::NoToken,
+            AuthError::NotAuthorized => return PhoneError::NotAuthorized,
+            AuthError::MalformedRequest => return PhoneError::MalformedRequest,
+        }
+    }
+}
+
+impl From<StepUpRequired> for PhoneError {
+    fn from(error: StepUpRequired) -> PhoneError {
+        return PhoneError::StepUpRequired(error);
+    }
+}
+
+impl From<DatabaseError> for PhoneError {
+    fn from(error: DatabaseError) -> PhoneError {
+
+        match error {
+            DatabaseError::PhoneNumberTaken => return PhoneError::PhoneNumberTaken,
+            DatabaseError::UserDoesntExist => return PhoneError::NotFound,
+            _ => (),
+        }
+
+        tracing::error!(?error, "database failure");
+
+        if error.is_transient() {
+            return PhoneError::ServiceUnavailable;
+        }
+
+        return PhoneError::ServerFailure;
+    }
+}
+
+impl ResponseError for PhoneError {
+    fn error_response(&self) -> HttpResponse {
+        if let PhoneError::StepUpRequired(step_up) = self {
+            return step_up.error_response();
+        }
+
+        let mut response = HttpResponse::build(self.status_code());
+
+        if let PhoneError::TooSoon(seconds) = self {
+            response.insert_header(("Retry-After", seconds.to_string()));
+        }
+
+        response
+        .insert_header(ContentType::json())
+        .body(self.to_string())
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PhoneError::NoToken => StatusCode::UNAUTHORIZED,
+            PhoneError::NotAuthorized => StatusCode::FORBIDDEN,
+            PhoneError::StepUpRequired(step_up) => step_up.status_code(),
+            PhoneError::MalformedRequest => StatusCode::BAD_REQUEST,
+            PhoneError::InvalidPhoneNumber => StatusCode::UNPROCESSABLE_ENTITY,
+            PhoneError::PhoneNumberUnchanged => StatusCode::UNPROCESSABLE_ENTITY,
+            PhoneError::PhoneNumberTaken => StatusCode::CONFLICT,
+            PhoneError::NoPendingChange => StatusCode::NOT_FOUND,
+            PhoneError::NoPhoneNumber => StatusCode::NOT_FOUND,
+            PhoneError::NeededForMfa => StatusCode::CONFLICT,
+            PhoneError::InvalidCode => StatusCode::FORBIDDEN,
+            PhoneError::CodeExpired => StatusCode::GONE,
+            PhoneError::TooManyAttempts => StatusCode::TOO_MANY_REQUESTS,
+            PhoneError::TooSoon(_) => StatusCode::TOO_MANY_REQUESTS,
+            PhoneError::AccountLocked => StatusCode::LOCKED,
+            PhoneError::NotFound => StatusCode::NOT_FOUND,
+            PhoneError::ServerFailure => StatusCode::INTERNAL_SERVER_ERROR,
+            PhoneError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+}
+
+async fn current_user_and_credentail(req: &HttpRequest, database: &Data<dyn Database>) -> Result<(User, UserCredentail), PhoneError> {
+
+    let claims = auth::full_token_claims(req)?;
+
+    let user = database.get_user(claims.sub.clone()).await?;
+
+    if user.is_none() {
+        return Err(PhoneError::NotFound);
+    }
+
+    let credentail = database.get_credentail(claims.sub).await?;
+
+    if credentail.is_none() {
+        return Err(PhoneError::ServerFailure);
+    }
+
+    return Ok((user.unwrap(), credentail.unwrap()));
+}
+
+async fn text_code(sms: &Data<dyn SmsProvider>, to: String, code: &str, config: &Config) -> Result<(), PhoneError> {
+
+    let message = SmsMessage::code(to, code, config.sms.code_ttl_minutes);
+
+    if let Err(error) = sms.send(message).await {
+        tracing::error!(%error, "could not send sms");
+        return Err(PhoneError::ServerFailure);
+    }
+
+    return Ok(());
+}
+
+fn parse_phone_number(raw: &str) -> Result<String, PhoneError> {
+
+    let phone_number = canonical_phone_number(raw);
+
+    if !valid_phone_number(&phone_number) {
+        return Err(PhoneError::InvalidPhoneNumber);
+    }
+
+    return Ok(phone_number);
+}
+
+// Texts a code to the new number. The current number, if any, stays until
+// the code comes back.
+#[put("/me/phone")]
+#[tracing::instrument(skip_all)]
+pub async fn put_phone_number(
+    request: Json<PhonePut>,
+    database: Data<dyn Database>,
+    sms: Data<dyn SmsProvider>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, PhoneError> {
+
+    let claims = auth::full_token_claims(&req)?;
+
+    let phone_number = parse_phone_number(&request.phone_number)?;
+
+    let (user, mut credentail) = current_user_and_credentail(&req, &database).await?;
+
+    // a number is a way to log in, adding one is as sensitive as a factor
+    auth::require_recent_auth(&claims, credentail.has_mfa(), config.token.step_up_max_age_minutes)?;
+
+    if user.phone_number.as_ref() == Some(&phone_number) {
+        return Err(PhoneError::PhoneNumberUnchanged);
+    }
+
+    if let Some(pending) = &credentail.phone_change {
+        let wait = pending.code.seconds_until_resend(config.sms.code_resend_cooldown_seconds);
+
+        if wait > 0 {
+            return Err(PhoneError::TooSoon(wait));
+        }
+    }
+
+    // the unique index decides at verify time, this just saves a text
+    if database.get_user_by_phone_number(phone_number.clone()).await?.is_some() {
+        return Err(PhoneError::PhoneNumberTaken);
+    }
+
+    let (code, stored) = OneTimeCode::numeric(6, config.sms.code_ttl_minutes * 60);
+
+    credentail.phone_change = Some(PhoneChange { new_phone_number: phone_number.clone(), code: stored });
+
+    database.update_credentail(credentail).await?;
+
+    text_code(&sms, phone_number, &code, &config).await?;
+
+    return Ok(HttpResponse::Accepted().finish());
+
+}
+
+#[post("/me/phone/verify")]
+#[tracing::instrument(skip_all)]
+pub async fn verify_phone_number(
+    request: Json<PhoneVerifyPost>,
+    database: Data<dyn Database>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<Json<User>, PhoneError> {
+
+    let (mut user, mut credentail) = current_user_and_credentail(&req, &database).await?;
+
+    let change = match credentail.phone_change.take() {
+        Some(change) => change,
+        None => return Err(PhoneError::NoPendingChange),
+    };
+
+    let attempt_counted = database.take_code_attempt(
+        user.user_uuid.clone(),
+        CodeSlot::PhoneChange,
+        change.code.issued_at,
+        config.sms.code_max_attempts
+    ).await?;
+
+    match change.code.check(&request.code, attempt_counted) {
+        CodeCheck::Valid => (),
+        CodeCheck::Invalid => return Err(PhoneError::InvalidCode),
+        CodeCheck::Expired => return Err(PhoneError::CodeExpired),
+        CodeCheck::TooManyAttempts => return Err(PhoneError::TooManyAttempts),
+    }
+
+    if !database.spend_code(user.user_uuid.clone(), CodeSlot::PhoneChange, change.code.issued_at).await? {
+        return Err(PhoneError::NoPendingChange);
+    }
+
+    user.phone_number = Some(change.new_phone_number);
+
+    // login codes went to the old number
+    credentail.phone_login = None;
+
+    let user = database.update_user_with_credentail(user, credentail).await?;
+
+    return Ok(Json(user));
+
+}
+
+// Removing the number also ends phone login, so it takes a recent login like
+// changing it does.
+#[delete("/me/phone")]
+#[tracing::instrument(skip_all)]
+pub async fn delete_phone_number(
+    database: Data<dyn Database>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, PhoneError> {
+
+    let claims = auth::full_token_claims(&req)?;
+
+    let (mut user, mut credentail) = current_user_and_credentail(&req, &database).await?;
+
+    auth::require_recent_auth(&claims, credentail.has_mfa(), config.token.step_up_max_age_minutes)?;
+
+    if user.phone_number.is_none() {
+        return Err(PhoneError::NoPhoneNumber);
+    }
+
+    if credentail.factor_of_type(&UserMfaState::SMS).is_some() {
+        return Err(PhoneError::NeededForMfa);
+    }
+
+    user.phone_number = None;
+    credentail.phone_login = None;
+
+    database.update_user_with_credentail(user, credentail).await?;
+
+    return Ok(HttpResponse::NoContent().finish());
+
+}
+
+// Texts a login code to a verified number. Answers 202 whether or not the
+// number belongs to anyone, and a resend inside the cooldown is dropped
+// quietly for the same reason.
+#[post("/login/phone")]
+#[tracing::instrument(skip_all)]
+pub async fn request_phone_login(
+    request: Json<PhoneLoginPost>,
+    database: Data<dyn Database>,
+    sms: Data<dyn SmsProvider>,
+    config: Data<Config>,
+) -> Result<HttpResponse, PhoneError> {
+
+    let phone_number = parse_phone_number(&request.phone_number)?;
+
+    let user = database.get_user_by_phone_number(phone_number.clone()).await?;
+
+    let user = match user.filter(|user| user.user_state != UserState::Disabled) {
+        Some(user) => user,
+        None => return Ok(HttpResponse::Accepted().finish()),
+    };
+
+    let mut credentail = match database.get_credentail(user.user_uuid.clone()).await? {
+        Some(credentail) => credentail,
+        None => return Err(PhoneError::ServerFailure),
+    };
+
+    if let Some(previous) = &credentail.phone_login {
+        if previous.seconds_until_resend(config.sms.code_resend_cooldown_seconds) > 0 {
+            return Ok(HttpResponse::Accepted().finish());
+        }
+    }
+
+    let (code, stored) = OneTimeCode::numeric(6, config.sms.code_ttl_minutes * 60);
+
+    credentail.phone_login = Some(stored);
+
+    database.update_credentail(credentail).await?;
+
+    text_code(&sms, phone_number, &code, &config).await?;
+
+    return Ok(HttpResponse::Accepted().finish());
+
+}
+
+// Exchanges the texted code for a token. Users with MFA get a RequiresMFA
+// token, same as a password login.
+#[post("/login/phone/verify")]
+#[tracing::instrument(skip_all)]
+pub async fn verify_phone_login(
+    request: Json<PhoneLoginVerifyPost>,
+    database: Data<dyn Database>,
+    config: Data<Config>,
+) -> Result<Json<Token>, PhoneError> {
+
+    let phone_number = parse_phone_number(&request.phone_number)?;
+
+    let user = match database.get_user_by_phone_number(phone_number).await? {
+        Some(user) => user,
+        None => return Err(PhoneError::InvalidCode),
+    };
+
+    let mut credentail = match database.get_credentail(user.user_uuid.clone()).await? {
+        Some(credentail) => credentail,
+        None => return Err(PhoneError::ServerFailure),
+    };
+
+    let stored = match credentail.phone_login.take() {
+        Some(stored) => stored,
+        None => return Err(PhoneError::InvalidCode),
+    };
+
+    // counted before the check, parallel guesses can't share one count
+    let attempt_counted = database.take_code_attempt(
+        user.user_uuid.clone(),
+        CodeSlot::PhoneLogin,
+        stored.issued_at,
+        config.sms.code_max_attempts
+    ).await?;
+
+    match stored.check(&request.code, attempt_counted) {
+        CodeCheck::Valid => (),
+        CodeCheck::Invalid => return Err(PhoneError::InvalidCode),
+        CodeCheck::Expired => return Err(PhoneError::CodeExpired),
+        CodeCheck::TooManyAttempts => return Err(PhoneError::TooManyAttempts),
+    }
+
+    if user.user_state == UserState::Disabled {
+        return Err(PhoneError::AccountLocked);
+    }
+
+    if !database.spend_code(user.user_uuid.clone(), CodeSlot::PhoneLogin, stored.issued_at).await? {
+        return Err(PhoneError::InvalidCode);
+    }
+
+    let auth_type = if !credentail.has_mfa() {
+        TokenAuthType::Full
+    } else {
+        TokenAuthType::RequiresMFA
+    };
+
+    let token_res = Token::new(
+        user.user_uuid.clone(),
+        config.token.ttl_minutes,
+        user.user_claims.clone(),
+        auth_type,
+        user.profile.token_claims(&config.profile.token_claims),
+        Authentication::now(AuthMethod::Sms),
+    );
+
+    if token_res.as_ref().is_err() {
+        tracing::error!(error = %token_res.as_ref().unwrap_err(), "could not sign token");
+        return Err(PhoneError::ServerFailure);
+    }
+
+    database.update_credentail(credentail).await?;
+    database.set_last_login(user.user_uuid.clone(), DateTime::now()).await?;
+
+    return Ok(Json(token_res.unwrap()));
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::database::sql::SqlRepo;
+    use actix_web::{http::header, test, App};
+    use std::sync::Arc;
+
+    async fn user_with_phone() -> (Data<dyn Database>, User) {
+        let path = std::env::temp_dir().join(format!("userauth-{}.db", uuid::Uuid::new_v4()));
+        let repo = SqlRepo::init(format!("sqlite://{}?mode=rwc", path.display()), String::new()).await.unwrap();
+        repo.migrate().await.unwrap();
+
+        let mut user = User::new("bob@example.org".to_owned());
+        user.phone_number = Some("+15550109999".to_owned());
+        let credentail = UserCredentail::new(user.clone(), "correct horse".to_owned(), 4);
+        repo.insert_user_with_credentail(user.clone(), credentail).await.unwrap();
+
+        let database: Arc<dyn Database> = Arc::new(repo);
+        return (Data::from(database), user);
+    }
+
+    fn token(user: &User, auth_time: i64) -> String {
+        let authentication = Authentication { auth_time, amr: vec![AuthMethod::Pwd] };
+        let token = Token::new(user.user_uuid.clone(), 60, user.user_claims.clone(), TokenAuthType::Full, Default::default(), authentication).unwrap();
+        return token.token.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn deleting_the_phone_number_needs_a_recent_login() {
+        let (database, user) = user_with_phone().await;
+        let app = test::init_service(App::new()
+            .app_data(Data::clone(&database))
+            .app_data(Data::new(Config::default()))
+            .service(delete_phone_number)).await;
+
+        let stale = chrono::Utc::now().timestamp() - 60 * 60;
+        let request = test::TestRequest::delete().uri("/me/phone")
+            .insert_header((header::AUTHORIZATION, token(&user, stale)))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(database.get_user(user.user_uuid.clone()).await.unwrap().unwrap().phone_number.is_some());
+
+        let request = test::TestRequest::delete().uri("/me/phone")
+            .insert_header((header::AUTHORIZATION, token(&user, chrono::Utc::now().timestamp())))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(database.get_user(user.user_uuid.clone()).await.unwrap().unwrap().phone_number.is_none());
+    }
+}
@@ -0,0 +1,182 @@
+use crate::model::privacy::{DataExport, ErasureReceipt};
+use crate::repo::database::base::{Database, DatabaseError};
+use crate::repo::blob::BlobStore;
+use crate::api::auth::{self, AuthError, StepUpRequired};
+use crate::config::Config;
+
+use actix_web::{
+    get,
+    delete,
+    error::ResponseError,
+    web::Path,
+    web::Json,
+    web::Data,
+    HttpRequest,
+    HttpResponse,
+    http::{header::{ContentDisposition, ContentType}, StatusCode}
+};
+use serde::{Serialize, Deserialize};
+use strum_macros::Display;
+
+#[derive(Deserialize, Serialize)]
+pub struct UserUuid {
+    user_uuid: String
+}
+
+#[derive(Debug, Display)]
+pub enum PrivacyError {
+    NoToken,
+    NotAuthorized,
+    StepUpRequired(StepUpRequired),
+    MalformedRequest,
+    NotFound,
+    ServerFailure,
+    ServiceUnavailable,
+}
+
+impl From<AuthError> for PrivacyError {
+    fn from(error: AuthError) -> PrivacyError {
+        match error {
+            AuthError::NoToken => return PrivacyError::NoToken,
+            AuthError::NotAuthorized => return PrivacyError::NotAuthorized,
+            AuthError::MalformedRequest => return PrivacyError::MalformedRequest,
+        }
+    }
+}
+
+impl From<StepUpRequired> for PrivacyError {
+    fn from(error: StepUpRequired) -> PrivacyError {
+        return PrivacyError::StepUpRequired(error);
+    }
+}
+
+impl From<DatabaseError> for PrivacyError {
+    fn from(error: DatabaseError) -> PrivacyError {
+
+        if matches!(error, DatabaseError::UserDoesntExist) {
+            return PrivacyError::NotFound;
+        }
+
+        tracing::error!(?error, "database failure");
+
+        if error.is_transient() {
+            return PrivacyError::ServiceUnavailable;
+        }
+
+        return PrivacyError::ServerFailure;
+    }
+}
+
+impl ResponseError for PrivacyError {
+    fn error_response(&self) -> HttpResponse {
+        if let PrivacyError::StepUpRequired(step_up) = self {
+            return step_up.error_response();
+        }
+
+        HttpResponse::build(self.status_code())
+        .insert_header(ContentType::json())
+        .body(self.to_string())
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PrivacyError::NoToken => StatusCode::UNAUTHORIZED,
+            PrivacyError::NotAuthorized => StatusCode::FORBIDDEN,
+            PrivacyError::StepUpRequired(step_up) => step_up.status_code(),
+            PrivacyError::MalformedRequest => StatusCode::BAD_REQUEST,
+            PrivacyError::NotFound => StatusCode::NOT_FOUND,
+            PrivacyError::ServerFailure => StatusCode::INTERNAL_SERVER_ERROR,
+            PrivacyError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+}
+
+// Everything held about the user as a JSON download, for the user themselves
+// or an Admin answering a request on their behalf.
+#[get("/user/{user_uuid}/data")]
+#[tracing::instrument(skip_all)]
+pub async fn get_user_data(
+    user_uuid: Path<UserUuid>,
+    database: Data<dyn Database>,
+    req: HttpRequest,
+) -> Result<HttpResponse, PrivacyError> {
+
+    let user_uuid = user_uuid.into_inner().user_uuid;
+
+    let claims = auth::full_token_claims(&req)?;
+    auth::self_or_admin(&claims, &user_uuid)?;
+
+    let user = database.get_user(user_uuid.clone()).await?;
+
+    if user.is_none() {
+        return Err(PrivacyError::NotFound);
+    }
+
+    let credentail = database.get_credentail(user_uuid.clone()).await?;
+
+    return Ok(HttpResponse::Ok()
+        .insert_header(ContentDisposition::attachment(format!("user-{}.json", user_uuid)))
+        .json(DataExport::new(user.unwrap(), credentail)));
+
+}
+
+// Right to erasure, deletes the user, credentail and avatar and returns a
+// signed receipt. Tokens already handed out stop resolving to a user but stay
+// valid until they expire. Whoever asks, the user or an Admin, has to have
+// authenticated recently.
+#[delete("/user/{user_uuid}")]
+#[tracing::instrument(skip_all)]
+pub async fn erase_user(
+    user_uuid: Path<UserUuid>,
+    database: Data<dyn Database>,
+    blobs: Data<dyn BlobStore>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<Json<ErasureReceipt>, PrivacyError> {
+
+    let user_uuid = user_uuid.into_inner().user_uuid;
+
+    let claims = auth::full_token_claims(&req)?;
+    auth::self_or_admin(&claims, &user_uuid)?;
+
+    let has_mfa = database.get_credentail(claims.sub.clone()).await?
+        .is_some_and(|credentail| credentail.has_mfa());
+
+    auth::require_recent_auth(&claims, has_mfa, config.token.step_up_max_age_minutes)?;
+
+    let user = database.get_user(user_uuid.clone()).await?;
+
+    if user.is_none() {
+        return Err(PrivacyError::NotFound);
+    }
+
+    let credentail = database.get_credentail(user_uuid.clone()).await?;
+
+    let user = database.delete_user(user.unwrap()).await?;
+
+    let mut erased = vec!["user".to_owned()];
+
+    if credentail.is_some() {
+        erased.push("credentail".to_owned());
+    }
+
+    if let Some(avatar) = user.profile.avatar {
+        if let Err(error) = blobs.delete(&avatar).await {
+            tracing::error!(%error, "could not delete avatar of erased user");
+            return Err(PrivacyError::ServerFailure);
+        }
+
+        erased.push("avatar".to_owned());
+    }
+
+    let receipt = ErasureReceipt::new(user_uuid, claims.sub, erased);
+
+    if let Err(error) = receipt {
+        tracing::error!(%error, "could not sign erasure receipt");
+        return Err(PrivacyError::ServerFailure);
+    }
+
+    return Ok(Json(receipt.unwrap()));
+
+}
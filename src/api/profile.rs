@@ -0,0 +1,304 @@
+use crate::model::user::User;
+use crate::model::profile::{ProfileError, ProfilePatch, UserProfile};
+use crate::model::username::{Username, UsernameError};
+use crate::repo::database::base::{Database, DatabaseError};
+use crate::repo::blob::{BlobError, BlobStore};
+use crate::api::auth::{self, AuthError};
+use crate::config::Config;
+
+use actix_web::{
+    get,
+    put,
+    patch,
+    delete,
+    error::ResponseError,
+    web::Path,
+    web::Json,
+    web::Data,
+    web::Payload,
+    web::BytesMut,
+    HttpRequest,
+    HttpResponse,
+    http::{header::ContentType, StatusCode}
+};
+use futures_util::StreamExt;
+use serde::{Serialize, Deserialize};
+use strum_macros::Display;
+use uuid::Uuid;
+
+#[derive(Deserialize, Serialize)]
+pub struct UserUuid {
+    user_uuid: String
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct UsernamePut {
+    username: String
+}
+
+#[derive(Debug, Display)]
+pub enum ProfileRequestError {
+    NoToken,
+    NotAuthorized,
+    MalformedRequest,
+    NotFound,
+    InvalidProfile(ProfileError),
+    InvalidUsername(UsernameError),
+    UsernameTaken,
+    AvatarTooLarge,
+    UnsupportedImageType,
+    ServerFailure,
+    ServiceUnavailable,
+}
+
+impl From<AuthError> for ProfileRequestError {
+    fn from(error: AuthError) -> ProfileRequestError {
+        match error {
+            AuthError::NoToken => return ProfileRequestError::NoToken,
+            AuthError::NotAuthorized => return ProfileRequestError::NotAuthorized,
+            AuthError::MalformedRequest => return ProfileRequestError::MalformedRequest,
+        }
+    }
+}
+
+impl From<DatabaseError> for ProfileRequestError {
+    fn from(error: DatabaseError) -> ProfileRequestError {
+
+        if matches!(error, DatabaseError::UsernameTaken) {
+            return ProfileRequestError::UsernameTaken;
+        }
+
+        tracing::error!(?error, "database failure");
+
+        if error.is_transient() {
+            return ProfileRequestError::ServiceUnavailable;
+        }
+
+        return ProfileRequestError::ServerFailure;
+    }
+}
+
+impl From<BlobError> for ProfileRequestError {
+    fn from(error: BlobError) -> ProfileRequestError {
+        tracing::error!(%error, "blob store failure");
+        return ProfileRequestError::ServerFailure;
+    }
+}
+
+impl ResponseError for ProfileRequestError {
+    fn error_response(&self) -> HttpResponse {
+        // name the field that failed validation rather than just InvalidProfile
+        let body = match self {
+            ProfileRequestError::InvalidProfile(error) => error.to_string(),
+            ProfileRequestError::InvalidUsername(error) => error.to_string(),
+            error => error.to_string(),
+        };
+
+        HttpResponse::build(self.status_code())
+        .insert_header(ContentType::json())
+        .body(body)
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ProfileRequestError::NoToken => StatusCode::UNAUTHORIZED,
+            ProfileRequestError::NotAuthorized => StatusCode::FORBIDDEN,
+            ProfileRequestError::MalformedRequest => StatusCode::BAD_REQUEST,
+            ProfileRequestError::NotFound => StatusCode::NOT_FOUND,
+            ProfileRequestError::InvalidProfile(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ProfileRequestError::InvalidUsername(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ProfileRequestError::UsernameTaken => StatusCode::CONFLICT,
+            ProfileRequestError::AvatarTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ProfileRequestError::UnsupportedImageType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ProfileRequestError::ServerFailure => StatusCode::INTERNAL_SERVER_ERROR,
+            ProfileRequestError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+}
+
+// Image type from the leading bytes, the Content-Type header isn't trusted.
+fn image_extension(data: &[u8]) -> Option<&'static str> {
+
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some("png");
+    }
+
+    if data.starts_with(b"\xff\xd8\xff") {
+        return Some("jpg");
+    }
+
+    if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        return Some("gif");
+    }
+
+    if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        return Some("webp");
+    }
+
+    return None;
+}
+
+fn content_type(key: &str) -> &'static str {
+    match key.rsplit('.').next() {
+        Some("png") => return "image/png",
+        Some("jpg") => return "image/jpeg",
+        Some("gif") => return "image/gif",
+        Some("webp") => return "image/webp",
+        _ => return "application/octet-stream",
+    }
+}
+
+async fn current_user(req: &HttpRequest, database: &Data<dyn Database>) -> Result<User, ProfileRequestError> {
+
+    let claims = auth::full_token_claims(req)?;
+
+    let user = database.get_user(claims.sub).await?;
+
+    return user.ok_or(ProfileRequestError::NotFound);
+}
+
+// The caller's own record, the full view of GET /user/{user_uuid}.
+#[get("/me")]
+#[tracing::instrument(skip_all)]
+pub async fn get_me(
+    database: Data<dyn Database>,
+    req: HttpRequest,
+) -> Result<Json<User>, ProfileRequestError> {
+
+    let user = current_user(&req, &database).await?;
+
+    return Ok(Json(user));
+
+}
+
+// Attributes copied into tokens only change in tokens issued after this.
+#[patch("/me")]
+#[tracing::instrument(skip_all)]
+pub async fn patch_me(
+    patch: Json<ProfilePatch>,
+    database: Data<dyn Database>,
+    req: HttpRequest,
+) -> Result<Json<UserProfile>, ProfileRequestError> {
+
+    let mut user = current_user(&req, &database).await?;
+
+    user.profile.apply(patch.into_inner()).map_err(ProfileRequestError::InvalidProfile)?;
+
+    let user = database.update_user(user).await?;
+
+    return Ok(Json(user.profile));
+
+}
+
+// Sets or changes the username. The old name is free for others to take
+// straight away, as is the case for emails.
+#[put("/me/username")]
+#[tracing::instrument(skip_all)]
+pub async fn put_username(
+    request: Json<UsernamePut>,
+    database: Data<dyn Database>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<Json<User>, ProfileRequestError> {
+
+    let mut user = current_user(&req, &database).await?;
+
+    let username = Username::parse(&request.username, &config.username).map_err(ProfileRequestError::InvalidUsername)?;
+
+    user.set_username(username);
+
+    let user = database.update_user(user).await?;
+
+    return Ok(Json(user));
+
+}
+
+// Each upload gets a fresh key so caches never serve the old image, the
+// previous blob is removed once the profile points at the new one.
+#[put("/me/avatar")]
+#[tracing::instrument(skip_all)]
+pub async fn put_avatar(
+    mut payload: Payload,
+    database: Data<dyn Database>,
+    blobs: Data<dyn BlobStore>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<Json<UserProfile>, ProfileRequestError> {
+
+    let mut user = current_user(&req, &database).await?;
+
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|_| ProfileRequestError::MalformedRequest)?;
+
+        if body.len() + chunk.len() > config.profile.avatar_max_bytes {
+            return Err(ProfileRequestError::AvatarTooLarge);
+        }
+
+        body.extend_from_slice(&chunk);
+    }
+
+    let extension = image_extension(&body).ok_or(ProfileRequestError::UnsupportedImageType)?;
+    let key = format!("{}-{}.{}", user.user_uuid, Uuid::new_v4(), extension);
+
+    blobs.put(&key, body.to_vec()).await?;
+
+    let previous = user.profile.avatar.replace(key);
+    let user = database.update_user(user).await?;
+
+    if let Some(previous) = previous {
+        blobs.delete(&previous).await?;
+    }
+
+    return Ok(Json(user.profile));
+
+}
+
+#[delete("/me/avatar")]
+#[tracing::instrument(skip_all)]
+pub async fn delete_avatar(
+    database: Data<dyn Database>,
+    blobs: Data<dyn BlobStore>,
+    req: HttpRequest,
+) -> Result<Json<UserProfile>, ProfileRequestError> {
+
+    let mut user = current_user(&req, &database).await?;
+
+    let previous = user.profile.avatar.take();
+    let user = database.update_user(user).await?;
+
+    if let Some(previous) = previous {
+        blobs.delete(&previous).await?;
+    }
+
+    return Ok(Json(user.profile));
+
+}
+
+// Any signed in user can see avatars.
+#[get("/user/{user_uuid}/avatar")]
+#[tracing::instrument(skip_all)]
+pub async fn get_avatar(
+    user_uuid: Path<UserUuid>,
+    database: Data<dyn Database>,
+    blobs: Data<dyn BlobStore>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ProfileRequestError> {
+
+    auth::full_token_claims(&req)?;
+
+    let user = database.get_user(user_uuid.into_inner().user_uuid).await?;
+
+    let key = match user.and_then(|user| user.profile.avatar) {
+        Some(key) => key,
+        None => return Err(ProfileRequestError::NotFound),
+    };
+
+    let data = blobs.get(&key).await?.ok_or(ProfileRequestError::NotFound)?;
+
+    return Ok(HttpResponse::Ok()
+        .insert_header(("Content-Type", content_type(&key)))
+        .body(data));
+
+}
@@ -1,7 +1,10 @@
-use crate::model::user::User;
+use crate::model::user::{PublicUser, User, UserView, canonical_email, valid_email};
+use crate::model::token::TokenClaims;
 use crate::model::credentail::UserCredentail;
-use crate::repo::database::mongodb::MongoRepo;
+use crate::model::username::{Username, UsernameError};
 use crate::repo::database::base::{Database, DatabaseError};
+use crate::api::auth::{self, AuthError};
+use crate::config::Config;
 
 
 use actix_web::{
@@ -13,9 +16,11 @@ use actix_web::{
     web::Data,
     web::Payload,
     web::BytesMut,
+    HttpRequest,
     HttpResponse,
     http::{header::ContentType, StatusCode}
 };
+use std::collections::{HashMap, HashSet};
 use futures_util::StreamExt;
 use serde::{Serialize, Deserialize};
 use strum_macros::Display;
@@ -26,21 +31,66 @@ use serde_json;
 pub struct UserUuid {
     user_uuid: String
 }
+#[derive(Deserialize, Serialize)]
+pub struct UserLookup {
+    user_uuids: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct UserLookupResult {
+    users: Vec<UserView>,
+    missing: Vec<String>,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct NewUser {
     user_name: String,
-    password: String
+    password: String,
+    #[serde(default)]
+    username: Option<String>,
 }
 #[derive(Debug, Display)]
 pub enum NewUserError {
     ServerFailure,
     UserAlreadyExists,
+    UsernameTaken,
+    InvalidEmail,
+    InvalidUsername(UsernameError),
     BadRequest,
+    ServiceUnavailable,
 }
 
 #[derive(Debug, Display)]
 pub enum UserGetError {
+    NoToken,
+    NotAuthorized,
+    MalformedRequest,
     NotFound,
+    TooManyUuids,
+    ServerFailure,
+    ServiceUnavailable,
+}
+
+impl From<AuthError> for UserGetError {
+    fn from(error: AuthError) -> UserGetError {
+        match error {
+            AuthError::NoToken => return UserGetError::NoToken,
+            AuthError::NotAuthorized => return UserGetError::NotAuthorized,
+            AuthError::MalformedRequest => return UserGetError::MalformedRequest,
+        }
+    }
+}
+
+impl From<DatabaseError> for UserGetError {
+    fn from(error: DatabaseError) -> UserGetError {
+        tracing::error!(?error, "database failure");
+
+        if error.is_transient() {
+            return UserGetError::ServiceUnavailable;
+        }
+
+        return UserGetError::ServerFailure;
+    }
 }
 
 impl ResponseError for UserGetError {
@@ -52,7 +102,13 @@ impl ResponseError for UserGetError {
 
     fn status_code(&self) -> StatusCode {
         match self {
+            UserGetError::NoToken => StatusCode::UNAUTHORIZED,
+            UserGetError::NotAuthorized => StatusCode::FORBIDDEN,
+            UserGetError::MalformedRequest => StatusCode::BAD_REQUEST,
             UserGetError::NotFound => StatusCode::NOT_FOUND,
+            UserGetError::TooManyUuids => StatusCode::UNPROCESSABLE_ENTITY,
+            UserGetError::ServerFailure => StatusCode::INTERNAL_SERVER_ERROR,
+            UserGetError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
         }
     }
 
@@ -60,39 +116,109 @@ impl ResponseError for UserGetError {
 
 impl ResponseError for NewUserError {
     fn error_response(&self) -> HttpResponse {
+        let body = match self {
+            NewUserError::InvalidUsername(error) => error.to_string(),
+            error => error.to_string(),
+        };
+
         HttpResponse::build(self.status_code())
         .insert_header(ContentType::json())
-        .body(self.to_string())
+        .body(body)
     }
 
     fn status_code(&self) -> StatusCode {
         match self {
             NewUserError::ServerFailure => StatusCode::INTERNAL_SERVER_ERROR,
             NewUserError::UserAlreadyExists => StatusCode::CONFLICT,
+            NewUserError::UsernameTaken => StatusCode::CONFLICT,
+            NewUserError::InvalidEmail => StatusCode::UNPROCESSABLE_ENTITY,
+            NewUserError::InvalidUsername(_) => StatusCode::UNPROCESSABLE_ENTITY,
             NewUserError::BadRequest => StatusCode::BAD_REQUEST,
+            NewUserError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
         }
     }
 
 }
 
+// The whole record for the user themselves or an Admin (same as GET /me),
+// the PublicUser view for anyone else who is signed in.
+fn view_for(claims: &TokenClaims, user: User) -> UserView {
+
+    if auth::self_or_admin(claims, &user.user_uuid).is_ok() {
+        return UserView::Full(Box::new(user));
+    }
+
+    return UserView::Public(PublicUser::from(user));
+}
+
 #[get("/user/{user_uuid}")]
+#[tracing::instrument(skip_all)]
 pub async fn get_user(
         user_uuid: Path<UserUuid>,
-        mongo_repo: Data<MongoRepo>,
-        ) -> Result<Json<User>, UserGetError>{
-    
-    let user = mongo_repo.get_user(user_uuid.into_inner().user_uuid).await;
-
-    match user {
-        Some(user) => Ok(Json(user)),
-        None => Err(UserGetError::NotFound)
+        database: Data<dyn Database>,
+        req: HttpRequest,
+        ) -> Result<HttpResponse, UserGetError>{
+
+    let user_uuid = user_uuid.into_inner().user_uuid;
+
+    let claims = auth::full_token_claims(&req)?;
+
+    let user = match database.get_user(user_uuid.clone()).await? {
+        Some(user) => user,
+        None => return Err(UserGetError::NotFound),
+    };
+
+    return Ok(HttpResponse::Ok().json(view_for(&claims, user)));
+}
+
+// Resolves many uuids in one call for services that would otherwise loop
+// over GET /user/{user_uuid}. Each user gets the same view as there, users
+// come back in request order and uuids with no user are listed in missing.
+#[post("/users/lookup")]
+#[tracing::instrument(skip_all)]
+pub async fn lookup_users(
+    request: Json<UserLookup>,
+    database: Data<dyn Database>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<Json<UserLookupResult>, UserGetError> {
+
+    let claims = auth::full_token_claims(&req)?;
+
+    let mut user_uuids = request.into_inner().user_uuids;
+
+    // duplicates are dropped rather than counted against the limit
+    let mut seen = HashSet::new();
+    user_uuids.retain(|user_uuid| seen.insert(user_uuid.clone()));
+
+    if user_uuids.len() > config.lookup.max_batch {
+        return Err(UserGetError::TooManyUuids);
+    }
+
+    let mut found: HashMap<String, User> = database.get_users(user_uuids.clone()).await?
+        .into_iter()
+        .map(|user| (user.user_uuid.clone(), user))
+        .collect();
+
+    let mut users = Vec::new();
+    let mut missing = Vec::new();
+
+    for user_uuid in user_uuids {
+        match found.remove(&user_uuid) {
+            Some(user) => users.push(view_for(&claims, user)),
+            None => missing.push(user_uuid),
+        }
     }
+
+    return Ok(Json(UserLookupResult { users, missing }));
 }
 
 #[post("/new/user")]
+#[tracing::instrument(skip_all)]
 pub async fn new_user (
     mut payload: Payload,
-    mongo_repo: Data<MongoRepo>,
+    database: Data<dyn Database>,
+    config: Data<Config>,
 ) -> Result<Json<User>, NewUserError> {
 
     let mut body = BytesMut::new();
@@ -109,48 +235,64 @@ pub async fn new_user (
 
     let user = obj_result.unwrap();
 
-    let user_exists = mongo_repo.get_user_by_user_name(user.user_name.clone()).await;
-
-    if user_exists.is_some() {
-        return Err(NewUserError::UserAlreadyExists);
+    // login tells emails and usernames apart by the '@', see LoginName
+    if !valid_email(&canonical_email(&user.user_name)) {
+        return Err(NewUserError::InvalidEmail);
     }
 
+    let username = match user.username.as_deref() {
+        Some(username) => Some(Username::parse(username, &config.username).map_err(NewUserError::InvalidUsername)?),
+        None => None,
+    };
+
     let mut user_obj: User = User::new(user.user_name.clone());
 
-    let mut user_insert_status = mongo_repo.insert_user(user_obj.clone()).await;
+    if let Some(username) = username.clone() {
+        user_obj.set_username(username);
+    }
+
+    let mut credentail_obj: UserCredentail = UserCredentail::new(user_obj.clone(), user.password.clone(), config.hashing.bcrypt_cost);
+
+    let mut user_insert_status = database.insert_user_with_credentail(user_obj.clone(), credentail_obj.clone()).await;
 
     if user_insert_status.is_err() {
 
-        while user_insert_status.as_ref().err() == Some(&DatabaseError::UserUuidExists) {
+        while matches!(user_insert_status, Err(DatabaseError::UserUuidExists)) {
             user_obj = User::new(user.user_name.clone());
-            user_insert_status = mongo_repo.insert_user(user_obj.clone()).await;
+            if let Some(username) = username.clone() {
+                user_obj.set_username(username);
+            }
+            credentail_obj.user_uuid = user_obj.user_uuid.clone();
+            user_insert_status = database.insert_user_with_credentail(user_obj.clone(), credentail_obj.clone()).await;
         }
 
     }
 
     drop(user_obj);
+    drop(credentail_obj);
 
     if user_insert_status.is_err() {
         match user_insert_status.as_ref().err().unwrap() {
             DatabaseError::UserNameExists => return Err(NewUserError::UserAlreadyExists),
-            DatabaseError::DBFailure => return Err(NewUserError::ServerFailure),
+            DatabaseError::UsernameTaken => return Err(NewUserError::UsernameTaken),
+            error @ DatabaseError::DBFailure { .. } => {
+                tracing::error!(?error, "database failure");
+
+                if error.is_transient() {
+                    return Err(NewUserError::ServiceUnavailable);
+                }
+
+                return Err(NewUserError::ServerFailure);
+            },
             DatabaseError::UserDoesntExist => return Err(NewUserError::ServerFailure),
             DatabaseError::UserUuidExists => return Err(NewUserError::ServerFailure),
+            // signup doesn't take a phone number
+            DatabaseError::PhoneNumberTaken => return Err(NewUserError::ServerFailure),
         }
     }
 
     let user_db_obj = user_insert_status.unwrap();
 
-    let credentail_obj: UserCredentail = UserCredentail::new(user_db_obj.clone(), user.password.clone());
-
-    let credentail_insert_status = mongo_repo.insert_credentail(credentail_obj).await;
-
-    if credentail_insert_status.as_ref().is_err() {
-        let _ = mongo_repo.delete_user(user_db_obj.clone()).await;
-
-        return Err(NewUserError::BadRequest);
-    }
-
     return Ok(Json(user_db_obj));    
 
 }
@@ -0,0 +1,411 @@
+// Operator tool for bootstrapping and repairing accounts without touching
+// the database by hand. Reads the same config.toml/USERAUTH_* settings as
+// the server and goes through the Database trait, so it works with either
+// backend.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use clap::{Parser, Subcommand};
+use dotenv::dotenv;
+use tracing_subscriber::EnvFilter;
+use user_auth_mongodb::config::Config;
+use user_auth_mongodb::secrets::SecretKeys;
+use user_auth_mongodb::model::claims::ClaimsUserType;
+use user_auth_mongodb::model::credentail::UserCredentail;
+use user_auth_mongodb::model::privacy::{DataExport, ErasureReceipt};
+use user_auth_mongodb::model::token::{Authentication, Token, TokenAuthType};
+use user_auth_mongodb::model::user::{User, UserState};
+use user_auth_mongodb::model::username::{LoginName, Username};
+use user_auth_mongodb::repo::blob;
+use user_auth_mongodb::repo::bulk::{self, BulkFormat, RecordWriter};
+use user_auth_mongodb::repo::database::{connect, base::Database};
+
+#[derive(Parser)]
+#[command(name = "userauth-admin", about = "Administer user accounts")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create a user with a password, e.g. the first Admin
+    CreateUser {
+        #[arg(long)]
+        email: String,
+        #[arg(long)]
+        username: Option<String>,
+        /// Read from stdin when left out, to keep it out of shell history
+        #[arg(long)]
+        password: Option<String>,
+        #[arg(long, default_value = "User")]
+        user_type: ClaimsUserType,
+        #[arg(long, default_value = "Active")]
+        state: UserState,
+    },
+    /// Replace a user's password, the old one goes into the password history
+    SetPassword {
+        /// user uuid, email or username
+        user: String,
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Change a user's state, e.g. Disabled to lock an account
+    SetState {
+        user: String,
+        state: UserState,
+    },
+    /// Remove all of the user's MFA factors so they can enrol again
+    ResetMfa {
+        user: String,
+    },
+    /// List users ordered by email
+    ListUsers {
+        #[arg(long, default_value_t = 0)]
+        skip: u64,
+        #[arg(long, default_value_t = 100)]
+        limit: u64,
+    },
+    /// Print a signed token for the user, for debugging
+    MintToken {
+        user: String,
+        #[arg(long, default_value = "Full")]
+        auth_type: TokenAuthType,
+        /// Defaults to token.ttl_minutes
+        #[arg(long)]
+        ttl_minutes: Option<i64>,
+    },
+    /// Print everything stored about a user as JSON, for data subject requests
+    ExportUserData {
+        user: String,
+    },
+    /// Delete a user, their credentail and avatar, printing a signed erasure receipt
+    EraseUser {
+        user: String,
+    },
+    /// Import users with pre-hashed passwords, one row per user
+    ImportUsers {
+        /// File to read, - for stdin
+        file: PathBuf,
+        #[arg(long, default_value = "jsonl")]
+        format: BulkFormat,
+        /// Validate every row and check for clashes without writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Apply pending schema migrations, for when database.migrate_on_start is off
+    Migrate,
+    /// Export users with their password hashes and MFA secrets
+    ExportUsers {
+        /// File to write, stdout when left out
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// csv holds one MFA factor per user and stops at a user with more
+        #[arg(long, default_value = "jsonl")]
+        format: BulkFormat,
+    },
+    /// Re-encrypt every MFA secret with secrets.active_key, after rotating keys
+    ReencryptSecrets,
+}
+
+const REENCRYPT_PAGE_SIZE: u64 = 500;
+// reads of one credentail before giving up on it
+const RESEAL_ATTEMPTS: u32 = 5;
+
+fn read_password(password: Option<String>) -> Result<String, String> {
+
+    if let Some(password) = password {
+        return Ok(password);
+    }
+
+    let mut line = String::new();
+
+    io::stdin().lock().read_line(&mut line).map_err(|error| format!("could not read password: {}", error))?;
+
+    let password = line.trim_end_matches(['\r', '\n']).to_owned();
+
+    if password.is_empty() {
+        return Err("password must not be empty".to_owned());
+    }
+
+    return Ok(password);
+}
+
+// Accepts a uuid, an email or a username so operators can use whichever they have.
+async fn find_user(database: &Arc<dyn Database>, user: &str) -> Result<User, String> {
+
+    let by_uuid = database.get_user(user.to_owned()).await.map_err(|error| format!("{:?}", error))?;
+
+    if let Some(found) = by_uuid {
+        return Ok(found);
+    }
+
+    let by_name = database.get_user_by_login_name(LoginName::parse(user)).await.map_err(|error| format!("{:?}", error))?;
+
+    return by_name.ok_or(format!("no user {}", user));
+}
+
+async fn find_credentail(database: &Arc<dyn Database>, user: &User) -> Result<UserCredentail, String> {
+
+    let credentail = database.get_credentail(user.user_uuid.clone()).await.map_err(|error| format!("{:?}", error))?;
+
+    return credentail.ok_or(format!("user {} has no credentail", user.user_uuid));
+}
+
+async fn run(command: Command, database: Arc<dyn Database>, config: Config) -> Result<(), String> {
+
+    match command {
+        Command::CreateUser { email, username, password, user_type, state } => {
+            let password = read_password(password)?;
+
+            let mut user = User::new(email);
+
+            if let Some(username) = username {
+                user.set_username(Username::parse(&username, &config.username).map_err(|error| error.to_string())?);
+            }
+
+            user.user_state = state;
+            user.user_claims.user_type = user_type;
+
+            let credentail = UserCredentail::new(user.clone(), password, config.hashing.bcrypt_cost);
+
+            let user = database.insert_user_with_credentail(user, credentail).await.map_err(|error| format!("{:?}", error))?;
+
+            println!("{}", user.user_uuid);
+        },
+        Command::SetPassword { user, password } => {
+            let user = find_user(&database, &user).await?;
+            let mut credentail = find_credentail(&database, &user).await?;
+
+            credentail.update_password(read_password(password)?, config.hashing.bcrypt_cost);
+
+            database.update_credentail(credentail).await.map_err(|error| format!("{:?}", error))?;
+        },
+        Command::SetState { user, state } => {
+            let mut user = find_user(&database, &user).await?;
+            user.user_state = state;
+
+            database.update_user(user).await.map_err(|error| format!("{:?}", error))?;
+        },
+        Command::ResetMfa { user } => {
+            let user = find_user(&database, &user).await?;
+            let mut credentail = find_credentail(&database, &user).await?;
+
+            credentail.remove_mfa();
+
+            database.update_credentail(credentail).await.map_err(|error| format!("{:?}", error))?;
+        },
+        Command::ListUsers { skip, limit } => {
+            let users = database.list_users(skip, limit).await.map_err(|error| format!("{:?}", error))?;
+
+            for user in users {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    user.user_uuid,
+                    user.user_email,
+                    user.user_state,
+                    user.user_claims.user_type,
+                    user.last_login.try_to_rfc3339_string().unwrap_or_default(),
+                );
+            }
+        },
+        Command::MintToken { user, auth_type, ttl_minutes } => {
+            let user = find_user(&database, &user).await?;
+            let ttl = ttl_minutes.unwrap_or(config.token.ttl_minutes);
+
+            let profile = user.profile.token_claims(&config.profile.token_claims);
+
+            // the user didn't authenticate, so no amr and the lowest acr
+            let authentication = Authentication { auth_time: chrono::Utc::now().timestamp(), amr: Vec::new() };
+
+            let token = Token::new(user.user_uuid.clone(), ttl, user.user_claims.clone(), auth_type, profile, authentication)
+                .map_err(|error| format!("could not sign token: {}", error))?;
+
+            println!("{}", token.token.unwrap_or_default());
+        },
+        Command::ExportUserData { user } => {
+            let user = find_user(&database, &user).await?;
+            let credentail = database.get_credentail(user.user_uuid.clone()).await.map_err(|error| format!("{:?}", error))?;
+
+            let export = serde_json::to_string_pretty(&DataExport::new(user, credentail)).map_err(|error| error.to_string())?;
+
+            println!("{}", export);
+        },
+        Command::EraseUser { user } => {
+            let user = find_user(&database, &user).await?;
+            let user_uuid = user.user_uuid.clone();
+            let credentail = database.get_credentail(user_uuid.clone()).await.map_err(|error| format!("{:?}", error))?;
+
+            let user = database.delete_user(user).await.map_err(|error| format!("{:?}", error))?;
+
+            let mut erased = vec!["user".to_owned()];
+
+            if credentail.is_some() {
+                erased.push("credentail".to_owned());
+            }
+
+            if let Some(avatar) = user.profile.avatar {
+                blob::open(&config.profile).delete(&avatar).await.map_err(|error| format!("user erased but not their avatar: {}", error))?;
+                erased.push("avatar".to_owned());
+            }
+
+            let receipt = ErasureReceipt::new(user_uuid, "userauth-admin".to_owned(), erased)
+                .map_err(|error| format!("user erased but the receipt could not be signed: {}", error))?;
+
+            println!("{}", serde_json::to_string_pretty(&receipt).map_err(|error| error.to_string())?);
+        },
+        Command::ImportUsers { file, format, dry_run } => {
+            let reader: Box<dyn Read> = match file.to_str() {
+                Some("-") => Box::new(io::stdin().lock()),
+                _ => Box::new(File::open(&file).map_err(|error| format!("could not open {}: {}", file.display(), error))?),
+            };
+
+            let rows = bulk::read_records(reader, format);
+
+            let report = bulk::import_users(&*database, rows, &config.username, &SecretKeys::from_config(&config.secrets), dry_run, |row, message| eprintln!("row {}: {}", row, message))
+                .await
+                .map_err(|error| error.to_string())?;
+
+            let verb = if dry_run { "would import" } else { "imported" };
+            eprintln!("{} {}, {} failed", verb, report.imported, report.failed);
+
+            if report.failed > 0 {
+                return Err(format!("{} rows failed", report.failed));
+            }
+        },
+        Command::Migrate => {
+            let ran = database.migrate().await.map_err(|error| format!("{:?}", error))?;
+
+            if ran.is_empty() {
+                eprintln!("schema is up to date");
+            }
+
+            for migration in ran {
+                println!("{}", migration);
+            }
+        },
+        Command::ExportUsers { output, format } => {
+            let writer: Box<dyn Write> = match output {
+                Some(path) => Box::new(File::create(&path).map_err(|error| format!("could not create {}: {}", path.display(), error))?),
+                None => Box::new(io::stdout().lock()),
+            };
+
+            let mut writer = RecordWriter::new(BufWriter::new(writer), format);
+
+            let report = bulk::export_users(&*database, &mut writer, &config.mfa, &SecretKeys::from_config(&config.secrets)).await.map_err(|error| error.to_string())?;
+
+            eprintln!("exported {}, {} skipped without a credentail", report.exported, report.skipped);
+        },
+        Command::ReencryptSecrets => {
+            let keys = SecretKeys::from_config(&config.secrets);
+
+            if keys.active_key().is_none() {
+                return Err("secrets.active_key is not set".to_owned());
+            }
+
+            // can run while servers are up: each secret is only replaced if
+            // it is still the one read, a credentail a login changed
+            // meanwhile is read again
+            let mut skip = 0;
+            let mut reencrypted = 0;
+
+            loop {
+                let users = database.list_users(skip, REENCRYPT_PAGE_SIZE).await.map_err(|error| format!("{:?}", error))?;
+                let page_len = users.len() as u64;
+
+                for user in users {
+                    let mut attempts = 0;
+
+                    loop {
+                        let credentail = match database.get_credentail(user.user_uuid.clone()).await.map_err(|error| format!("{:?}", error))? {
+                            Some(credentail) => credentail,
+                            None => break,
+                        };
+
+                        let resealed = credentail.resealed_secrets(&keys).map_err(|error| format!("user {}: {}", user.user_uuid, error))?;
+
+                        if resealed.is_empty() {
+                            break;
+                        }
+
+                        let mut swapped = true;
+
+                        for (slot, old, new) in resealed {
+                            swapped &= database.swap_secret(user.user_uuid.clone(), slot, old, new).await.map_err(|error| format!("{:?}", error))?;
+                        }
+
+                        if swapped {
+                            reencrypted += 1;
+                            break;
+                        }
+
+                        attempts += 1;
+
+                        if attempts == RESEAL_ATTEMPTS {
+                            return Err(format!("user {}: secrets kept changing, run again", user.user_uuid));
+                        }
+                    }
+                }
+
+                if page_len < REENCRYPT_PAGE_SIZE {
+                    break;
+                }
+
+                skip += page_len;
+            }
+
+            eprintln!("re-encrypted the secrets of {} users", reencrypted);
+        },
+    }
+
+    return Ok(());
+}
+
+#[actix_web::main]
+async fn main() -> ExitCode {
+
+    let cli = Cli::parse();
+
+    dotenv().ok();
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or(EnvFilter::new("warn")))
+        .with_writer(io::stderr)
+        .init();
+
+    let mut config = match Config::load() {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("configuration is invalid: {}", error);
+            return ExitCode::FAILURE;
+        },
+    };
+
+    for warning in &config.warnings {
+        tracing::warn!("{}", warning);
+    }
+
+    // so migrate gets to report what it applied
+    if matches!(cli.command, Command::Migrate) {
+        config.database.migrate_on_start = false;
+    }
+
+    let database = match connect::open(&config.database).await {
+        Ok(database) => database,
+        Err(error) => {
+            eprintln!("could not connect to the database: {:?}", error);
+            return ExitCode::FAILURE;
+        },
+    };
+
+    match run(cli.command, database, config).await {
+        Ok(_) => return ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{}", error);
+            return ExitCode::FAILURE;
+        },
+    }
+}
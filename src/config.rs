@@ -0,0 +1,651 @@
+use std::{collections::BTreeMap, env, fmt, fs, io, str::FromStr};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::Deserialize;
+use strum_macros::{EnumString, Display};
+use crate::model::profile::ProfileClaim;
+use crate::model::totp::TotpAlgorithm;
+
+// Settings are read from the toml file named by USERAUTH_CONFIG (config.toml
+// by default, a missing file just means defaults), then USERAUTH_* environment
+// variables override individual values, then everything is validated.
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(io::Error),
+    Parse(toml::de::Error),
+    Env { var: String, value: String },
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(error) => write!(f, "could not read config file: {}", error),
+            ConfigError::Parse(error) => write!(f, "could not parse config file: {}", error),
+            ConfigError::Env { var, value } => write!(f, "{} has an invalid value {:?}", var, value),
+            ConfigError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, EnumString, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum DatabaseBackend {
+    Mongodb,
+    Sql,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, EnumString, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum MailSink {
+    Log,
+    File,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, EnumString, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum SmsSink {
+    File,
+    Http,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub log_level: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub backend: DatabaseBackend,
+    pub url: String,
+    pub name: String,
+    pub connect_attempts: u32,
+    pub connect_backoff_ms: u64,
+    pub migrate_on_start: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TokenConfig {
+    pub ttl_minutes: i64,
+    // how long a login or /reauth counts as recent for operations that ask
+    // for it
+    pub step_up_max_age_minutes: i64,
+    // /reauth tries per user within reauth_window_minutes
+    pub reauth_max_attempts: u32,
+    pub reauth_window_minutes: i64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct HashingConfig {
+    pub bcrypt_cost: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MfaConfig {
+    // algorithm, digits and step only apply to new enrolments, each
+    // credentail keeps what it was enrolled with
+    pub totp_algorithm: TotpAlgorithm,
+    pub totp_digits: usize,
+    pub totp_step: u64,
+    pub totp_skew: u8,
+    // shown next to the account in authenticator apps
+    pub totp_issuer: String,
+    // how long a started authenticator app enrolment waits for its first code
+    pub totp_enrollment_ttl_minutes: i64,
+    // authenticator app codes, per factor, also take code_max_attempts
+    pub totp_attempt_window_minutes: i64,
+    // codes sent by email or SMS
+    pub code_ttl_minutes: i64,
+    pub code_max_attempts: u32,
+    pub code_resend_cooldown_seconds: i64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileConfig {
+    pub avatar_dir: String,
+    pub avatar_max_bytes: usize,
+    pub token_claims: Vec<ProfileClaim>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    pub sink: MailSink,
+    pub file_path: String,
+    pub from: String,
+    // where links in mails point. Has to be a frontend: the API only takes
+    // the tokens by POST, the page behind the link posts them on
+    pub link_base_url: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmailConfig {
+    pub code_ttl_minutes: i64,
+    pub code_max_attempts: u32,
+    pub code_resend_cooldown_seconds: i64,
+    pub revert_ttl_hours: i64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmsConfig {
+    pub sink: SmsSink,
+    pub file_path: String,
+    // the http sink POSTs {from, to, body} as JSON here
+    pub gateway_url: String,
+    pub api_key: String,
+    pub from: String,
+    pub timeout_seconds: u64,
+    // phone number verification and phone login codes
+    pub code_ttl_minutes: i64,
+    pub code_max_attempts: u32,
+    pub code_resend_cooldown_seconds: i64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MagicLinkConfig {
+    pub ttl_minutes: i64,
+    pub max_attempts: u32,
+    pub resend_cooldown_seconds: i64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsernameConfig {
+    pub min_length: usize,
+    pub max_length: usize,
+    // compared after normalization, so "Admin" and "ａｄｍｉｎ" are caught too
+    pub reserved: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LookupConfig {
+    // most uuids one POST /users/lookup may ask for
+    pub max_batch: usize,
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecretsConfig {
+    // key id to base64 of 32 random bytes, AES-256-GCM data keys for MFA
+    // secrets. Keep retired keys until nothing is encrypted with them.
+    pub keys: BTreeMap<String, String>,
+    // the key new secrets are encrypted with, empty stores them in plaintext
+    pub active_key: String,
+    // the server refuses to start without active_key unless this is set
+    pub allow_plaintext: bool,
+}
+
+// keys stay out of logs and panics
+impl fmt::Debug for SecretsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecretsConfig")
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .field("active_key", &self.active_key)
+            .field("allow_plaintext", &self.allow_plaintext)
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    pub otlp_endpoint: String,
+    pub service_name: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub token: TokenConfig,
+    pub hashing: HashingConfig,
+    pub mfa: MfaConfig,
+    pub profile: ProfileConfig,
+    pub mail: MailConfig,
+    pub email: EmailConfig,
+    pub sms: SmsConfig,
+    pub magic_link: MagicLinkConfig,
+    pub username: UsernameConfig,
+    pub lookup: LookupConfig,
+    pub secrets: SecretsConfig,
+    pub tracing: TracingConfig,
+    // deprecations noticed while loading, logged once tracing is up
+    #[serde(skip)]
+    pub warnings: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        return ServerConfig {
+            host: "127.0.0.1".to_owned(),
+            port: 8000,
+            log_level: "info".to_owned(),
+        };
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> DatabaseConfig {
+        return DatabaseConfig {
+            backend: DatabaseBackend::Mongodb,
+            url: String::new(),
+            name: "userauth".to_owned(),
+            connect_attempts: 10,
+            connect_backoff_ms: 500,
+            migrate_on_start: true,
+        };
+    }
+}
+
+impl Default for TokenConfig {
+    fn default() -> TokenConfig {
+        return TokenConfig {
+            ttl_minutes: 180,
+            step_up_max_age_minutes: 10,
+            reauth_max_attempts: 5,
+            reauth_window_minutes: 15,
+        };
+    }
+}
+
+impl Default for HashingConfig {
+    fn default() -> HashingConfig {
+        return HashingConfig {
+            bcrypt_cost: 10,
+        };
+    }
+}
+
+impl Default for MfaConfig {
+    fn default() -> MfaConfig {
+        return MfaConfig {
+            totp_algorithm: TotpAlgorithm::SHA1,
+            totp_digits: 6,
+            totp_step: 30,
+            totp_skew: 1,
+            totp_issuer: "userauth".to_owned(),
+            totp_enrollment_ttl_minutes: 15,
+            totp_attempt_window_minutes: 15,
+            code_ttl_minutes: 10,
+            code_max_attempts: 5,
+            code_resend_cooldown_seconds: 60,
+        };
+    }
+}
+
+impl Default for ProfileConfig {
+    fn default() -> ProfileConfig {
+        return ProfileConfig {
+            avatar_dir: "avatars".to_owned(),
+            avatar_max_bytes: 1024 * 1024,
+            token_claims: Vec::new(),
+        };
+    }
+}
+
+impl Default for MailConfig {
+    fn default() -> MailConfig {
+        return MailConfig {
+            sink: MailSink::Log,
+            file_path: "mail.log".to_owned(),
+            from: "no-reply@localhost".to_owned(),
+            link_base_url: String::new(),
+        };
+    }
+}
+
+impl Default for EmailConfig {
+    fn default() -> EmailConfig {
+        return EmailConfig {
+            code_ttl_minutes: 15,
+            code_max_attempts: 5,
+            code_resend_cooldown_seconds: 60,
+            revert_ttl_hours: 24 * 7,
+        };
+    }
+}
+
+impl Default for SmsConfig {
+    fn default() -> SmsConfig {
+        return SmsConfig {
+            sink: SmsSink::File,
+            file_path: "sms.log".to_owned(),
+            gateway_url: String::new(),
+            api_key: String::new(),
+            from: "userauth".to_owned(),
+            timeout_seconds: 10,
+            code_ttl_minutes: 10,
+            code_max_attempts: 5,
+            code_resend_cooldown_seconds: 60,
+        };
+    }
+}
+
+impl Default for MagicLinkConfig {
+    fn default() -> MagicLinkConfig {
+        return MagicLinkConfig {
+            ttl_minutes: 15,
+            max_attempts: 5,
+            resend_cooldown_seconds: 60,
+        };
+    }
+}
+
+impl Default for UsernameConfig {
+    fn default() -> UsernameConfig {
+        return UsernameConfig {
+            min_length: 3,
+            max_length: 32,
+            reserved: [
+                "admin", "administrator", "root", "system", "support", "help", "security",
+                "abuse", "postmaster", "webmaster", "hostmaster", "noreply", "no-reply",
+                "me", "api", "user", "users", "auth", "login", "logout", "signup", "settings",
+            ].iter().map(|name| name.to_string()).collect(),
+        };
+    }
+}
+
+impl Default for LookupConfig {
+    fn default() -> LookupConfig {
+        return LookupConfig {
+            max_batch: 100,
+        };
+    }
+}
+
+impl Default for TracingConfig {
+    fn default() -> TracingConfig {
+        return TracingConfig {
+            otlp_endpoint: String::new(),
+            service_name: "userauth".to_owned(),
+        };
+    }
+}
+
+// id=base64 pairs separated by commas, replacing the keys from the file.
+fn keys_from_env(target: &mut BTreeMap<String, String>, var: &str) -> Result<(), ConfigError> {
+
+    let value = match env::var(var) {
+        Ok(value) => value,
+        Err(_) => return Ok(()),
+    };
+
+    let mut keys = BTreeMap::new();
+
+    for pair in value.split(',').filter(|pair| !pair.trim().is_empty()) {
+        match pair.split_once('=') {
+            Some((key_id, key)) => keys.insert(key_id.trim().to_owned(), key.trim().to_owned()),
+            None => return Err(ConfigError::Env { var: var.to_owned(), value: "<redacted>".to_owned() }),
+        };
+    }
+
+    *target = keys;
+
+    return Ok(());
+}
+
+fn override_from_env<T: FromStr>(target: &mut T, var: &str) -> Result<(), ConfigError> {
+
+    let value = match env::var(var) {
+        Ok(value) => value,
+        Err(_) => return Ok(()),
+    };
+
+    match value.parse::<T>() {
+        Ok(parsed) => *target = parsed,
+        Err(_) => return Err(ConfigError::Env { var: var.to_owned(), value }),
+    }
+
+    return Ok(());
+}
+
+impl Config {
+
+    pub fn load() -> Result<Config, ConfigError> {
+
+        let path = env::var("USERAUTH_CONFIG").unwrap_or("config.toml".to_owned());
+
+        let mut config = match fs::read_to_string(&path) {
+            Ok(contents) => Config::parse(&contents)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Config::default(),
+            Err(error) => return Err(ConfigError::Read(error)),
+        };
+
+        config.apply_env()?;
+        config.validate()?;
+
+        return Ok(config);
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, ConfigError> {
+        return toml::from_str(contents).map_err(ConfigError::Parse);
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+
+        // MONGOURL, DATABASE_URL and DATABASE_BACKEND predate the config
+        // file, keep honouring them below the USERAUTH_* names
+        for (old, new) in [("MONGOURL", "USERAUTH_DATABASE_URL"), ("DATABASE_URL", "USERAUTH_DATABASE_URL"), ("DATABASE_BACKEND", "USERAUTH_DATABASE_BACKEND")] {
+            if env::var(old).is_ok() {
+                self.warnings.push(format!("{} is deprecated, use {} instead", old, new));
+            }
+        }
+
+        override_from_env(&mut self.database.url, "MONGOURL")?;
+        override_from_env(&mut self.database.url, "DATABASE_URL")?;
+        override_from_env(&mut self.database.backend, "DATABASE_BACKEND")?;
+
+        override_from_env(&mut self.server.host, "USERAUTH_SERVER_HOST")?;
+        override_from_env(&mut self.server.port, "USERAUTH_SERVER_PORT")?;
+        override_from_env(&mut self.server.log_level, "USERAUTH_SERVER_LOG_LEVEL")?;
+        override_from_env(&mut self.database.backend, "USERAUTH_DATABASE_BACKEND")?;
+        override_from_env(&mut self.database.url, "USERAUTH_DATABASE_URL")?;
+        override_from_env(&mut self.database.name, "USERAUTH_DATABASE_NAME")?;
+        override_from_env(&mut self.database.connect_attempts, "USERAUTH_DATABASE_CONNECT_ATTEMPTS")?;
+        override_from_env(&mut self.database.connect_backoff_ms, "USERAUTH_DATABASE_CONNECT_BACKOFF_MS")?;
+        override_from_env(&mut self.database.migrate_on_start, "USERAUTH_DATABASE_MIGRATE_ON_START")?;
+        override_from_env(&mut self.token.ttl_minutes, "USERAUTH_TOKEN_TTL_MINUTES")?;
+        override_from_env(&mut self.token.step_up_max_age_minutes, "USERAUTH_TOKEN_STEP_UP_MAX_AGE_MINUTES")?;
+        override_from_env(&mut self.token.reauth_max_attempts, "USERAUTH_TOKEN_REAUTH_MAX_ATTEMPTS")?;
+        override_from_env(&mut self.token.reauth_window_minutes, "USERAUTH_TOKEN_REAUTH_WINDOW_MINUTES")?;
+        override_from_env(&mut self.hashing.bcrypt_cost, "USERAUTH_HASHING_BCRYPT_COST")?;
+        override_from_env(&mut self.mfa.totp_algorithm, "USERAUTH_MFA_TOTP_ALGORITHM")?;
+        override_from_env(&mut self.mfa.totp_digits, "USERAUTH_MFA_TOTP_DIGITS")?;
+        override_from_env(&mut self.mfa.totp_step, "USERAUTH_MFA_TOTP_STEP")?;
+        override_from_env(&mut self.mfa.totp_skew, "USERAUTH_MFA_TOTP_SKEW")?;
+        override_from_env(&mut self.mfa.totp_issuer, "USERAUTH_MFA_TOTP_ISSUER")?;
+        override_from_env(&mut self.mfa.totp_enrollment_ttl_minutes, "USERAUTH_MFA_TOTP_ENROLLMENT_TTL_MINUTES")?;
+        override_from_env(&mut self.mfa.totp_attempt_window_minutes, "USERAUTH_MFA_TOTP_ATTEMPT_WINDOW_MINUTES")?;
+        override_from_env(&mut self.mfa.code_ttl_minutes, "USERAUTH_MFA_CODE_TTL_MINUTES")?;
+        override_from_env(&mut self.mfa.code_max_attempts, "USERAUTH_MFA_CODE_MAX_ATTEMPTS")?;
+        override_from_env(&mut self.mfa.code_resend_cooldown_seconds, "USERAUTH_MFA_CODE_RESEND_COOLDOWN_SECONDS")?;
+        override_from_env(&mut self.profile.avatar_dir, "USERAUTH_PROFILE_AVATAR_DIR")?;
+        override_from_env(&mut self.profile.avatar_max_bytes, "USERAUTH_PROFILE_AVATAR_MAX_BYTES")?;
+        override_from_env(&mut self.mail.sink, "USERAUTH_MAIL_SINK")?;
+        override_from_env(&mut self.mail.file_path, "USERAUTH_MAIL_FILE_PATH")?;
+        override_from_env(&mut self.mail.from, "USERAUTH_MAIL_FROM")?;
+        override_from_env(&mut self.mail.link_base_url, "USERAUTH_MAIL_LINK_BASE_URL")?;
+        override_from_env(&mut self.email.code_ttl_minutes, "USERAUTH_EMAIL_CODE_TTL_MINUTES")?;
+        override_from_env(&mut self.email.code_max_attempts, "USERAUTH_EMAIL_CODE_MAX_ATTEMPTS")?;
+        override_from_env(&mut self.email.code_resend_cooldown_seconds, "USERAUTH_EMAIL_CODE_RESEND_COOLDOWN_SECONDS")?;
+        override_from_env(&mut self.email.revert_ttl_hours, "USERAUTH_EMAIL_REVERT_TTL_HOURS")?;
+        override_from_env(&mut self.sms.sink, "USERAUTH_SMS_SINK")?;
+        override_from_env(&mut self.sms.file_path, "USERAUTH_SMS_FILE_PATH")?;
+        override_from_env(&mut self.sms.gateway_url, "USERAUTH_SMS_GATEWAY_URL")?;
+        override_from_env(&mut self.sms.api_key, "USERAUTH_SMS_API_KEY")?;
+        override_from_env(&mut self.sms.from, "USERAUTH_SMS_FROM")?;
+        override_from_env(&mut self.sms.timeout_seconds, "USERAUTH_SMS_TIMEOUT_SECONDS")?;
+        override_from_env(&mut self.sms.code_ttl_minutes, "USERAUTH_SMS_CODE_TTL_MINUTES")?;
+        override_from_env(&mut self.sms.code_max_attempts, "USERAUTH_SMS_CODE_MAX_ATTEMPTS")?;
+        override_from_env(&mut self.sms.code_resend_cooldown_seconds, "USERAUTH_SMS_CODE_RESEND_COOLDOWN_SECONDS")?;
+        override_from_env(&mut self.magic_link.ttl_minutes, "USERAUTH_MAGIC_LINK_TTL_MINUTES")?;
+        override_from_env(&mut self.magic_link.max_attempts, "USERAUTH_MAGIC_LINK_MAX_ATTEMPTS")?;
+        override_from_env(&mut self.magic_link.resend_cooldown_seconds, "USERAUTH_MAGIC_LINK_RESEND_COOLDOWN_SECONDS")?;
+        override_from_env(&mut self.username.min_length, "USERAUTH_USERNAME_MIN_LENGTH")?;
+        override_from_env(&mut self.username.max_length, "USERAUTH_USERNAME_MAX_LENGTH")?;
+        override_from_env(&mut self.lookup.max_batch, "USERAUTH_LOOKUP_MAX_BATCH")?;
+        keys_from_env(&mut self.secrets.keys, "USERAUTH_SECRETS_KEYS")?;
+        override_from_env(&mut self.secrets.active_key, "USERAUTH_SECRETS_ACTIVE_KEY")?;
+        override_from_env(&mut self.secrets.allow_plaintext, "USERAUTH_SECRETS_ALLOW_PLAINTEXT")?;
+        override_from_env(&mut self.tracing.otlp_endpoint, "USERAUTH_TRACING_OTLP_ENDPOINT")?;
+        override_from_env(&mut self.tracing.service_name, "USERAUTH_TRACING_SERVICE_NAME")?;
+
+        return Ok(());
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+
+        if self.server.port == 0 {
+            return Err(ConfigError::Invalid("server.port must not be 0".to_owned()));
+        }
+
+        if tracing::level_filters::LevelFilter::from_str(&self.server.log_level).is_err() {
+            return Err(ConfigError::Invalid(format!("server.log_level {} is not a log level", self.server.log_level)));
+        }
+
+        if self.database.url.is_empty() {
+            return Err(ConfigError::Invalid("database.url needs to be set".to_owned()));
+        }
+
+        if self.database.name.is_empty() {
+            return Err(ConfigError::Invalid("database.name needs to be set".to_owned()));
+        }
+
+        if self.database.connect_attempts == 0 {
+            return Err(ConfigError::Invalid("database.connect_attempts must be at least 1".to_owned()));
+        }
+
+        if self.token.ttl_minutes <= 0 || self.token.step_up_max_age_minutes <= 0 {
+            return Err(ConfigError::Invalid("token.ttl_minutes and token.step_up_max_age_minutes must be positive".to_owned()));
+        }
+
+        if self.token.reauth_max_attempts == 0 || self.token.reauth_window_minutes <= 0 {
+            return Err(ConfigError::Invalid("token.reauth_max_attempts must be at least 1 and token.reauth_window_minutes positive".to_owned()));
+        }
+
+        if !(4..=31).contains(&self.hashing.bcrypt_cost) {
+            return Err(ConfigError::Invalid("hashing.bcrypt_cost must be between 4 and 31".to_owned()));
+        }
+
+        if self.mfa.totp_step == 0 {
+            return Err(ConfigError::Invalid("mfa.totp_step must be positive".to_owned()));
+        }
+
+        if !(6..=8).contains(&self.mfa.totp_digits) {
+            return Err(ConfigError::Invalid("mfa.totp_digits must be 6, 7 or 8".to_owned()));
+        }
+
+        if self.mfa.totp_issuer.is_empty() || self.mfa.totp_issuer.contains(':') {
+            return Err(ConfigError::Invalid("mfa.totp_issuer needs to be set and can't contain ':'".to_owned()));
+        }
+
+        if self.mfa.totp_enrollment_ttl_minutes <= 0 {
+            return Err(ConfigError::Invalid("mfa.totp_enrollment_ttl_minutes must be positive".to_owned()));
+        }
+
+        if self.mfa.totp_attempt_window_minutes <= 0 {
+            return Err(ConfigError::Invalid("mfa.totp_attempt_window_minutes must be positive".to_owned()));
+        }
+
+        if self.mfa.code_ttl_minutes <= 0 || self.mfa.code_resend_cooldown_seconds < 0 {
+            return Err(ConfigError::Invalid("mfa.code_ttl_minutes must be positive and mfa.code_resend_cooldown_seconds not negative".to_owned()));
+        }
+
+        if self.mfa.code_max_attempts == 0 {
+            return Err(ConfigError::Invalid("mfa.code_max_attempts must be at least 1".to_owned()));
+        }
+
+        if self.profile.avatar_dir.is_empty() {
+            return Err(ConfigError::Invalid("profile.avatar_dir needs to be set".to_owned()));
+        }
+
+        if self.profile.avatar_max_bytes == 0 {
+            return Err(ConfigError::Invalid("profile.avatar_max_bytes must be positive".to_owned()));
+        }
+
+        if !self.mail.link_base_url.starts_with("http://") && !self.mail.link_base_url.starts_with("https://") {
+            return Err(ConfigError::Invalid("mail.link_base_url needs to be the http(s) url of the frontend that handles mailed links".to_owned()));
+        }
+
+        if self.mail.sink == MailSink::File && self.mail.file_path.is_empty() {
+            return Err(ConfigError::Invalid("mail.file_path needs to be set for the file sink".to_owned()));
+        }
+
+        if self.email.code_ttl_minutes <= 0 || self.email.revert_ttl_hours <= 0 {
+            return Err(ConfigError::Invalid("email.code_ttl_minutes and email.revert_ttl_hours must be positive".to_owned()));
+        }
+
+        if self.email.code_max_attempts == 0 {
+            return Err(ConfigError::Invalid("email.code_max_attempts must be at least 1".to_owned()));
+        }
+
+        if self.email.code_resend_cooldown_seconds < 0 {
+            return Err(ConfigError::Invalid("email.code_resend_cooldown_seconds must not be negative".to_owned()));
+        }
+
+        if self.sms.sink == SmsSink::File && self.sms.file_path.is_empty() {
+            return Err(ConfigError::Invalid("sms.file_path needs to be set for the file sink".to_owned()));
+        }
+
+        if self.sms.sink == SmsSink::Http && !self.sms.gateway_url.starts_with("http") {
+            return Err(ConfigError::Invalid("sms.gateway_url needs to be an http(s) url for the http sink".to_owned()));
+        }
+
+        if self.sms.timeout_seconds == 0 {
+            return Err(ConfigError::Invalid("sms.timeout_seconds must be positive".to_owned()));
+        }
+
+        if self.sms.code_ttl_minutes <= 0 || self.sms.code_resend_cooldown_seconds < 0 {
+            return Err(ConfigError::Invalid("sms.code_ttl_minutes must be positive and sms.code_resend_cooldown_seconds not negative".to_owned()));
+        }
+
+        if self.sms.code_max_attempts == 0 {
+            return Err(ConfigError::Invalid("sms.code_max_attempts must be at least 1".to_owned()));
+        }
+
+        for (key_id, key) in &self.secrets.keys {
+            if key_id.is_empty() || !matches!(STANDARD.decode(key), Ok(bytes) if bytes.len() == 32) {
+                return Err(ConfigError::Invalid(format!("secrets.keys {:?} must be base64 of 32 bytes under a non-empty id", key_id)));
+            }
+        }
+
+        if !self.secrets.active_key.is_empty() && !self.secrets.keys.contains_key(&self.secrets.active_key) {
+            return Err(ConfigError::Invalid(format!("secrets.active_key {} is not in secrets.keys", self.secrets.active_key)));
+        }
+
+        if self.magic_link.ttl_minutes <= 0 || self.magic_link.resend_cooldown_seconds < 0 {
+            return Err(ConfigError::Invalid("magic_link.ttl_minutes must be positive and magic_link.resend_cooldown_seconds not negative".to_owned()));
+        }
+
+        if self.magic_link.max_attempts == 0 {
+            return Err(ConfigError::Invalid("magic_link.max_attempts must be at least 1".to_owned()));
+        }
+
+        if self.username.min_length == 0 || self.username.min_length > self.username.max_length {
+            return Err(ConfigError::Invalid("username.min_length must be at least 1 and not above username.max_length".to_owned()));
+        }
+
+        if self.lookup.max_batch == 0 {
+            return Err(ConfigError::Invalid("lookup.max_batch must be at least 1".to_owned()));
+        }
+
+        return Ok(());
+    }
+
+}
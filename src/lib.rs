@@ -0,0 +1,9 @@
+pub mod config;
+pub mod metrics;
+pub mod telemetry;
+pub mod mailer;
+pub mod sms;
+pub mod secrets;
+pub mod model;
+pub mod repo;
+pub mod api;
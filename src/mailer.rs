@@ -0,0 +1,99 @@
+use crate::config::{MailConfig, MailSink};
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use async_trait::async_trait;
+use bson::DateTime;
+use tokio::io::AsyncWriteExt;
+
+// Outgoing email. Handlers build a MailMessage and hand it to whichever
+// Mailer mail.sink selects. The sinks here don't deliver anything, they are
+// for development and for relaying through something that watches the file.
+
+pub struct MailMessage {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub enum MailError {
+    Io(io::Error),
+}
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailError::Io(error) => write!(f, "could not send mail: {}", error),
+        }
+    }
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+
+    async fn send(
+        &self,
+        message: MailMessage
+    ) -> Result<(), MailError>;
+
+}
+
+// Writes messages to the log. Bodies hold codes and links, only use it where
+// the logs are as private as the mailboxes would be.
+pub struct LogMailer {
+    from: String,
+}
+
+#[async_trait]
+impl Mailer for LogMailer {
+
+    async fn send(&self, message: MailMessage) -> Result<(), MailError> {
+
+        tracing::info!(from = %self.from, to = %message.to, subject = %message.subject, body = %message.body, "mail");
+
+        return Ok(());
+    }
+
+}
+
+// Appends each message to a file as a plain RFC 5322 style block.
+pub struct FileMailer {
+    from: String,
+    path: PathBuf,
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+
+    async fn send(&self, message: MailMessage) -> Result<(), MailError> {
+
+        let date = DateTime::now().try_to_rfc3339_string().unwrap_or_default();
+
+        let rendered = format!(
+            "From: {}\nTo: {}\nDate: {}\nSubject: {}\n\n{}\n\n",
+            self.from, message.to, date, message.subject, message.body
+        );
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(MailError::Io)?;
+
+        file.write_all(rendered.as_bytes()).await.map_err(MailError::Io)?;
+
+        return Ok(());
+    }
+
+}
+
+pub fn open(config: &MailConfig) -> Arc<dyn Mailer> {
+    match config.sink {
+        MailSink::Log => return Arc::new(LogMailer { from: config.from.clone() }),
+        MailSink::File => return Arc::new(FileMailer { from: config.from.clone(), path: PathBuf::from(&config.file_path) }),
+    }
+}
@@ -1,46 +1,120 @@
-#![feature(async_fn_in_trait)]
-
-mod model;
-mod repo;
-mod api;
-
-use std::env;
+use std::sync::Arc;
 use dotenv::dotenv;
-use repo::database::mongodb::MongoRepo;
-use repo::database::base::Database;
+use user_auth_mongodb::{config::Config, mailer::{self, Mailer}, sms::{self, SmsProvider}, secrets::SecretKeys, metrics, telemetry};
+use user_auth_mongodb::repo::database::{connect, base::Database};
+use user_auth_mongodb::repo::blob::{self, BlobStore};
+use user_auth_mongodb::api::user::{get_user, lookup_users, new_user};
+use user_auth_mongodb::api::credentail::varify_password;
+use user_auth_mongodb::api::hidden::get_hidden;
+use user_auth_mongodb::api::privacy::{get_user_data, erase_user};
+use user_auth_mongodb::api::profile::{get_me, patch_me, put_username, put_avatar, delete_avatar, get_avatar};
+use user_auth_mongodb::api::email::{request_email_change, verify_email_change, revert_email_change};
+use user_auth_mongodb::api::magic_link::{request_magic_link, redeem_magic_link};
+use user_auth_mongodb::api::mfa::{send_mfa_code, send_mfa_sms, verify_mfa, request_email_mfa, confirm_email_mfa, enable_sms_mfa, begin_totp_mfa, confirm_totp_mfa, list_login_factors, list_mfa_factors, update_mfa_factor, remove_mfa_factor, reauthenticate};
+use user_auth_mongodb::api::phone::{put_phone_number, verify_phone_number, delete_phone_number, request_phone_login, verify_phone_login};
+use user_auth_mongodb::api::health::{get_live, get_ready};
+use user_auth_mongodb::api::metrics::get_metrics;
 use actix_web::{HttpServer, App, web::Data, middleware::Logger};
-use api::user::{get_user, new_user};
-use api::credentail::varify_password;
-use api::hidden::get_hidden;
 
 #[actix_web::main]
 async fn main() -> ::std::io::Result<()>  {
 
-    std::env::set_var("RUST_LOG", "debug");
     std::env::set_var("RUST_BACKTRACE", "1");
-    env_logger::init();
 
     dotenv().ok();
 
-    let mongodb_url = env::var("MONGOURL").expect("MONGOURL needs to be defined");
-    
-    let mongodb = MongoRepo::init(mongodb_url, "userauth".to_owned()).await;
+    let config = Config::load().unwrap_or_else(|error| panic!("configuration is invalid: {}", error));
+
+    // RUST_LOG still wins over server.log_level when set, for one-off debugging
+    telemetry::init(&config.server, &config.tracing).unwrap_or_else(|error| panic!("could not set up tracing: {}", error));
+
+    for warning in &config.warnings {
+        tracing::warn!("{}", warning);
+    }
+
+    metrics::register();
+
+    let keys_data = Data::new(SecretKeys::from_config(&config.secrets));
 
-    let mongodb_data = Data::new(mongodb);    
+    if keys_data.active_key().is_none() {
+        if !config.secrets.allow_plaintext {
+            panic!("secrets.active_key is not set, set one or secrets.allow_plaintext to store MFA secrets in plaintext");
+        }
 
-    HttpServer::new(move || {
+        tracing::warn!("secrets.active_key is not set, MFA secrets are stored in plaintext");
+    }
+
+    let database: Arc<dyn Database> = connect::open(&config.database)
+        .await
+        .unwrap_or_else(|error| panic!("could not connect to the database: {:?}", error));
+
+    let database_data: Data<dyn Database> = Data::from(database);
+    let blob_data: Data<dyn BlobStore> = Data::from(blob::open(&config.profile));
+    let mailer_data: Data<dyn Mailer> = Data::from(mailer::open(&config.mail));
+    let sms_data: Data<dyn SmsProvider> = Data::from(sms::open(&config.sms));
+
+    let bind_address = (config.server.host.clone(), config.server.port);
+    let config_data = Data::new(config);
+
+    let server = HttpServer::new(move || {
         let logger = Logger::default();
 
         App::new()
         .wrap(logger)
-        .app_data(Data::clone(&mongodb_data))
+        .wrap_fn(metrics::track_request)
+        .wrap_fn(telemetry::trace_request)
+        .app_data(Data::clone(&database_data))
+        .app_data(Data::clone(&blob_data))
+        .app_data(Data::clone(&mailer_data))
+        .app_data(Data::clone(&sms_data))
+        .app_data(Data::clone(&keys_data))
+        .app_data(Data::clone(&config_data))
         .service(get_user)
+        .service(lookup_users)
         .service(new_user)
         .service(varify_password)
+        .service(request_magic_link)
+        .service(redeem_magic_link)
+        .service(send_mfa_code)
+        .service(verify_mfa)
+        .service(request_email_mfa)
+        .service(confirm_email_mfa)
+        .service(send_mfa_sms)
+        .service(enable_sms_mfa)
+        .service(begin_totp_mfa)
+        .service(confirm_totp_mfa)
+        .service(list_login_factors)
+        .service(list_mfa_factors)
+        .service(update_mfa_factor)
+        .service(remove_mfa_factor)
+        .service(reauthenticate)
+        .service(put_phone_number)
+        .service(verify_phone_number)
+        .service(delete_phone_number)
+        .service(request_phone_login)
+        .service(verify_phone_login)
         .service(get_hidden)
+        .service(get_user_data)
+        .service(erase_user)
+        .service(get_me)
+        .service(patch_me)
+        .service(put_username)
+        .service(put_avatar)
+        .service(delete_avatar)
+        .service(get_avatar)
+        .service(request_email_change)
+        .service(verify_email_change)
+        .service(revert_email_change)
+        .service(get_live)
+        .service(get_ready)
+        .service(get_metrics)
     })
-    .bind(("127.0.0.1", 8000))?
+    .bind(bind_address)?
     .run()
-    .await
+    .await;
+
+    telemetry::shutdown();
+
+    return server;
     
 }
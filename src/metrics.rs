@@ -0,0 +1,102 @@
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::Instant;
+use actix_web::{
+    Error,
+    dev::{Service, ServiceRequest, ServiceResponse},
+};
+use prometheus::{
+    HistogramVec,
+    IntCounterVec,
+    register_histogram_vec,
+    register_int_counter_vec,
+};
+
+// Everything is registered in the prometheus default registry and served
+// by api::metrics. Label values are kept to fixed sets (route templates,
+// enum names) so series don't grow with user input.
+
+pub static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("http_requests_total", "HTTP requests by route and status", &["method", "route", "status"]).unwrap()
+});
+
+pub static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!("http_request_duration_seconds", "HTTP request latency by route", &["method", "route"]).unwrap()
+});
+
+pub static LOGIN_OUTCOMES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("login_outcomes_total", "Password checks by VarifyPasswordState", &["outcome"]).unwrap()
+});
+
+pub static MFA_OUTCOMES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("mfa_verifications_total", "MFA code checks by VarifyMfaState", &["outcome"]).unwrap()
+});
+
+pub static TOKENS_ISSUED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("tokens_issued_total", "Tokens signed by TokenAuthType", &["auth_type"]).unwrap()
+});
+
+pub static TOKEN_VALIDATIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("token_validations_total", "Token validations by result", &["result"]).unwrap()
+});
+
+pub static DATABASE_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!("database_operation_duration_seconds", "Database trait call latency", &["method", "result"]).unwrap()
+});
+
+pub static BCRYPT_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "bcrypt_duration_seconds",
+        "Time spent hashing and verifying passwords",
+        &["operation"],
+        vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]
+    ).unwrap()
+});
+
+pub static PASSWORD_UPGRADES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("password_hash_upgrades_total", "Legacy password hashes rehashed with bcrypt on login", &["scheme"]).unwrap()
+});
+
+pub static MAGIC_LINK_OUTCOMES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("magic_link_logins_total", "Magic link redemptions by CodeCheck, or WrongBrowser", &["outcome"]).unwrap()
+});
+
+// Forces registration so every metric shows up on /metrics from the start,
+// not only after it is first touched.
+pub fn register() {
+    LazyLock::force(&HTTP_REQUESTS);
+    LazyLock::force(&HTTP_REQUEST_DURATION);
+    LazyLock::force(&LOGIN_OUTCOMES);
+    LazyLock::force(&MFA_OUTCOMES);
+    LazyLock::force(&MAGIC_LINK_OUTCOMES);
+    LazyLock::force(&TOKENS_ISSUED);
+    LazyLock::force(&TOKEN_VALIDATIONS);
+    LazyLock::force(&DATABASE_DURATION);
+    LazyLock::force(&BCRYPT_DURATION);
+    LazyLock::force(&PASSWORD_UPGRADES);
+}
+
+// Used with App::wrap_fn, records count and latency under the matched route
+// template (/user/{user_uuid}) rather than the raw path.
+pub fn track_request<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let route = req.match_pattern().unwrap_or("unmatched".to_owned());
+    let response = srv.call(req);
+
+    async move {
+        let response = response.await?;
+        let status = response.status().as_u16().to_string();
+
+        HTTP_REQUESTS.with_label_values(&[&method, &route, &status]).inc();
+        HTTP_REQUEST_DURATION.with_label_values(&[&method, &route]).observe(start.elapsed().as_secs_f64());
+
+        return Ok(response);
+    }
+}
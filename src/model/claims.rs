@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
+use strum_macros::{EnumString, Display};
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, EnumString, Display)]
 pub enum ClaimsUserType {
     Admin,
     User,
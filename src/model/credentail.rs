@@ -1,31 +1,75 @@
 use bcrypt;
-use totp_rs::{Algorithm, TOTP, Secret};
 use bson::DateTime;
 use strum_macros::{EnumString, Display};
 use serde::{Serialize, Deserialize};
+use crate::model::SCHEMA_VERSION;
 use crate::model::user::User;
-
-
+use crate::model::password::{self, PasswordHash, PasswordHashError, PasswordScheme};
+use crate::model::one_time_code::{CodeCheck, OneTimeCode};
+use crate::model::totp::{self, TotpSettings};
+use crate::model::mfa_factor::{MfaFactor, MfaFactorSummary};
+use crate::model::token::AuthMethod;
+use crate::secrets::{SecretError, SecretKeys, StoredSecret};
+use crate::config::MfaConfig;
+use crate::metrics::{BCRYPT_DURATION, MFA_OUTCOMES, PASSWORD_UPGRADES};
+
+
+// variant names are what gets stored in the database
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Serialize, Deserialize, EnumString, Display, Eq, Debug, Clone)]
 pub enum UserMfaState {
     None,
     OTP,
+    // a code mailed to user_email for each login
+    Email,
+    // a code texted to the user's verified phone_number for each login
+    SMS,
 }
 
+impl UserMfaState {
+    // What a login with the factor adds to a token's amr.
+    pub fn auth_method(&self) -> Option<AuthMethod> {
+        match self {
+            UserMfaState::None => return None,
+            UserMfaState::OTP => return Some(AuthMethod::Otp),
+            UserMfaState::Email => return Some(AuthMethod::Email),
+            UserMfaState::SMS => return Some(AuthMethod::Sms),
+        }
+    }
+}
+
+#[derive(Display)]
 pub enum VarifyMfaState {
     Failed,
     Success,
     NotConfigured,
+    // Email and SMS only: no code was sent, it expired, or it had too many tries.
+    // After the latter two a new one has to be sent.
+    NoCodeIssued,
+    Expired,
+    TooManyAttempts,
+    // OTP only: the code's time step was already used for a login
+    Replayed,
 }
 pub enum VarifyMfaStateError {
     MissingMfaStore,
     MfaTypeNotImplimented,
+    UnknownFactor,
+    // its key is missing from [secrets] or the ciphertext was tampered with
+    UndecryptableSecret(SecretError),
 }
 
 pub enum AddMfaError {
     Failed,
     MfaTypeNotImplimented,
     MfaTypeNone,
+    // only one Email and one SMS factor
+    AlreadyEnrolled,
+}
+
+pub enum IssueMfaCodeError {
+    // seconds until another code may be sent
+    TooSoon(i64),
 }
 
 #[derive(PartialEq, Serialize, Deserialize, EnumString, Display, Eq, Debug, Clone)]
@@ -37,7 +81,10 @@ pub enum VarifyPasswordState {
 
 pub struct VarifyPassword {
     pub state: VarifyPasswordState,
-    pub password_set: Option<DateTime>
+    pub password_set: Option<DateTime>,
+    // set when a legacy hash was replaced with bcrypt, the caller has to
+    // save the credentail
+    pub upgraded: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -46,14 +93,235 @@ pub struct UserCredentailsExistingPasswords {
     changed_date: DateTime
 }
 
+// A requested address change waiting for the code sent to new_email.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmailChange {
+    pub new_email: String,
+    pub code: OneTimeCode,
+}
+
+// Lets whoever holds the previous address undo a change, via the link sent
+// to old_email.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmailRevert {
+    pub old_email: String,
+    pub token: OneTimeCode,
+}
+
+// A number waiting for the code texted to it before it goes on the User.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PhoneChange {
+    pub new_phone_number: String,
+    pub code: OneTimeCode,
+}
+
+// An authenticator app being set up, it becomes an OTP factor once a code
+// from it comes back.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TotpEnrollment {
+    pub secret: StoredSecret,
+    pub settings: TotpSettings,
+    pub expires_at: DateTime,
+    #[serde(default)]
+    pub attempts: Option<AttemptWindow>,
+}
+
+// Where a sealed secret sits on a credentail, see Database::swap_secret.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecretSlot {
+    Factor(String),
+    TotpEnrollment,
+}
+
+// Tries since started_at, see Database::take_reauth_attempt and
+// Database::take_totp_attempt.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttemptWindow {
+    pub started_at: DateTime,
+    pub attempts: u32,
+}
+
+impl AttemptWindow {
+    // Counts a try, starting a new window if the current one began before
+    // window_start. False once max_attempts are used up in it.
+    pub fn take (window: &mut Option<AttemptWindow>, window_start: DateTime, max_attempts: u32) -> bool {
+
+        match window.as_mut() {
+            Some(window) if window.started_at >= window_start => {
+                if window.attempts >= max_attempts {
+                    return false;
+                }
+
+                window.attempts += 1;
+            },
+            _ => *window = Some(AttemptWindow { started_at: DateTime::now(), attempts: 1 }),
+        }
+
+        return true;
+    }
+}
+
+// A pending passwordless login. The link carries token, binding is the
+// cookie handed to the browser that asked for it, both are needed to log in.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MagicLink {
+    pub token: OneTimeCode,
+    pub binding: OneTimeCode,
+}
+
+// The one time codes a credentail holds, for Database::take_code_attempt
+// and Database::spend_code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeSlot {
+    MfaCode,
+    EmailChange,
+    EmailRevert,
+    MagicLink,
+    PhoneChange,
+    PhoneLogin,
+}
+
+impl CodeSlot {
+    pub fn code_mut<'a>(&self, credentail: &'a mut UserCredentail) -> Option<&'a mut OneTimeCode> {
+        match self {
+            CodeSlot::MfaCode => return credentail.mfa_code.as_mut(),
+            CodeSlot::EmailChange => return credentail.email_change.as_mut().map(|change| &mut change.code),
+            CodeSlot::EmailRevert => return credentail.email_revert.as_mut().map(|revert| &mut revert.token),
+            CodeSlot::MagicLink => return credentail.magic_link.as_mut().map(|link| &mut link.token),
+            CodeSlot::PhoneChange => return credentail.phone_change.as_mut().map(|change| &mut change.code),
+            CodeSlot::PhoneLogin => return credentail.phone_login.as_mut(),
+        }
+    }
+
+    // Drops the code along with whatever it was protecting.
+    pub fn clear(&self, credentail: &mut UserCredentail) {
+        match self {
+            CodeSlot::MfaCode => {
+                credentail.mfa_code = None;
+                credentail.mfa_code_factor = None;
+            },
+            CodeSlot::EmailChange => credentail.email_change = None,
+            CodeSlot::EmailRevert => credentail.email_revert = None,
+            CodeSlot::MagicLink => credentail.magic_link = None,
+            CodeSlot::PhoneChange => credentail.phone_change = None,
+            CodeSlot::PhoneLogin => credentail.phone_login = None,
+        }
+    }
+}
+
+// Stored credentails may still be in the single factor layout, with
+// user_mfa_state, user_mfa_store, totp and totp_last_step at the top. They
+// are moved into mfa_factors as they are read and written back in the new
+// layout on the next save, so there is no migration for it.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "StoredCredentail")]
 pub struct UserCredentail {
     pub user_uuid: String,
     user_password: String,
-    pub user_mfa_state: UserMfaState,
-    pub user_mfa_store: Option<String>,
-    exsting_passwords: Vec<UserCredentailsExistingPasswords>
+    exsting_passwords: Vec<UserCredentailsExistingPasswords>,
+    pub email_change: Option<EmailChange>,
+    pub email_revert: Option<EmailRevert>,
+    pub magic_link: Option<MagicLink>,
+    pub mfa_factors: Vec<MfaFactor>,
+    // factor_id offered first at login, set to the first factor added
+    pub preferred_factor: Option<String>,
+    // the last code sent out of band for Email or SMS MFA, single use
+    pub mfa_code: Option<OneTimeCode>,
+    // factor_id mfa_code was sent for, None while enrolling Email
+    pub mfa_code_factor: Option<String>,
+    pub phone_change: Option<PhoneChange>,
+    // kept apart from mfa_code so a login code can't pass as a second factor
+    pub phone_login: Option<OneTimeCode>,
+    pub totp_enrollment: Option<TotpEnrollment>,
+    pub reauth_attempts: Option<AttemptWindow>,
+    pub schema_version: u32,
+}
 
+#[derive(Deserialize)]
+struct StoredCredentail {
+    user_uuid: String,
+    user_password: String,
+    exsting_passwords: Vec<UserCredentailsExistingPasswords>,
+    #[serde(default)]
+    email_change: Option<EmailChange>,
+    #[serde(default)]
+    email_revert: Option<EmailRevert>,
+    #[serde(default)]
+    magic_link: Option<MagicLink>,
+    #[serde(default)]
+    mfa_factors: Vec<MfaFactor>,
+    #[serde(default)]
+    preferred_factor: Option<String>,
+    #[serde(default)]
+    mfa_code: Option<OneTimeCode>,
+    #[serde(default)]
+    mfa_code_factor: Option<String>,
+    #[serde(default)]
+    phone_change: Option<PhoneChange>,
+    #[serde(default)]
+    phone_login: Option<OneTimeCode>,
+    #[serde(default)]
+    totp_enrollment: Option<TotpEnrollment>,
+    #[serde(default)]
+    reauth_attempts: Option<AttemptWindow>,
+    #[serde(default)]
+    schema_version: u32,
+    // the single factor layout
+    #[serde(default)]
+    user_mfa_state: Option<UserMfaState>,
+    #[serde(default)]
+    // plaintext, or encrypted by reencrypt-secrets before the first save in
+    // the new layout
+    user_mfa_store: Option<StoredSecret>,
+    #[serde(default)]
+    totp: Option<TotpSettings>,
+    #[serde(default)]
+    totp_last_step: Option<u64>,
+    #[serde(default)]
+    totp_attempts: Option<AttemptWindow>,
+}
+
+impl From<StoredCredentail> for UserCredentail {
+    fn from(stored: StoredCredentail) -> UserCredentail {
+
+        let mut mfa_factors = stored.mfa_factors;
+        let mut preferred_factor = stored.preferred_factor;
+        let mut mfa_code_factor = stored.mfa_code_factor;
+
+        if mfa_factors.is_empty() {
+            let legacy = stored.user_mfa_state
+                .and_then(|state| MfaFactor::legacy(state, stored.user_mfa_store, stored.totp, stored.totp_last_step, stored.totp_attempts));
+
+            if let Some(factor) = legacy {
+                // a code sent before the upgrade was for the one factor there
+                // was, it has to stay usable with it
+                if stored.mfa_code.is_some() && (factor.factor_type == UserMfaState::Email || factor.factor_type == UserMfaState::SMS) {
+                    mfa_code_factor = Some(factor.factor_id.clone());
+                }
+
+                preferred_factor = Some(factor.factor_id.clone());
+                mfa_factors.push(factor);
+            }
+        }
+
+        return UserCredentail {
+            user_uuid: stored.user_uuid,
+            user_password: stored.user_password,
+            exsting_passwords: stored.exsting_passwords,
+            email_change: stored.email_change,
+            email_revert: stored.email_revert,
+            magic_link: stored.magic_link,
+            mfa_factors,
+            preferred_factor,
+            mfa_code: stored.mfa_code,
+            mfa_code_factor,
+            phone_change: stored.phone_change,
+            phone_login: stored.phone_login,
+            totp_enrollment: stored.totp_enrollment,
+            reauth_attempts: stored.reauth_attempts,
+            schema_version: stored.schema_version,
+        };
+    }
 }
 
 
@@ -61,37 +329,62 @@ impl UserCredentail {
     pub fn new (
         user: User,
         plain_password: String,
+        bcrypt_cost: u32,
     ) -> UserCredentail {
+        let hash_timer = BCRYPT_DURATION.with_label_values(&["hash"]).start_timer();
+        let user_password = bcrypt::hash(plain_password, bcrypt_cost).unwrap();
+        hash_timer.observe_duration();
+
         return UserCredentail {
             user_uuid: user.user_uuid,
-            user_password: bcrypt::hash(plain_password, 10).unwrap(),
-            user_mfa_state: UserMfaState::None,
-            user_mfa_store: None,
-            exsting_passwords: Vec::new()
+            user_password,
+            exsting_passwords: Vec::new(),
+            email_change: None,
+            email_revert: None,
+            magic_link: None,
+            mfa_factors: Vec::new(),
+            preferred_factor: None,
+            mfa_code: None,
+            mfa_code_factor: None,
+            phone_change: None,
+            phone_login: None,
+            totp_enrollment: None,
+            reauth_attempts: None,
+            schema_version: SCHEMA_VERSION,
         };
     }
 
-    pub fn varify_password (&self, plain_password: String) -> VarifyPassword {
+    // Imported users may still have a legacy hash, see model::password. On a
+    // successful check it is rehashed with bcrypt in place.
+    pub fn varify_password (&mut self, plain_password: String, bcrypt_cost: u32) -> VarifyPassword {
 
-        let mut hash_state = bcrypt::verify(&plain_password, &self.user_password).unwrap();
-    
-        if hash_state {
+        let matched = password::verify_stored(&self.user_password, &plain_password);
+
+        if let Some(scheme) = matched {
+
+            let upgraded = scheme != PasswordScheme::Bcrypt;
+
+            if upgraded {
+                let _hash_timer = BCRYPT_DURATION.with_label_values(&["hash"]).start_timer();
+                self.user_password = bcrypt::hash(&plain_password, bcrypt_cost).unwrap();
+                PASSWORD_UPGRADES.with_label_values(&[&scheme.to_string()]).inc();
+            }
 
             return VarifyPassword {
                 state: VarifyPasswordState::Success,
-                password_set: None
+                password_set: None,
+                upgraded,
             };
             
         }
 
         for last_password in &self.exsting_passwords {
 
-            hash_state = bcrypt::verify(&plain_password, &last_password.password).unwrap();
-
-            if hash_state {
+            if password::verify_stored(&last_password.password, &plain_password).is_some() {
                 return VarifyPassword {
                     state: VarifyPasswordState::FailedPreviousPassword,
-                    password_set: Some(last_password.changed_date)
+                    password_set: Some(last_password.changed_date),
+                    upgraded: false,
                 };
             }
 
@@ -99,13 +392,51 @@ impl UserCredentail {
 
         return VarifyPassword{
             state: VarifyPasswordState::Failed,
-            password_set: None
+            password_set: None,
+            upgraded: false,
         };
 
 
     }
 
-    pub fn update_password (&mut self, plain_password: String) {
+    // Builds a credentail around an already hashed password, used by bulk
+    // import. The hash has to be in one of the formats model::password knows.
+    pub fn from_password_hash (
+        user_uuid: String,
+        password_hash: String,
+    ) -> Result<UserCredentail, PasswordHashError> {
+
+        PasswordHash::parse(&password_hash)?;
+
+        return Ok(UserCredentail {
+            user_uuid,
+            user_password: password_hash,
+            exsting_passwords: Vec::new(),
+            email_change: None,
+            email_revert: None,
+            magic_link: None,
+            mfa_factors: Vec::new(),
+            preferred_factor: None,
+            mfa_code: None,
+            mfa_code_factor: None,
+            phone_change: None,
+            phone_login: None,
+            totp_enrollment: None,
+            reauth_attempts: None,
+            schema_version: SCHEMA_VERSION,
+        });
+    }
+
+    pub fn password_hash (&self) -> &str {
+        return &self.user_password;
+    }
+
+    // When each password in the history was replaced, without the hashes.
+    pub fn password_changed_dates (&self) -> Vec<DateTime> {
+        return self.exsting_passwords.iter().map(|existing| existing.changed_date).collect();
+    }
+
+    pub fn update_password (&mut self, plain_password: String, bcrypt_cost: u32) {
 
         self.exsting_passwords.push(UserCredentailsExistingPasswords { password: self.user_password.clone(), changed_date: DateTime::now() });
 
@@ -113,30 +444,189 @@ impl UserCredentail {
             self.exsting_passwords.remove(0);
         }
 
-        self.user_password = bcrypt::hash(plain_password, 10).unwrap();
+        let _hash_timer = BCRYPT_DURATION.with_label_values(&["hash"]).start_timer();
+        self.user_password = bcrypt::hash(plain_password, bcrypt_cost).unwrap();
+    }
+
+
+    // Every secret not under the active key, as it is stored now and
+    // sealed with the active key, for Database::swap_secret.
+    pub fn resealed_secrets (&self, keys: &SecretKeys) -> Result<Vec<(SecretSlot, StoredSecret, StoredSecret)>, SecretError> {
+
+        let mut resealed = Vec::new();
+
+        for factor in &self.mfa_factors {
+            if let Some((stored, sealed)) = factor.resealed(keys, &self.user_uuid)? {
+                resealed.push((SecretSlot::Factor(factor.factor_id.clone()), stored, sealed));
+            }
+        }
+
+        if let Some(enrollment) = &self.totp_enrollment {
+            if keys.needs_reseal(&enrollment.secret) {
+                let sealed = keys.seal(&keys.open(&enrollment.secret, &self.user_uuid)?, &self.user_uuid);
+                resealed.push((SecretSlot::TotpEnrollment, enrollment.secret.clone(), sealed));
+            }
+        }
+
+        return Ok(resealed);
+    }
+
+    // Puts new in slot if the secret there is still old.
+    pub fn swap_secret (&mut self, slot: &SecretSlot, old: &StoredSecret, new: StoredSecret) -> bool {
+
+        match slot {
+            SecretSlot::Factor(factor_id) => {
+                return self.mfa_factors.iter_mut()
+                    .find(|factor| &factor.factor_id == factor_id)
+                    .is_some_and(|factor| factor.swap_secret(old, new));
+            },
+            SecretSlot::TotpEnrollment => match self.totp_enrollment.as_mut() {
+                Some(enrollment) if &enrollment.secret == old => {
+                    enrollment.secret = new;
+                    return true;
+                },
+                _ => return false,
+            },
+        }
+    }
+
+    // Counts a /reauth try, see AttemptWindow::take.
+    pub fn take_reauth_attempt (&mut self, window_start: DateTime, max_attempts: u32) -> bool {
+        return AttemptWindow::take(&mut self.reauth_attempts, window_start, max_attempts);
+    }
+
+    // Counts a code tried against the OTP secret in slot, see
+    // AttemptWindow::take. False as well when there is nothing in slot.
+    pub fn take_totp_attempt (&mut self, slot: &SecretSlot, window_start: DateTime, max_attempts: u32) -> bool {
+
+        match slot {
+            SecretSlot::Factor(factor_id) => return self.mfa_factors.iter_mut()
+                .find(|factor| &factor.factor_id == factor_id && factor.factor_type == UserMfaState::OTP)
+                .is_some_and(|factor| factor.take_attempt(window_start, max_attempts)),
+            SecretSlot::TotpEnrollment => return self.totp_enrollment.as_mut()
+                .is_some_and(|enrollment| AttemptWindow::take(&mut enrollment.attempts, window_start, max_attempts)),
+        }
+    }
+
+    pub fn has_mfa (&self) -> bool {
+        return !self.mfa_factors.is_empty();
+    }
+
+    pub fn factor (&self, factor_id: &str) -> Option<&MfaFactor> {
+        return self.mfa_factors.iter().find(|factor| factor.factor_id == factor_id);
+    }
+
+    pub fn factor_of_type (&self, factor_type: &UserMfaState) -> Option<&MfaFactor> {
+        return self.mfa_factors.iter().find(|factor| &factor.factor_type == factor_type);
+    }
+
+    // The factor login asks for unless the user picks another one.
+    pub fn preferred (&self) -> Option<&MfaFactor> {
+        return self.preferred_factor.as_deref()
+            .and_then(|factor_id| self.factor(factor_id))
+            .or(self.mfa_factors.first());
+    }
+
+    // factor_id, or the preferred factor when None.
+    pub fn resolve_factor (&self, factor_id: Option<&str>) -> Option<&MfaFactor> {
+        match factor_id {
+            Some(factor_id) => return self.factor(factor_id),
+            None => return self.preferred(),
+        }
+    }
+
+    pub fn factor_summaries (&self) -> Vec<MfaFactorSummary> {
+        let preferred = self.preferred().map(|factor| factor.factor_id.as_str());
+
+        return self.mfa_factors.iter().map(|factor| factor.summary(preferred)).collect();
+    }
+
+    fn push_factor (&mut self, factor: MfaFactor) -> String {
+
+        let factor_id = factor.factor_id.clone();
+
+        if self.preferred_factor.is_none() {
+            self.preferred_factor = Some(factor_id.clone());
+        }
+
+        self.mfa_factors.push(factor);
+
+        return factor_id;
     }
 
     pub fn remove_mfa (&mut self) {
 
-        self.user_mfa_state = UserMfaState::None;
-        self.user_mfa_store = None;
+        self.mfa_factors.clear();
+        self.preferred_factor = None;
+        self.mfa_code = None;
+        self.mfa_code_factor = None;
+        self.totp_enrollment = None;
+
+    }
+
+    // Returns false if there is no such factor. Removing the preferred one
+    // passes that on to the oldest remaining factor.
+    pub fn remove_factor (&mut self, factor_id: &str) -> bool {
+
+        let before = self.mfa_factors.len();
+
+        self.mfa_factors.retain(|factor| factor.factor_id != factor_id);
+
+        if self.mfa_factors.len() == before {
+            return false;
+        }
+
+        if self.preferred_factor.as_deref() == Some(factor_id) {
+            self.preferred_factor = self.mfa_factors.first().map(|factor| factor.factor_id.clone());
+        }
+
+        if self.mfa_code_factor.as_deref() == Some(factor_id) {
+            self.mfa_code = None;
+            self.mfa_code_factor = None;
+        }
+
+        return true;
+    }
+
+    pub fn set_preferred_factor (&mut self, factor_id: &str) -> bool {
+
+        if self.factor(factor_id).is_none() {
+            return false;
+        }
+
+        self.preferred_factor = Some(factor_id.to_owned());
+
+        return true;
+    }
+
+    // label has to have passed mfa_factor::valid_label
+    pub fn rename_factor (&mut self, factor_id: &str, label: String) -> bool {
 
+        match self.mfa_factors.iter_mut().find(|factor| factor.factor_id == factor_id) {
+            Some(factor) => {
+                factor.label = label;
+                return true;
+            },
+            None => return false,
+        }
     }
 
-    
-    pub fn add_mfa (&mut self, mfa_type: UserMfaState) -> Result<String, AddMfaError> {
+    // Adds an Email or SMS factor and returns its factor_id. OTP factors
+    // need a secret, they come from confirm_totp or import_totp. Callers are
+    // expected to have proven the address or the phone number first.
+    pub fn add_mfa (&mut self, mfa_type: UserMfaState, label: Option<String>) -> Result<String, AddMfaError> {
 
         if mfa_type == UserMfaState::None {
             return Result::Err(AddMfaError::MfaTypeNone);
         }
 
-        if mfa_type == UserMfaState::OTP {
+        if mfa_type == UserMfaState::Email || mfa_type == UserMfaState::SMS {
 
-            let secret = Secret::generate_secret().to_string();
+            if self.factor_of_type(&mfa_type).is_some() {
+                return Result::Err(AddMfaError::AlreadyEnrolled);
+            }
 
-            self.user_mfa_store = Some(secret.clone());
-            self.user_mfa_state = UserMfaState::OTP;
-            return Result::Ok(secret);
+            return Result::Ok(self.push_factor(MfaFactor::new(mfa_type, label)));
 
         }
 
@@ -144,39 +634,303 @@ impl UserCredentail {
 
     }
 
+    // Adds an OTP factor from a secret exported elsewhere, already base32.
+    // label has to have passed mfa_factor::valid_label.
+    pub fn import_totp (&mut self, secret: &str, settings: TotpSettings, label: Option<String>, keys: &SecretKeys) -> Result<String, AddMfaError> {
 
-    pub fn check_mfa (&mut self, mfa_code: String, submit_time: u64) -> Result<VarifyMfaState, VarifyMfaStateError> {
+        let bytes = match totp::decode_secret(secret) {
+            Some(bytes) => bytes,
+            None => return Err(AddMfaError::Failed),
+        };
 
-        if self.user_mfa_state == UserMfaState::None {
-            return Result::Ok(VarifyMfaState::NotConfigured);
+        let secret = keys.seal(&totp::encode_secret(&bytes), &self.user_uuid);
+
+        return Ok(self.push_factor(MfaFactor::new_totp(secret, settings, None, label)));
+    }
+
+    // Starts setting up an authenticator app and returns the secret to show
+    // the user. Nothing changes for logins until confirm_totp sees a code
+    // from it, so a botched scan can't lock the user out.
+    pub fn begin_totp (&mut self, mfa_config: &MfaConfig, keys: &SecretKeys) -> (String, TotpSettings) {
+
+        let secret = totp::generate_secret();
+        let settings = TotpSettings::from_config(mfa_config);
+
+        self.totp_enrollment = Some(TotpEnrollment {
+            secret: keys.seal(&secret, &self.user_uuid),
+            settings: settings.clone(),
+            expires_at: DateTime::from_millis(DateTime::now().timestamp_millis() + mfa_config.totp_enrollment_ttl_minutes * 60 * 1000),
+            attempts: None,
+        });
+
+        return (secret, settings);
+    }
+
+    // attempt_counted is what Database::take_totp_attempt said about the
+    // enrolment.
+    pub fn confirm_totp (&mut self, mfa_code: &str, label: Option<String>, attempt_counted: bool, submit_time: u64, mfa_config: &MfaConfig, keys: &SecretKeys) -> VarifyMfaState {
+
+        if self.totp_enrollment.is_some() && !attempt_counted {
+            return VarifyMfaState::TooManyAttempts;
         }
 
+        let enrollment = match self.totp_enrollment.take() {
+            Some(enrollment) => enrollment,
+            None => return VarifyMfaState::NoCodeIssued,
+        };
 
+        if DateTime::now() > enrollment.expires_at {
+            return VarifyMfaState::Expired;
+        }
 
-        if self.user_mfa_store.is_none() {
-            return Result::Err(VarifyMfaStateError::MissingMfaStore);
+        // a secret that doesn't open any more counts as no enrolment
+        let secret = match keys.open(&enrollment.secret, &self.user_uuid) {
+            Ok(secret) => secret,
+            Err(_) => return VarifyMfaState::NoCodeIssued,
+        };
+
+        let step = totp::decode_secret(&secret)
+            .and_then(|secret| totp::matching_step(&secret, &enrollment.settings, mfa_code, submit_time, mfa_config.totp_skew));
+
+        match step {
+            Some(step) => {
+                // the confirming code can't be used again to log in
+                let secret = keys.seal(&secret, &self.user_uuid);
+                self.push_factor(MfaFactor::new_totp(secret, enrollment.settings, Some(step), label));
+                return VarifyMfaState::Success;
+            },
+            None => {
+                self.totp_enrollment = Some(enrollment);
+                return VarifyMfaState::Failed;
+            },
         }
+    }
 
-        if self.user_mfa_state == UserMfaState::OTP {
+    // A fresh code to send out of band for factor_id, or for enrolling Email
+    // when None, replacing any earlier one. Refused while the previous code
+    // for the same factor is younger than the resend cooldown.
+    pub fn issue_mfa_code (&mut self, factor_id: Option<String>, mfa_config: &MfaConfig) -> Result<String, IssueMfaCodeError> {
 
-            let totp = TOTP::new(
-                Algorithm::SHA1,
-                6,
-                1,
-                30,
-                self.user_mfa_store.clone().unwrap().as_bytes().to_vec()
-            ).unwrap();
+        if let Some(previous) = self.mfa_code.as_ref().filter(|_| self.mfa_code_factor == factor_id) {
+            let wait = previous.seconds_until_resend(mfa_config.code_resend_cooldown_seconds);
 
-            if totp.check(&mfa_code, submit_time) {
-                return Result::Ok(VarifyMfaState::Success);
-            } else {
-                return Result::Ok(VarifyMfaState::Failed);
+            if wait > 0 {
+                return Err(IssueMfaCodeError::TooSoon(wait));
             }
+        }
+
+        let (code, stored) = OneTimeCode::numeric(6, mfa_config.code_ttl_minutes * 60);
+
+        self.mfa_code = Some(stored);
+        self.mfa_code_factor = factor_id;
 
+        return Ok(code);
+    }
+
+    // The code issue_mfa_code made for factor_id, if that is the factor it
+    // was sent for.
+    pub fn mfa_code_for (&self, factor_id: Option<&str>) -> Option<&OneTimeCode> {
+        return self.mfa_code.as_ref().filter(|_| self.mfa_code_factor.as_deref() == factor_id);
+    }
+
+    // Checks against the code issue_mfa_code made for the same factor_id,
+    // attempt_counted is what Database::take_code_attempt said for it. A
+    // match clears the code here, the caller still has to spend it in the
+    // database with Database::spend_code.
+    pub fn check_mfa_code (&mut self, factor_id: Option<&str>, mfa_code: &str, attempt_counted: bool) -> VarifyMfaState {
+
+        let stored = match self.mfa_code_for(factor_id) {
+            Some(stored) => stored,
+            None => return VarifyMfaState::NoCodeIssued,
+        };
+
+        match stored.check(mfa_code, attempt_counted) {
+            CodeCheck::Valid => (),
+            CodeCheck::Invalid => return VarifyMfaState::Failed,
+            CodeCheck::Expired => return VarifyMfaState::Expired,
+            CodeCheck::TooManyAttempts => return VarifyMfaState::TooManyAttempts,
+        }
+
+        CodeSlot::MfaCode.clear(self);
+
+        return VarifyMfaState::Success;
+    }
+
+
+    // Checks a code against factor_id, or the preferred factor when None.
+    // attempt_counted is what Database::take_code_attempt said about
+    // mfa_code for Email and SMS, and what Database::take_totp_attempt said
+    // about the factor for OTP. OTP secrets are decrypted with
+    // keys, and re-encrypted if the active key changed since, so the
+    // credentail has to be saved after a success.
+    pub fn check_mfa (
+        &mut self,
+        factor_id: Option<&str>,
+        mfa_code: String,
+        attempt_counted: bool,
+        submit_time: u64,
+        mfa_config: &MfaConfig,
+        keys: &SecretKeys,
+    ) -> Result<VarifyMfaState, VarifyMfaStateError> {
+
+        if !self.has_mfa() {
+            MFA_OUTCOMES.with_label_values(&[&VarifyMfaState::NotConfigured.to_string()]).inc();
+            return Result::Ok(VarifyMfaState::NotConfigured);
+        }
+
+        let factor_id = match self.resolve_factor(factor_id) {
+            Some(factor) => factor.factor_id.clone(),
+            None => return Result::Err(VarifyMfaStateError::UnknownFactor),
+        };
+
+        let index = match self.mfa_factors.iter().position(|factor| factor.factor_id == factor_id) {
+            Some(index) => index,
+            None => return Result::Err(VarifyMfaStateError::UnknownFactor),
+        };
+
+        let state = match self.mfa_factors[index].factor_type {
+            UserMfaState::Email | UserMfaState::SMS => self.check_mfa_code(Some(&factor_id), &mfa_code, attempt_counted),
+            UserMfaState::OTP if !attempt_counted => VarifyMfaState::TooManyAttempts,
+            UserMfaState::OTP => self.mfa_factors[index].check_totp(&mfa_code, submit_time, mfa_config, keys, &self.user_uuid)?,
+            UserMfaState::None => return Result::Err(VarifyMfaStateError::MfaTypeNotImplimented),
+        };
+
+        if let VarifyMfaState::Success = state {
+            self.mfa_factors[index].last_used_at = Some(DateTime::now());
         }
 
-        return Result::Err(VarifyMfaStateError::MfaTypeNotImplimented);        
+        MFA_OUTCOMES.with_label_values(&[&state.to_string()]).inc();
+
+        return Result::Ok(state);
+
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn stored(fields: serde_json::Value) -> UserCredentail {
+        let mut stored = json!({
+            "user_uuid": "5f0c7e52-3c1b-4a8e-9d55-0b8c1f1e2a10",
+            "user_password": "$2b$04$invalidinvalidinvalidinvalidinvalidinvalidinvalidinva",
+            "exsting_passwords": [],
+        });
+        stored.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+        return serde_json::from_value(stored).unwrap();
+    }
 
+    #[test]
+    fn a_single_factor_credentail_gets_one_factor() {
+        let credentail = stored(json!({
+            "user_mfa_state": "OTP",
+            "user_mfa_store": "12345678901234567890",
+            "totp_last_step": 7,
+        }));
+
+        assert_eq!(credentail.mfa_factors.len(), 1);
+        let factor = &credentail.mfa_factors[0];
+        assert_eq!(factor.factor_type, UserMfaState::OTP);
+        assert_eq!(factor.factor_id, "legacy-otp");
+        assert_eq!(factor.totp_last_step(), Some(7));
+        assert_eq!(credentail.preferred_factor.as_deref(), Some("legacy-otp"));
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn a_pending_legacy_code_stays_with_its_factor() {
+        let (code, sent) = OneTimeCode::numeric(6, 600);
+
+        let mut credentail = stored(json!({
+            "user_mfa_state": "Email",
+            "mfa_code": serde_json::to_value(&sent).unwrap(),
+        }));
+
+        assert_eq!(credentail.mfa_code_factor.as_deref(), Some("legacy-email"));
+        assert!(matches!(credentail.check_mfa_code(Some("legacy-email"), &code, true), VarifyMfaState::Success));
+    }
+
+    #[test]
+    fn no_legacy_factor_leaves_mfa_off() {
+        assert!(!stored(json!({})).has_mfa());
+        assert!(!stored(json!({"user_mfa_state": "None"})).has_mfa());
+        // an OTP state without its secret can't be used
+        assert!(!stored(json!({"user_mfa_state": "OTP"})).has_mfa());
+    }
+
+    #[test]
+    fn reauth_attempts_stop_until_the_window_is_over() {
+        let mut credentail = stored(json!({}));
+        let window_start = DateTime::from_millis(DateTime::now().timestamp_millis() - 15 * 60 * 1000);
+
+        for _ in 0..3 {
+            assert!(credentail.take_reauth_attempt(window_start, 3));
+        }
+        assert!(!credentail.take_reauth_attempt(window_start, 3));
+
+        // a window that began before window_start is over
+        let later = DateTime::from_millis(DateTime::now().timestamp_millis() + 1000);
+        assert!(credentail.take_reauth_attempt(later, 3));
+        assert_eq!(credentail.reauth_attempts.as_ref().unwrap().attempts, 1);
+    }
+
+    #[test]
+    fn a_totp_factor_locks_after_max_attempts() {
+        let mfa_config = MfaConfig::default();
+        let keys = SecretKeys::from_config(&crate::config::SecretsConfig::default());
+        let mut credentail = stored(json!({"user_mfa_state": "OTP", "user_mfa_store": "12345678901234567890"}));
+        let slot = SecretSlot::Factor("legacy-otp".to_owned());
+        let window_start = DateTime::from_millis(DateTime::now().timestamp_millis() - 15 * 60 * 1000);
+
+        for _ in 0..mfa_config.code_max_attempts {
+            assert!(credentail.take_totp_attempt(&slot, window_start, mfa_config.code_max_attempts));
+            let state = credentail.check_mfa(None, "000000".to_owned(), true, 59, &mfa_config, &keys).ok().unwrap();
+            assert!(matches!(state, VarifyMfaState::Failed));
+        }
+
+        // RFC 6238's code for T=59, refused without a counted attempt
+        assert!(!credentail.take_totp_attempt(&slot, window_start, mfa_config.code_max_attempts));
+        let state = credentail.check_mfa(None, "287082".to_owned(), false, 59, &mfa_config, &keys).ok().unwrap();
+        assert!(matches!(state, VarifyMfaState::TooManyAttempts));
+
+        // the next window starts over and a success clears the count
+        let later = DateTime::from_millis(DateTime::now().timestamp_millis() + 1000);
+        assert!(credentail.take_totp_attempt(&slot, later, mfa_config.code_max_attempts));
+        let state = credentail.check_mfa(None, "287082".to_owned(), true, 59, &mfa_config, &keys).ok().unwrap();
+        assert!(matches!(state, VarifyMfaState::Success));
+        assert!(credentail.take_totp_attempt(&slot, later, 1));
+    }
+
+    #[test]
+    fn a_totp_enrollment_locks_after_max_attempts() {
+        let mfa_config = MfaConfig::default();
+        let keys = SecretKeys::from_config(&crate::config::SecretsConfig::default());
+        let mut credentail = stored(json!({}));
+        let window_start = DateTime::from_millis(DateTime::now().timestamp_millis() - 15 * 60 * 1000);
+
+        assert!(!credentail.take_totp_attempt(&SecretSlot::TotpEnrollment, window_start, 1));
+
+        credentail.begin_totp(&mfa_config, &keys);
+
+        assert!(credentail.take_totp_attempt(&SecretSlot::TotpEnrollment, window_start, 1));
+        assert!(!credentail.take_totp_attempt(&SecretSlot::TotpEnrollment, window_start, 1));
+        assert!(matches!(credentail.confirm_totp("000000", None, false, 59, &mfa_config, &keys), VarifyMfaState::TooManyAttempts));
+        assert!(credentail.totp_enrollment.is_some());
+    }
+
+    #[test]
+    fn factors_in_the_new_layout_win() {
+        let mut credentail = UserCredentail::from_password_hash("5f0c7e52-3c1b-4a8e-9d55-0b8c1f1e2a10".to_owned(), "sha1$seasalt$55eef163bf2e349b9946e3183eef620dde0247f5".to_owned()).ok().unwrap();
+        let factor_id = credentail.add_mfa(UserMfaState::SMS, None).ok().unwrap();
+
+        let mut stored_form = serde_json::to_value(&credentail).unwrap();
+        stored_form["user_mfa_state"] = json!("Email");
+
+        let credentail: UserCredentail = serde_json::from_value(stored_form).unwrap();
+
+        assert_eq!(credentail.mfa_factors.len(), 1);
+        assert_eq!(credentail.mfa_factors[0].factor_id, factor_id);
+        assert_eq!(credentail.preferred_factor, Some(factor_id));
+    }
+}
@@ -0,0 +1,312 @@
+use bson::DateTime;
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+use crate::config::MfaConfig;
+use crate::model::credentail::{AttemptWindow, UserMfaState, VarifyMfaState, VarifyMfaStateError};
+use crate::model::totp::{self, TotpSettings};
+use crate::secrets::{SecretError, SecretKeys, StoredSecret};
+
+// One enrolled second factor, a credentail holds a list of them and any one
+// passes the MFA step of login. Email and SMS codes go to the address and
+// number on the User, so there is at most one of each. Authenticator apps
+// can be added once per device.
+
+const MAX_LABEL_CHARS: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MfaFactor {
+    pub factor_id: String,
+    pub factor_type: UserMfaState,
+    pub label: String,
+    // None for a factor carried over from the single factor layout
+    #[serde(default)]
+    pub created_at: Option<DateTime>,
+    #[serde(default)]
+    pub last_used_at: Option<DateTime>,
+    // OTP only, base32 once opened. A legacy secret is raw bytes and has no
+    // totp.
+    #[serde(default)]
+    secret: Option<StoredSecret>,
+    #[serde(default)]
+    totp: Option<TotpSettings>,
+    // time step of the last accepted OTP code, it and earlier ones are refused
+    #[serde(default)]
+    totp_last_step: Option<u64>,
+    // OTP codes tried, see Database::take_totp_attempt
+    #[serde(default)]
+    attempts: Option<AttemptWindow>,
+}
+
+// What the user gets to see of a factor, the secret stays behind.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MfaFactorSummary {
+    pub factor_id: String,
+    pub factor_type: UserMfaState,
+    pub label: String,
+    pub created_at: Option<DateTime>,
+    pub last_used_at: Option<DateTime>,
+    pub preferred: bool,
+}
+
+// Trimmed, not empty, no control characters and at most 64 characters.
+pub fn valid_label(label: &str) -> Option<String> {
+
+    let label = label.trim();
+
+    if label.is_empty() || label.chars().count() > MAX_LABEL_CHARS || label.chars().any(char::is_control) {
+        return None;
+    }
+
+    return Some(label.to_owned());
+}
+
+fn default_label(factor_type: &UserMfaState) -> String {
+    match factor_type {
+        UserMfaState::OTP => return "Authenticator app".to_owned(),
+        factor_type => return factor_type.to_string(),
+    }
+}
+
+impl MfaFactor {
+    pub fn new (factor_type: UserMfaState, label: Option<String>) -> MfaFactor {
+        return MfaFactor {
+            factor_id: Uuid::new_v4().to_string(),
+            label: label.unwrap_or(default_label(&factor_type)),
+            factor_type,
+            created_at: Some(DateTime::now()),
+            last_used_at: None,
+            secret: None,
+            totp: None,
+            totp_last_step: None,
+            attempts: None,
+        };
+    }
+
+    // secret has to be base32 already, sealed for the credentail's user_uuid
+    pub fn new_totp (secret: StoredSecret, settings: TotpSettings, last_step: Option<u64>, label: Option<String>) -> MfaFactor {
+        return MfaFactor {
+            secret: Some(secret),
+            totp: Some(settings),
+            totp_last_step: last_step,
+            ..MfaFactor::new(UserMfaState::OTP, label)
+        };
+    }
+
+    // The factor of a credentail from before there were several. Its id is
+    // fixed so it stays the same across reads until the credentail is saved.
+    pub(crate) fn legacy (
+        factor_type: UserMfaState,
+        store: Option<StoredSecret>,
+        totp: Option<TotpSettings>,
+        totp_last_step: Option<u64>,
+        attempts: Option<AttemptWindow>,
+    ) -> Option<MfaFactor> {
+
+        if factor_type == UserMfaState::None || (factor_type == UserMfaState::OTP && store.is_none()) {
+            return None;
+        }
+
+        return Some(MfaFactor {
+            factor_id: format!("legacy-{}", factor_type.to_string().to_lowercase()),
+            label: default_label(&factor_type),
+            factor_type,
+            created_at: None,
+            last_used_at: None,
+            secret: store,
+            totp,
+            totp_last_step,
+            attempts,
+        });
+    }
+
+    pub fn summary (&self, preferred_factor: Option<&str>) -> MfaFactorSummary {
+        return MfaFactorSummary {
+            factor_id: self.factor_id.clone(),
+            factor_type: self.factor_type.clone(),
+            label: self.label.clone(),
+            created_at: self.created_at,
+            last_used_at: self.last_used_at,
+            preferred: preferred_factor == Some(self.factor_id.as_str()),
+        };
+    }
+
+    // The OTP secret as bytes with the settings to use it with. Legacy
+    // secrets used their own bytes as SHA1, 6 digits, totp_step. context is
+    // the user_uuid, see secrets.rs.
+    fn totp_secret (&self, keys: &SecretKeys, context: &str, mfa_config: &MfaConfig) -> Result<(Vec<u8>, TotpSettings), VarifyMfaStateError> {
+
+        let secret = match &self.secret {
+            Some(stored) => keys.open(stored, context).map_err(VarifyMfaStateError::UndecryptableSecret)?,
+            None => return Err(VarifyMfaStateError::MissingMfaStore),
+        };
+
+        match &self.totp {
+            Some(settings) => match totp::decode_secret(&secret) {
+                Some(bytes) => return Ok((bytes, settings.clone())),
+                None => return Err(VarifyMfaStateError::MissingMfaStore),
+            },
+            None => return Ok((secret.into_bytes(), TotpSettings { period: mfa_config.totp_step, ..TotpSettings::default() })),
+        }
+    }
+
+    // The OTP secret base32 encoded with its settings, for export. None for
+    // other factor types and unusable secrets.
+    pub fn totp_export (&self, keys: &SecretKeys, context: &str, mfa_config: &MfaConfig) -> Result<Option<(String, TotpSettings)>, SecretError> {
+
+        if self.factor_type != UserMfaState::OTP {
+            return Ok(None);
+        }
+
+        match self.totp_secret(keys, context, mfa_config) {
+            Ok((secret, settings)) => return Ok(Some((totp::encode_secret(&secret), settings))),
+            Err(VarifyMfaStateError::UndecryptableSecret(error)) => return Err(error),
+            Err(_) => return Ok(None),
+        }
+    }
+
+    pub fn check_totp (
+        &mut self,
+        mfa_code: &str,
+        submit_time: u64,
+        mfa_config: &MfaConfig,
+        keys: &SecretKeys,
+        context: &str,
+    ) -> Result<VarifyMfaState, VarifyMfaStateError> {
+
+        let (secret, settings) = self.totp_secret(keys, context, mfa_config)?;
+
+        let state = match totp::matching_step(&secret, &settings, mfa_code, submit_time, mfa_config.totp_skew) {
+            Some(step) if !self.claim_totp_step(step) => VarifyMfaState::Replayed,
+            Some(_) => {
+                // legacy secrets are stored as base32 from here on, same codes
+                self.secret = Some(keys.seal(&totp::encode_secret(&secret), context));
+                self.totp = Some(settings);
+                self.attempts = None;
+                VarifyMfaState::Success
+            },
+            None => VarifyMfaState::Failed,
+        };
+
+        // the caller saves the credentail whatever the outcome, a rotated
+        // key is replaced on the way
+        self.reseal(keys, context).map_err(VarifyMfaStateError::UndecryptableSecret)?;
+
+        return Ok(state);
+    }
+
+    pub fn take_attempt (&mut self, window_start: DateTime, max_attempts: u32) -> bool {
+        return AttemptWindow::take(&mut self.attempts, window_start, max_attempts);
+    }
+
+    pub fn totp_last_step (&self) -> Option<u64> {
+        return self.totp_last_step;
+    }
+
+    // Takes step as the last used one if it is later, see
+    // Database::claim_totp_step.
+    pub fn claim_totp_step (&mut self, step: u64) -> bool {
+
+        if self.totp_last_step.is_some_and(|last_step| step <= last_step) {
+            return false;
+        }
+
+        self.totp_last_step = Some(step);
+
+        return true;
+    }
+
+    // The stored secret and the same secret under the active key, if it
+    // isn't under it already.
+    pub fn resealed (&self, keys: &SecretKeys, context: &str) -> Result<Option<(StoredSecret, StoredSecret)>, SecretError> {
+
+        let stored = match &self.secret {
+            Some(stored) if keys.needs_reseal(stored) => stored,
+            _ => return Ok(None),
+        };
+
+        let plaintext = keys.open(stored, context)?;
+
+        return Ok(Some((stored.clone(), keys.seal(&plaintext, context))));
+    }
+
+    // Encrypts the secret with the active key if it isn't already, returns
+    // whether anything changed.
+    pub fn reseal (&mut self, keys: &SecretKeys, context: &str) -> Result<bool, SecretError> {
+
+        match self.resealed(keys, context)? {
+            Some((_, resealed)) => {
+                self.secret = Some(resealed);
+                return Ok(true);
+            },
+            None => return Ok(false),
+        }
+    }
+
+    // Puts new in place of the secret if it is still old, see
+    // Database::swap_secret.
+    pub fn swap_secret (&mut self, old: &StoredSecret, new: StoredSecret) -> bool {
+
+        if self.secret.as_ref() != Some(old) {
+            return false;
+        }
+
+        self.secret = Some(new);
+
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SecretsConfig;
+
+    const USER_UUID: &str = "5f0c7e52-3c1b-4a8e-9d55-0b8c1f1e2a10";
+
+    fn keys() -> SecretKeys {
+        return SecretKeys::from_config(&SecretsConfig::default());
+    }
+
+    #[test]
+    fn valid_label_trims_and_limits() {
+        assert_eq!(valid_label("  Work phone "), Some("Work phone".to_owned()));
+        assert_eq!(valid_label(&"é".repeat(64)), Some("é".repeat(64)));
+        assert_eq!(valid_label(&"é".repeat(65)), None);
+        assert_eq!(valid_label("   "), None);
+        assert_eq!(valid_label("tab\there"), None);
+        assert_eq!(valid_label("new\nline"), None);
+    }
+
+    #[test]
+    fn legacy_secrets_are_raw_sha1_bytes() {
+        let mut factor = MfaFactor::legacy(UserMfaState::OTP, Some(StoredSecret::Plain("12345678901234567890".to_owned())), None, None, None).unwrap();
+
+        // the last six digits of the RFC 6238 SHA1 vector for T=59
+        let state = factor.check_totp("287082", 59, &MfaConfig::default(), &keys(), USER_UUID);
+
+        assert!(matches!(state, Ok(VarifyMfaState::Success)));
+        assert_eq!(factor.totp_last_step(), Some(1));
+        // carried over to the base32 form with the settings it was used with
+        assert_eq!(factor.totp, Some(TotpSettings::default()));
+        let (secret, _) = factor.totp_export(&keys(), USER_UUID, &MfaConfig::default()).unwrap().unwrap();
+        assert_eq!(secret, totp::encode_secret(b"12345678901234567890"));
+    }
+
+    #[test]
+    fn a_used_step_is_replayed() {
+        let mut factor = MfaFactor::legacy(UserMfaState::OTP, Some(StoredSecret::Plain("12345678901234567890".to_owned())), None, None, None).unwrap();
+
+        assert!(matches!(factor.check_totp("287082", 59, &MfaConfig::default(), &keys(), USER_UUID), Ok(VarifyMfaState::Success)));
+        assert!(matches!(factor.check_totp("287082", 59, &MfaConfig::default(), &keys(), USER_UUID), Ok(VarifyMfaState::Replayed)));
+    }
+
+    #[test]
+    fn claim_totp_step_only_moves_forward() {
+        let mut factor = MfaFactor::new_totp(keys().seal(&totp::generate_secret(), USER_UUID), TotpSettings::default(), Some(10), None);
+
+        assert!(!factor.claim_totp_step(9));
+        assert!(!factor.claim_totp_step(10));
+        assert!(factor.claim_totp_step(11));
+        assert_eq!(factor.totp_last_step(), Some(11));
+    }
+}
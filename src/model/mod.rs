@@ -1,4 +1,15 @@
 pub mod user;
 pub mod credentail;
 pub mod token;
-pub mod claims;
\ No newline at end of file
+pub mod claims;
+pub mod password;
+pub mod privacy;
+pub mod profile;
+pub mod one_time_code;
+pub mod username;
+pub mod totp;
+pub mod mfa_factor;
+
+// Version of the stored User and UserCredentail layout. Bump it together
+// with a new migration in repo/database/mongodb_migrations.rs.
+pub const SCHEMA_VERSION: u32 = 3;
@@ -0,0 +1,149 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use bson::DateTime;
+use rand::{Rng, RngCore, rngs::OsRng};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use strum_macros::Display;
+use crate::model::password::constant_time_eq;
+
+// Short lived secrets sent out of band: numeric codes people type in, and
+// long random tokens that go into links. Only a SHA-256 of the secret is
+// stored, the plain value exists in the message that was sent.
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OneTimeCode {
+    code_hash: String,
+    pub issued_at: DateTime,
+    pub expires_at: DateTime,
+    pub attempts: u32,
+}
+
+#[derive(Debug, Display, PartialEq, Eq)]
+pub enum CodeCheck {
+    Valid,
+    Invalid,
+    Expired,
+    TooManyAttempts,
+}
+
+fn hash(secret: &str) -> String {
+    return format!("{:x}", Sha256::digest(secret.as_bytes()));
+}
+
+impl OneTimeCode {
+
+    fn from_secret(secret: &str, ttl_seconds: i64) -> OneTimeCode {
+
+        let now = DateTime::now();
+
+        return OneTimeCode {
+            code_hash: hash(secret),
+            issued_at: now,
+            expires_at: DateTime::from_millis(now.timestamp_millis() + ttl_seconds * 1000),
+            attempts: 0,
+        };
+    }
+
+    // A zero padded decimal code, returned alongside what gets stored.
+    pub fn numeric(digits: u32, ttl_seconds: i64) -> (String, OneTimeCode) {
+
+        let code = format!("{:0width$}", OsRng.gen_range(0..10u64.pow(digits)), width = digits as usize);
+        let stored = OneTimeCode::from_secret(&code, ttl_seconds);
+
+        return (code, stored);
+    }
+
+    // 256 random bits, url safe, for links.
+    pub fn token(ttl_seconds: i64) -> (String, OneTimeCode) {
+
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+
+        let token = URL_SAFE_NO_PAD.encode(bytes);
+        let stored = OneTimeCode::from_secret(&token, ttl_seconds);
+
+        return (token, stored);
+    }
+
+    // Seconds until a replacement may be sent, 0 once cooldown_seconds have
+    // passed since this one was issued.
+    pub fn seconds_until_resend(&self, cooldown_seconds: i64) -> i64 {
+
+        let wait = self.issued_at.timestamp_millis() + cooldown_seconds * 1000 - DateTime::now().timestamp_millis();
+
+        return (wait.max(0) + 999) / 1000;
+    }
+
+    // Attempts are counted by the database before the check, see
+    // Database::take_code_attempt, so parallel guesses can't share one count.
+    // attempt_counted is its answer: once max_attempts are used up even the
+    // right code is refused.
+    pub fn check(&self, secret: &str, attempt_counted: bool) -> CodeCheck {
+
+        if !attempt_counted {
+            return CodeCheck::TooManyAttempts;
+        }
+
+        if DateTime::now() > self.expires_at {
+            return CodeCheck::Expired;
+        }
+
+        if constant_time_eq(hash(secret.trim()).as_bytes(), self.code_hash.as_bytes()) {
+            return CodeCheck::Valid;
+        }
+
+        return CodeCheck::Invalid;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numeric_codes_are_zero_padded_digits() {
+        for _ in 0..50 {
+            let (code, _) = OneTimeCode::numeric(6, 60);
+            assert_eq!(code.len(), 6);
+            assert!(code.chars().all(|c| c.is_ascii_digit()));
+        }
+    }
+
+    #[test]
+    fn only_the_hash_is_stored() {
+        let (token, stored) = OneTimeCode::token(60);
+        assert_eq!(URL_SAFE_NO_PAD.decode(&token).unwrap().len(), 32);
+        assert!(!format!("{:?}", stored).contains(&token));
+        assert_eq!(stored.attempts, 0);
+    }
+
+    #[test]
+    fn check_matches_the_issued_secret() {
+        let (code, stored) = OneTimeCode::numeric(6, 60);
+        assert_eq!(stored.check(&code, true), CodeCheck::Valid);
+        // pasted codes often come with whitespace
+        assert_eq!(stored.check(&format!(" {}\n", code), true), CodeCheck::Valid);
+        assert_eq!(stored.check("not it", true), CodeCheck::Invalid);
+    }
+
+    #[test]
+    fn check_refuses_once_attempts_are_used_up() {
+        let (code, stored) = OneTimeCode::numeric(6, 60);
+        assert_eq!(stored.check(&code, false), CodeCheck::TooManyAttempts);
+    }
+
+    #[test]
+    fn check_refuses_expired_codes() {
+        let (code, stored) = OneTimeCode::numeric(6, -1);
+        assert_eq!(stored.check(&code, true), CodeCheck::Expired);
+    }
+
+    #[test]
+    fn resend_waits_out_the_cooldown() {
+        let (_, mut stored) = OneTimeCode::numeric(6, 600);
+        let wait = stored.seconds_until_resend(60);
+        assert!(wait > 58 && wait <= 60);
+        stored.issued_at = DateTime::from_millis(stored.issued_at.timestamp_millis() - 61_000);
+        assert_eq!(stored.seconds_until_resend(60), 0);
+    }
+}
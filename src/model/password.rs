@@ -0,0 +1,184 @@
+use std::fmt;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use pbkdf2::pbkdf2_hmac;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use strum_macros::Display;
+use crate::metrics::BCRYPT_DURATION;
+
+// Password hashes are stored as strings in UserCredentail. New ones are always
+// bcrypt, the legacy schemes are only accepted from bulk imports and get
+// replaced with bcrypt the first time the user logs in successfully.
+//
+// Legacy strings use the Django layouts most exports already come in:
+//   pbkdf2_sha256$<iterations>$<salt>$<base64 hash>
+//   sha1$<salt>$<hex sha1(salt + password)>
+
+#[derive(Debug, Display, PartialEq, Eq, Clone, Copy)]
+#[strum(serialize_all = "snake_case")]
+pub enum PasswordScheme {
+    Bcrypt,
+    Pbkdf2Sha256,
+    SaltedSha1,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PasswordHashError {
+    UnknownScheme,
+    Malformed(PasswordScheme),
+}
+
+impl fmt::Display for PasswordHashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordHashError::UnknownScheme => write!(f, "unrecognised password hash format"),
+            PasswordHashError::Malformed(scheme) => write!(f, "malformed {} password hash", scheme),
+        }
+    }
+}
+
+pub enum PasswordHash {
+    Bcrypt(String),
+    Pbkdf2Sha256 { iterations: u32, salt: String, hash: Vec<u8> },
+    SaltedSha1 { salt: String, hash: String },
+}
+
+impl PasswordHash {
+    pub fn parse (stored: &str) -> Result<PasswordHash, PasswordHashError> {
+
+        if stored.starts_with("$2") {
+            if stored.parse::<bcrypt::HashParts>().is_err() {
+                return Err(PasswordHashError::Malformed(PasswordScheme::Bcrypt));
+            }
+            return Ok(PasswordHash::Bcrypt(stored.to_owned()));
+        }
+
+        let parts: Vec<&str> = stored.split('$').collect();
+
+        match parts.as_slice() {
+            ["pbkdf2_sha256", iterations, salt, hash] => {
+                let iterations = iterations.parse::<u32>().ok().filter(|iterations| *iterations > 0);
+                let hash = STANDARD.decode(hash).ok().filter(|hash| !hash.is_empty());
+
+                match (iterations, hash) {
+                    (Some(iterations), Some(hash)) if !salt.is_empty() => {
+                        return Ok(PasswordHash::Pbkdf2Sha256 { iterations, salt: salt.to_string(), hash });
+                    },
+                    _ => return Err(PasswordHashError::Malformed(PasswordScheme::Pbkdf2Sha256)),
+                }
+            },
+            ["sha1", salt, hash] => {
+                if hash.len() != 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(PasswordHashError::Malformed(PasswordScheme::SaltedSha1));
+                }
+
+                return Ok(PasswordHash::SaltedSha1 { salt: salt.to_string(), hash: hash.to_ascii_lowercase() });
+            },
+            _ => return Err(PasswordHashError::UnknownScheme),
+        }
+    }
+
+    pub fn scheme (&self) -> PasswordScheme {
+        match self {
+            PasswordHash::Bcrypt(_) => PasswordScheme::Bcrypt,
+            PasswordHash::Pbkdf2Sha256 { .. } => PasswordScheme::Pbkdf2Sha256,
+            PasswordHash::SaltedSha1 { .. } => PasswordScheme::SaltedSha1,
+        }
+    }
+
+    pub fn verify (&self, plain_password: &str) -> bool {
+
+        match self {
+            PasswordHash::Bcrypt(hash) => {
+                let _verify_timer = BCRYPT_DURATION.with_label_values(&["verify"]).start_timer();
+                return bcrypt::verify(plain_password, hash).unwrap_or(false);
+            },
+            PasswordHash::Pbkdf2Sha256 { iterations, salt, hash } => {
+                let mut derived = vec![0u8; hash.len()];
+                pbkdf2_hmac::<Sha256>(plain_password.as_bytes(), salt.as_bytes(), *iterations, &mut derived);
+                return constant_time_eq(&derived, hash);
+            },
+            PasswordHash::SaltedSha1 { salt, hash } => {
+                let mut hasher = Sha1::new();
+                hasher.update(salt.as_bytes());
+                hasher.update(plain_password.as_bytes());
+                let derived = format!("{:x}", hasher.finalize());
+                return constant_time_eq(derived.as_bytes(), hash.as_bytes());
+            },
+        }
+    }
+}
+
+// Hashes that can't be parsed never match, a corrupt row shouldn't take the
+// login handler down with it.
+pub fn verify_stored (stored: &str, plain_password: &str) -> Option<PasswordScheme> {
+
+    let hash = PasswordHash::parse(stored).ok()?;
+
+    if hash.verify(plain_password) {
+        return Some(hash.scheme());
+    }
+
+    return None;
+}
+
+pub fn constant_time_eq (a: &[u8], b: &[u8]) -> bool {
+
+    if a.len() != b.len() {
+        return false;
+    }
+
+    return a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // generated with Python's hashlib, the same derivation Django uses
+    const PBKDF2: &str = "pbkdf2_sha256$1000$seasalt$mQnueSakb748zqBAC1tmWVZsZbi2zPGZarEzTGdfmso=";
+    const SHA1: &str = "sha1$seasalt$55eef163bf2e349b9946e3183eef620dde0247f5";
+
+    #[test]
+    fn parses_legacy_schemes() {
+        assert_eq!(PasswordHash::parse(PBKDF2).unwrap().scheme(), PasswordScheme::Pbkdf2Sha256);
+        assert_eq!(PasswordHash::parse(SHA1).unwrap().scheme(), PasswordScheme::SaltedSha1);
+    }
+
+    #[test]
+    fn rejects_malformed_hashes() {
+        assert_eq!(PasswordHash::parse("md5$salt$abc").err(), Some(PasswordHashError::UnknownScheme));
+        assert_eq!(PasswordHash::parse("pbkdf2_sha256$0$seasalt$AAAA").err(), Some(PasswordHashError::Malformed(PasswordScheme::Pbkdf2Sha256)));
+        assert_eq!(PasswordHash::parse("pbkdf2_sha256$1000$$AAAA").err(), Some(PasswordHashError::Malformed(PasswordScheme::Pbkdf2Sha256)));
+        assert_eq!(PasswordHash::parse("pbkdf2_sha256$1000$seasalt$not base64").err(), Some(PasswordHashError::Malformed(PasswordScheme::Pbkdf2Sha256)));
+        assert_eq!(PasswordHash::parse("sha1$seasalt$55eef163").err(), Some(PasswordHashError::Malformed(PasswordScheme::SaltedSha1)));
+        assert_eq!(PasswordHash::parse("$2b$04$short").err(), Some(PasswordHashError::Malformed(PasswordScheme::Bcrypt)));
+    }
+
+    #[test]
+    fn verifies_pbkdf2_sha256() {
+        assert_eq!(verify_stored(PBKDF2, "correct horse"), Some(PasswordScheme::Pbkdf2Sha256));
+        assert_eq!(verify_stored(PBKDF2, "correct horse "), None);
+    }
+
+    #[test]
+    fn verifies_salted_sha1() {
+        assert_eq!(verify_stored(SHA1, "correct horse"), Some(PasswordScheme::SaltedSha1));
+        assert_eq!(verify_stored(SHA1, "Correct horse"), None);
+        // some exports upper case the hex digest
+        assert_eq!(verify_stored("sha1$seasalt$55EEF163BF2E349B9946E3183EEF620DDE0247F5", "correct horse"), Some(PasswordScheme::SaltedSha1));
+    }
+
+    #[test]
+    fn verifies_bcrypt() {
+        let hash = bcrypt::hash("correct horse", 4).unwrap();
+        assert_eq!(verify_stored(&hash, "correct horse"), Some(PasswordScheme::Bcrypt));
+        assert_eq!(verify_stored(&hash, "battery staple"), None);
+    }
+
+    #[test]
+    fn unparseable_hashes_never_match() {
+        assert_eq!(verify_stored("", ""), None);
+        assert_eq!(verify_stored("plaintext", "plaintext"), None);
+    }
+}
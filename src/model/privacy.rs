@@ -0,0 +1,105 @@
+use bson::DateTime;
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+use crate::model::user::User;
+use crate::model::credentail::UserCredentail;
+use crate::model::mfa_factor::MfaFactorSummary;
+
+// Data subject requests. DataExport is everything stored about a user,
+// minus secrets: no password hashes and no MFA secret. Tokens aren't stored
+// anywhere, so there are no sessions to export or erase.
+
+#[derive(Serialize, Deserialize)]
+pub struct CredentailMetadata {
+    pub mfa_factors: Vec<MfaFactorSummary>,
+    pub password_changed: Vec<DateTime>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DataExport {
+    pub generated_at: DateTime,
+    pub user: User,
+    pub credentail: Option<CredentailMetadata>,
+}
+
+impl DataExport {
+    pub fn new (user: User, credentail: Option<UserCredentail>) -> DataExport {
+        return DataExport {
+            generated_at: DateTime::now(),
+            user,
+            credentail: credentail.map(|credentail| CredentailMetadata {
+                password_changed: credentail.password_changed_dates(),
+                mfa_factors: credentail.factor_summaries(),
+            }),
+        };
+    }
+}
+
+pub const RECEIPT_TYPE: &str = "erasure-receipt+jwt";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErasureClaims {
+    pub receipt_uuid: String,
+    pub sub: String,
+    pub requested_by: String,
+    pub erased: Vec<String>,
+    pub iat: i64,
+}
+
+// The receipt is signed with the token key, so anyone holding public.pem can
+// check it was issued by this service. Its own typ keeps it from passing as
+// an access token. It's the only thing left that names the erased uuid and
+// it isn't stored, keeping it is up to the requester.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErasureReceipt {
+    pub receipt: String,
+    pub claims: ErasureClaims,
+}
+
+impl ErasureReceipt {
+    // erased lists what was actually deleted, e.g. no "avatar" for a user
+    // who never uploaded one.
+    pub fn new (user_uuid: String, requested_by: String, erased: Vec<String>) -> Result<ErasureReceipt, jsonwebtoken::errors::Error> {
+
+        let claims = ErasureClaims {
+            receipt_uuid: Uuid::new_v4().to_string(),
+            sub: user_uuid,
+            requested_by,
+            erased,
+            iat: chrono::Utc::now().timestamp(),
+        };
+
+        let mut header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::ES256);
+        header.typ = Some(RECEIPT_TYPE.to_owned());
+        let receipt = jsonwebtoken::encode(
+            &header,
+            &claims,
+            &jsonwebtoken::EncodingKey::from_ec_pem(include_bytes!("../../private.pem"))?,
+        )?;
+
+        return Ok(ErasureReceipt { receipt, claims });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_receipt_has_its_own_type_and_lists_what_was_erased() {
+        let receipt = ErasureReceipt::new(
+            "5f0c7e52-3c1b-4a8e-9d55-0b8c1f1e2a10".to_owned(),
+            "5f0c7e52-3c1b-4a8e-9d55-0b8c1f1e2a10".to_owned(),
+            vec!["user".to_owned(), "credentail".to_owned()],
+        ).unwrap();
+
+        let header = jsonwebtoken::decode_header(&receipt.receipt).unwrap();
+        assert_eq!(header.typ.as_deref(), Some(RECEIPT_TYPE));
+
+        let key = jsonwebtoken::DecodingKey::from_ec_pem(include_bytes!("../../public.pem")).unwrap();
+        let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::ES256);
+        validation.required_spec_claims.clear();
+        let decoded = jsonwebtoken::decode::<ErasureClaims>(&receipt.receipt, &key, &validation).unwrap();
+        assert_eq!(decoded.claims.erased, vec!["user", "credentail"]);
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use serde::{Serialize, Deserialize, Deserializer};
+use strum_macros::{EnumString, Display};
+
+// Optional self-service attributes on a User. Everything is validated on the
+// way in through ProfilePatch, so stored profiles can be trusted to be sane.
+
+const MAX_NAME_CHARS: usize = 64;
+const MAX_LOCALE_LEN: usize = 35;
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct UserProfile {
+    pub display_name: Option<String>,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    pub locale: Option<String>,
+    pub timezone: Option<String>,
+    // key in the blob store, served from /user/{user_uuid}/avatar
+    pub avatar: Option<String>,
+}
+
+// Profile attributes that can be copied into tokens, see profile.token_claims
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, EnumString, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ProfileClaim {
+    DisplayName,
+    GivenName,
+    FamilyName,
+    Locale,
+    Timezone,
+}
+
+#[derive(Debug, Display, PartialEq, Eq)]
+pub enum ProfileError {
+    InvalidDisplayName,
+    InvalidGivenName,
+    InvalidFamilyName,
+    InvalidLocale,
+    InvalidTimezone,
+}
+
+// Distinguishes a missing field (leave alone) from an explicit null (clear).
+fn present<'de, D>(deserializer: D) -> Result<Option<Option<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    return Option::<String>::deserialize(deserializer).map(Some);
+}
+
+// Body of PATCH /me, merge-patch style: leave a field out to keep it, send
+// null to clear it. The avatar has its own endpoint.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ProfilePatch {
+    #[serde(default, deserialize_with = "present")]
+    pub display_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub given_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub family_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub locale: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub timezone: Option<Option<String>>,
+}
+
+fn valid_name(name: &str) -> bool {
+    let length = name.chars().count();
+    return (1..=MAX_NAME_CHARS).contains(&length) && !name.chars().any(char::is_control);
+}
+
+// BCP 47 shape only (en, en-GB, zh-Hant-TW), not checked against the registry.
+fn valid_locale(locale: &str) -> bool {
+
+    if locale.len() > MAX_LOCALE_LEN {
+        return false;
+    }
+
+    let mut subtags = locale.split('-');
+
+    let language_ok = subtags.next()
+        .is_some_and(|language| (2..=8).contains(&language.len()) && language.chars().all(|c| c.is_ascii_alphabetic()));
+
+    return language_ok && subtags.all(|subtag| (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric()));
+}
+
+fn valid_timezone(timezone: &str) -> bool {
+    return chrono_tz::Tz::from_str(timezone).is_ok();
+}
+
+// Names are trimmed before they're checked and stored.
+fn patch_field(
+    target: &mut Option<String>,
+    patch: Option<Option<String>>,
+    valid: fn(&str) -> bool,
+    error: ProfileError,
+) -> Result<(), ProfileError> {
+
+    match patch {
+        None => {},
+        Some(None) => *target = None,
+        Some(Some(value)) => {
+            let value = value.trim();
+
+            if !valid(value) {
+                return Err(error);
+            }
+
+            *target = Some(value.to_owned());
+        },
+    }
+
+    return Ok(());
+}
+
+impl UserProfile {
+
+    // Either the whole patch applies or none of it does.
+    pub fn apply (&mut self, patch: ProfilePatch) -> Result<(), ProfileError> {
+
+        let mut updated = self.clone();
+
+        patch_field(&mut updated.display_name, patch.display_name, valid_name, ProfileError::InvalidDisplayName)?;
+        patch_field(&mut updated.given_name, patch.given_name, valid_name, ProfileError::InvalidGivenName)?;
+        patch_field(&mut updated.family_name, patch.family_name, valid_name, ProfileError::InvalidFamilyName)?;
+        patch_field(&mut updated.locale, patch.locale, valid_locale, ProfileError::InvalidLocale)?;
+        patch_field(&mut updated.timezone, patch.timezone, valid_timezone, ProfileError::InvalidTimezone)?;
+
+        *self = updated;
+
+        return Ok(());
+    }
+
+    pub fn get (&self, claim: ProfileClaim) -> Option<&String> {
+        match claim {
+            ProfileClaim::DisplayName => return self.display_name.as_ref(),
+            ProfileClaim::GivenName => return self.given_name.as_ref(),
+            ProfileClaim::FamilyName => return self.family_name.as_ref(),
+            ProfileClaim::Locale => return self.locale.as_ref(),
+            ProfileClaim::Timezone => return self.timezone.as_ref(),
+        }
+    }
+
+    // The configured attributes that are set, keyed by claim name.
+    pub fn token_claims (&self, claims: &[ProfileClaim]) -> BTreeMap<String, String> {
+        return claims.iter()
+            .filter_map(|claim| self.get(*claim).map(|value| (claim.to_string(), value.clone())))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn patch(body: serde_json::Value) -> ProfilePatch {
+        return serde_json::from_value(body).unwrap();
+    }
+
+    fn profile() -> UserProfile {
+        return UserProfile {
+            display_name: Some("Bob".to_owned()),
+            locale: Some("en-GB".to_owned()),
+            timezone: Some("Europe/London".to_owned()),
+            ..UserProfile::default()
+        };
+    }
+
+    #[test]
+    fn locales_need_the_bcp_47_shape() {
+        assert!(valid_locale("en"));
+        assert!(valid_locale("en-GB"));
+        assert!(valid_locale("zh-Hant-TW"));
+        assert!(valid_locale("es-419"));
+        assert!(!valid_locale(""));
+        assert!(!valid_locale("e"));
+        assert!(!valid_locale("en_GB"));
+        assert!(!valid_locale("en-"));
+        assert!(!valid_locale("12-GB"));
+        assert!(!valid_locale("en-toolongsubtag"));
+        assert!(!valid_locale(&format!("en{}", "-abc".repeat(9))));
+    }
+
+    #[test]
+    fn timezones_come_from_the_tz_database() {
+        assert!(valid_timezone("Europe/London"));
+        assert!(valid_timezone("America/Argentina/Buenos_Aires"));
+        assert!(valid_timezone("UTC"));
+        assert!(!valid_timezone("Europe/Atlantis"));
+        assert!(!valid_timezone("+01:00"));
+        assert!(!valid_timezone(""));
+    }
+
+    #[test]
+    fn an_absent_field_is_left_alone() {
+        let mut profile = profile();
+
+        profile.apply(patch(json!({"given_name": " Robert "}))).unwrap();
+
+        assert_eq!(profile.given_name.as_deref(), Some("Robert"));
+        assert_eq!(profile.display_name.as_deref(), Some("Bob"));
+        assert_eq!(profile.locale.as_deref(), Some("en-GB"));
+        assert_eq!(profile.timezone.as_deref(), Some("Europe/London"));
+    }
+
+    #[test]
+    fn null_clears_a_field() {
+        let mut profile = profile();
+
+        profile.apply(patch(json!({"display_name": null, "timezone": null}))).unwrap();
+
+        assert_eq!(profile.display_name, None);
+        assert_eq!(profile.timezone, None);
+        assert_eq!(profile.locale.as_deref(), Some("en-GB"));
+    }
+
+    #[test]
+    fn an_invalid_field_leaves_the_whole_profile() {
+        let mut profile = profile();
+
+        let result = profile.apply(patch(json!({"display_name": "Robert", "timezone": "Mars/Olympus_Mons"})));
+
+        assert_eq!(result, Err(ProfileError::InvalidTimezone));
+        assert_eq!(profile, self::profile());
+
+        assert_eq!(profile.apply(patch(json!({"locale": "en_GB"}))), Err(ProfileError::InvalidLocale));
+        assert_eq!(profile.apply(patch(json!({"given_name": "  "}))), Err(ProfileError::InvalidGivenName));
+    }
+
+    #[test]
+    fn unknown_fields_and_the_avatar_are_refused() {
+        assert!(serde_json::from_value::<ProfilePatch>(json!({"avatar": null})).is_err());
+        assert!(serde_json::from_value::<ProfilePatch>(json!({"nickname": "Bobby"})).is_err());
+    }
+}
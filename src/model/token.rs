@@ -1,7 +1,10 @@
+use std::collections::BTreeMap;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use jsonwebtoken::Validation;
 use crate::model::claims::Claims;
+use crate::metrics::{TOKENS_ISSUED, TOKEN_VALIDATIONS};
+use strum_macros::{EnumString, Display};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Token {
@@ -12,13 +15,78 @@ pub struct Token {
     pub claims: Option<TokenClaims>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, EnumString, Display)]
 pub enum TokenAuthType {
     Full,
     RequiresMFA,
     RequiresValidation,
 }
 
+// How the user proved who they are, the amr values of RFC 8176 where there
+// is one.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum AuthMethod {
+    Pwd,
+    // authenticator app
+    Otp,
+    Sms,
+    // a code or link mailed to user_email
+    Email,
+    // security keys and passkeys, nothing issues it yet
+    Webauthn,
+}
+
+// acr, aal2 once two different methods were used.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, EnumString, Display, Default)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum AuthAssurance {
+    #[default]
+    Aal1,
+    Aal2,
+}
+
+// When and how the user last authenticated, what Token::new turns into
+// auth_time, amr and acr.
+#[derive(Debug, Clone)]
+pub struct Authentication {
+    pub auth_time: i64,
+    pub amr: Vec<AuthMethod>,
+}
+
+impl Authentication {
+    // The user just authenticated with method.
+    pub fn now(method: AuthMethod) -> Authentication {
+        return Authentication { auth_time: chrono::Utc::now().timestamp(), amr: vec![method] };
+    }
+
+    // For a token reissued without the user authenticating again.
+    pub fn from_claims(claims: &TokenClaims) -> Authentication {
+        return Authentication { auth_time: claims.auth_time, amr: claims.amr.clone() };
+    }
+
+    // Another method on top, e.g. the second factor after the password.
+    pub fn with(mut self, method: AuthMethod) -> Authentication {
+        if !self.amr.contains(&method) {
+            self.amr.push(method);
+        }
+
+        self.auth_time = chrono::Utc::now().timestamp();
+
+        return self;
+    }
+
+    fn acr(&self) -> AuthAssurance {
+        if self.amr.len() >= 2 {
+            return AuthAssurance::Aal2;
+        }
+
+        return AuthAssurance::Aal1;
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TokenClaims {
     pub sub: String,
@@ -27,7 +95,17 @@ pub struct TokenClaims {
     pub exp: i64,
     pub iat: i64,
     pub nbf: i64,
-    pub auth_type: TokenAuthType
+    pub auth_type: TokenAuthType,
+    // profile attributes picked by profile.token_claims
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profile: BTreeMap<String, String>,
+    // unix seconds, 0 on tokens from before it was recorded
+    #[serde(default)]
+    pub auth_time: i64,
+    #[serde(default)]
+    pub amr: Vec<AuthMethod>,
+    #[serde(default)]
+    pub acr: AuthAssurance,
 }
 
 pub enum ValidateError {
@@ -54,7 +132,9 @@ impl Token {
         user_id: String,
         ttl: i64,
         user_claims: Claims,
-        auth_type: TokenAuthType
+        auth_type: TokenAuthType,
+        profile: BTreeMap<String, String>,
+        authentication: Authentication,
     ) -> Result<Token, jsonwebtoken::errors::Error> {
     
         let now = chrono::Utc::now();
@@ -71,10 +151,14 @@ impl Token {
             sub: token_details.user_id.as_ref().unwrap().to_string(),
             token_uuid: token_details.token_uuid.as_ref().unwrap().to_string(),
             user_claim: user_claims,
-            exp: token_details.expires_in.clone().unwrap(),
+            exp: token_details.expires_in.unwrap(),
             iat: now.timestamp(),
             nbf: now.timestamp(),
-            auth_type
+            auth_type,
+            profile,
+            auth_time: authentication.auth_time,
+            acr: authentication.acr(),
+            amr: authentication.amr,
         };
     
         let header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::ES256);
@@ -83,16 +167,26 @@ impl Token {
             &claims,
             &jsonwebtoken::EncodingKey::from_ec_pem(include_bytes!("../../private.pem"))?,
         )?;
+        TOKENS_ISSUED.with_label_values(&[&claims.auth_type.to_string()]).inc();
+
         token_details.token = Some(token);
         token_details.claims = Some(claims);
         return Ok(token_details);
     }
 
+    // The keys are compiled in, this checks they actually parse so the
+    // readiness probe can report a broken build before logins fail.
+    pub fn signing_keys_loaded() -> bool {
+        return jsonwebtoken::EncodingKey::from_ec_pem(include_bytes!("../../private.pem")).is_ok()
+            && jsonwebtoken::DecodingKey::from_ec_pem(include_bytes!("../../public.pem")).is_ok();
+    }
+
     pub fn validate_jwt_token(&mut self) -> Result<bool, ValidateError> {
 
         let validation = Validation::new(jsonwebtoken::Algorithm::ES256);
 
         if self.token.is_none() {
+            TOKEN_VALIDATIONS.with_label_values(&["missing"]).inc();
             return Err(ValidateError::NoToken)
         }
 
@@ -104,16 +198,45 @@ impl Token {
             &validation);
 
         if token_data.is_ok() {
+            TOKEN_VALIDATIONS.with_label_values(&["valid"]).inc();
             self.claims = Some(token_data.clone().unwrap().claims);
             self.user_id = Some(token_data.clone().unwrap().claims.user_claim.user_name);
             self.token_uuid = Some(token_data.clone().unwrap().claims.token_uuid);
             return Ok(true);
         }
 
-        println!("{:?}", token_data.err());
+        TOKEN_VALIDATIONS.with_label_values(&["invalid"]).inc();
+
+        tracing::warn!(error = ?token_data.err(), "token failed validation");
 
         return Err(ValidateError::TokenNotValid);
         
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_method_is_aal1() {
+        assert_eq!(Authentication::now(AuthMethod::Pwd).acr(), AuthAssurance::Aal1);
+        // the same method twice is still one
+        assert_eq!(Authentication::now(AuthMethod::Pwd).with(AuthMethod::Pwd).acr(), AuthAssurance::Aal1);
+    }
+
+    #[test]
+    fn two_methods_are_aal2() {
+        assert_eq!(Authentication::now(AuthMethod::Pwd).with(AuthMethod::Otp).acr(), AuthAssurance::Aal2);
+        assert_eq!(Authentication::now(AuthMethod::Email).with(AuthMethod::Sms).acr(), AuthAssurance::Aal2);
+    }
+
+    #[test]
+    fn with_refreshes_auth_time() {
+        let authentication = Authentication { auth_time: 0, amr: vec![AuthMethod::Pwd] }.with(AuthMethod::Otp);
+
+        assert!(authentication.auth_time > 0);
+        assert_eq!(authentication.amr, vec![AuthMethod::Pwd, AuthMethod::Otp]);
+    }
+}
@@ -0,0 +1,180 @@
+use serde::{Serialize, Deserialize};
+use strum_macros::{EnumString, Display};
+use totp_rs::{Algorithm, Secret, TOTP};
+use crate::config::MfaConfig;
+use crate::model::password::constant_time_eq;
+
+// Authenticator app codes (RFC 6238). Secrets are stored base32 encoded, the
+// form authenticator apps take them in, and each credentail remembers the
+// algorithm, digits and period it was enrolled with so changing the defaults
+// in [mfa] doesn't break existing enrolments.
+
+// RFC 4226 asks for at least 128 bits, generate_secret makes the
+// recommended 160.
+const MIN_SECRET_BYTES: usize = 16;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(PartialEq, Serialize, Deserialize, EnumString, Display, Eq, Debug, Clone, Copy)]
+pub enum TotpAlgorithm {
+    SHA1,
+    SHA256,
+    SHA512,
+}
+
+impl TotpAlgorithm {
+    fn algorithm(self) -> Algorithm {
+        match self {
+            TotpAlgorithm::SHA1 => Algorithm::SHA1,
+            TotpAlgorithm::SHA256 => Algorithm::SHA256,
+            TotpAlgorithm::SHA512 => Algorithm::SHA512,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TotpSettings {
+    pub algorithm: TotpAlgorithm,
+    pub digits: usize,
+    // seconds per time step
+    pub period: u64,
+}
+
+impl TotpSettings {
+    // What new enrolments get.
+    pub fn from_config(config: &MfaConfig) -> TotpSettings {
+        return TotpSettings {
+            algorithm: config.totp_algorithm,
+            digits: config.totp_digits,
+            period: config.totp_step,
+        };
+    }
+}
+
+// What most authenticator apps assume when the uri doesn't say otherwise.
+impl Default for TotpSettings {
+    fn default() -> TotpSettings {
+        return TotpSettings {
+            algorithm: TotpAlgorithm::SHA1,
+            digits: 6,
+            period: 30,
+        };
+    }
+}
+
+pub fn generate_secret() -> String {
+    return Secret::generate_secret().to_encoded().to_string();
+}
+
+pub fn encode_secret(bytes: &[u8]) -> String {
+    return Secret::Raw(bytes.to_vec()).to_encoded().to_string();
+}
+
+// Accepts secrets the way people copy them around: lower case, grouped with
+// spaces or dashes, with or without '=' padding.
+pub fn decode_secret(encoded: &str) -> Option<Vec<u8>> {
+
+    let cleaned: String = encoded.chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '='))
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+
+    let bytes = Secret::Encoded(cleaned).to_bytes().ok()?;
+
+    if bytes.len() < MIN_SECRET_BYTES {
+        return None;
+    }
+
+    return Some(bytes);
+}
+
+fn totp(secret: &[u8], settings: &TotpSettings, issuer: Option<String>, account_name: String) -> TOTP {
+    // unchecked since legacy secrets predate the length rule, digits are
+    // checked when the config is loaded
+    return TOTP::new_unchecked(settings.algorithm.algorithm(), settings.digits, 0, settings.period, secret.to_vec(), issuer, account_name);
+}
+
+// The time step the code belongs to, looking up to skew steps either side of
+// time. If a code happens to match more than one step the latest wins, so a
+// collision with an already used step can't lock out a fresh code.
+pub fn matching_step(secret: &[u8], settings: &TotpSettings, code: &str, time: u64, skew: u8) -> Option<u64> {
+
+    let totp = totp(secret, settings, None, String::new());
+    let current = time / settings.period;
+    let code = code.trim();
+
+    let mut matched = None;
+
+    for step in current.saturating_sub(skew as u64)..=current + skew as u64 {
+        if constant_time_eq(totp.generate(step * settings.period).as_bytes(), code.as_bytes()) {
+            matched = Some(step);
+        }
+    }
+
+    return matched;
+}
+
+// otpauth://totp/... for a QR code, parameters at their defaults are left
+// out as the Key Uri Format suggests.
+pub fn provisioning_uri(secret: &[u8], settings: &TotpSettings, issuer: &str, account_name: &str) -> String {
+    return totp(secret, settings, Some(issuer.to_owned()), account_name.to_owned()).get_url();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6238 appendix B, the seeds are the ASCII bytes shown there
+    const SHA1_SEED: &[u8] = b"12345678901234567890";
+    const SHA256_SEED: &[u8] = b"12345678901234567890123456789012";
+    const SHA512_SEED: &[u8] = b"1234567890123456789012345678901234567890123456789012345678901234";
+
+    const VECTORS: [(u64, &str, &str, &str); 6] = [
+        (59, "94287082", "46119246", "90693936"),
+        (1111111109, "07081804", "68084774", "25091201"),
+        (1111111111, "14050471", "67062674", "99943326"),
+        (1234567890, "89005924", "91819424", "93441116"),
+        (2000000000, "69279037", "90698825", "38618901"),
+        (20000000000, "65353130", "77737706", "47863826"),
+    ];
+
+    fn settings(algorithm: TotpAlgorithm) -> TotpSettings {
+        return TotpSettings { algorithm, digits: 8, period: 30 };
+    }
+
+    #[test]
+    fn matches_the_rfc_6238_vectors() {
+        for (time, sha1, sha256, sha512) in VECTORS {
+            let step = Some(time / 30);
+            assert_eq!(matching_step(SHA1_SEED, &settings(TotpAlgorithm::SHA1), sha1, time, 0), step, "SHA1 at {}", time);
+            assert_eq!(matching_step(SHA256_SEED, &settings(TotpAlgorithm::SHA256), sha256, time, 0), step, "SHA256 at {}", time);
+            assert_eq!(matching_step(SHA512_SEED, &settings(TotpAlgorithm::SHA512), sha512, time, 0), step, "SHA512 at {}", time);
+        }
+    }
+
+    #[test]
+    fn skew_reaches_neighbouring_steps_only() {
+        let settings = settings(TotpAlgorithm::SHA1);
+
+        assert_eq!(matching_step(SHA1_SEED, &settings, "07081804", 1111111109 + 30, 1), Some(1111111109 / 30));
+        assert_eq!(matching_step(SHA1_SEED, &settings, "07081804", 1111111109 + 60, 1), None);
+        assert_eq!(matching_step(SHA1_SEED, &settings, "07081805", 1111111109, 1), None);
+    }
+
+    #[test]
+    fn decode_secret_takes_copied_forms() {
+        let encoded = encode_secret(SHA1_SEED);
+
+        assert_eq!(encoded, "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(decode_secret(&encoded).unwrap(), SHA1_SEED);
+        assert_eq!(decode_secret("gezd gnbv gy3t qojq gezd gnbv gy3t qojq").unwrap(), SHA1_SEED);
+        assert_eq!(decode_secret("GEZDGNBV-GY3TQOJQ-GEZDGNBV-GY3TQOJQ").unwrap(), SHA1_SEED);
+        assert_eq!(decode_secret(&encode_secret(SHA256_SEED)).unwrap(), SHA256_SEED);
+    }
+
+    #[test]
+    fn decode_secret_refuses_short_and_invalid_secrets() {
+        assert_eq!(decode_secret(&encode_secret(b"0123456789abcde")), None);
+        assert_eq!(decode_secret("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJ1"), None);
+        assert_eq!(decode_secret(""), None);
+    }
+}
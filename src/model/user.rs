@@ -2,8 +2,12 @@
 use bson::DateTime;
 use strum_macros::{EnumString, Display};
 use serde::{Serialize, Deserialize};
+use unicode_normalization::UnicodeNormalization;
 use uuid::Uuid;
+use crate::model::SCHEMA_VERSION;
 use crate::model::claims::{Claims, ClaimsUserType};
+use crate::model::profile::UserProfile;
+use crate::model::username::Username;
 
 #[derive(PartialEq, Serialize, Deserialize, EnumString, Display, Eq, Clone)]
 pub enum UserState {
@@ -17,10 +21,96 @@ pub enum UserState {
 #[derive(Serialize, Deserialize, Clone)]
 pub struct User {
     pub user_uuid: String,
+    // always canonical_email, it's what logins and the unique index compare
     pub user_email: String,
+    // optional handle, stored normalized, see model/username.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username_skeleton: Option<String>,
+    // E.164, only set once a code sent to it came back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phone_number: Option<String>,
     pub user_state: UserState,
     pub last_login: DateTime,
     pub user_claims: Claims,
+    #[serde(default)]
+    pub profile: UserProfile,
+    // 0 on documents written before versioning, the migrations stamp them
+    #[serde(default)]
+    pub schema_version: u32,
+}
+
+// What other signed in users get from GET /user/{user_uuid}. No email,
+// state, claims or login times, only what's needed to show who someone is.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PublicUser {
+    pub user_uuid: String,
+    pub username: Option<String>,
+    pub display_name: Option<String>,
+    pub has_avatar: bool,
+}
+
+impl From<User> for PublicUser {
+    fn from(user: User) -> PublicUser {
+        return PublicUser {
+            user_uuid: user.user_uuid,
+            username: user.username,
+            display_name: user.profile.display_name,
+            has_avatar: user.profile.avatar.is_some(),
+        };
+    }
+}
+
+// A user as some particular caller may see them.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum UserView {
+    Full(Box<User>),
+    Public(PublicUser),
+}
+
+const MAX_EMAIL_LEN: usize = 254;
+
+// Addresses are compared case insensitively. Strictly the local part is case
+// sensitive, but no provider in practice treats Bob@ and bob@ as two people.
+pub fn canonical_email(email: &str) -> String {
+    return email.trim().nfkc().collect::<String>().to_lowercase();
+}
+
+// Shape check only, whether the address works is proven by the code sent to it.
+pub fn valid_email(email: &str) -> bool {
+
+    if email.len() > MAX_EMAIL_LEN || email.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return false;
+    }
+
+    match email.rsplit_once('@') {
+        Some((local, domain)) => return !local.is_empty() && !domain.starts_with('.') && domain.contains('.') && !domain.ends_with('.'),
+        None => return false,
+    }
+}
+
+// Drops the punctuation people type into phone numbers, "+1 (555) 010-9999"
+// becomes "+15550109999". A leading 00 is the international prefix.
+pub fn canonical_phone_number(phone_number: &str) -> String {
+
+    let digits: String = phone_number.trim().chars().filter(|c| !matches!(c, ' ' | '-' | '.' | '(' | ')')).collect();
+
+    match digits.strip_prefix("00") {
+        Some(rest) => return format!("+{}", rest),
+        None => return digits,
+    }
+}
+
+// E.164: a plus, a country code that doesn't start with 0, at most 15 digits.
+// Like emails, whether the number works is proven by the code sent to it.
+pub fn valid_phone_number(phone_number: &str) -> bool {
+
+    match phone_number.strip_prefix('+') {
+        Some(digits) => return (8..=15).contains(&digits.len()) && digits.chars().all(|c| c.is_ascii_digit()) && !digits.starts_with('0'),
+        None => return false,
+    }
 }
 
 impl User {
@@ -30,9 +120,13 @@ impl User {
     ) -> User {
 
         let uuid = Uuid::new_v4().to_string();
+        let user_email = canonical_email(&user_email);
         User {
             user_uuid: uuid.clone(),
             user_email: user_email.clone(),
+            username: None,
+            username_skeleton: None,
+            phone_number: None,
             user_state: UserState::NotActivated,
             last_login: DateTime::now(),
             user_claims: Claims{
@@ -40,12 +134,50 @@ impl User {
                 user_uuid: uuid.clone(),
                 user_name: user_email,
                 group_uuid: Vec::new(),
-            }
+            },
+            profile: UserProfile::default(),
+            schema_version: SCHEMA_VERSION,
         }
     }
 
-    pub fn login(&mut self) {
-        self.last_login = DateTime::now();
+    // user_claims carries the email as user_name, they have to change together.
+    pub fn set_email(&mut self, user_email: String) {
+        let user_email = canonical_email(&user_email);
+        self.user_claims.user_name = user_email.clone();
+        self.user_email = user_email;
+    }
+
+    pub fn set_username(&mut self, username: Username) {
+        self.username = Some(username.normalized);
+        self.username_skeleton = Some(username.skeleton);
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_email_folds_case_and_unicode_forms() {
+        assert_eq!(canonical_email("  Bob@Example.ORG "), "bob@example.org");
+        assert_eq!(canonical_email("ÉMILE@example.org"), "émile@example.org");
+        // fullwidth letters and ligatures only match after NFKC
+        assert_eq!(canonical_email("ｂｏｂ@example.org"), "bob@example.org");
+        assert_eq!(canonical_email("ﬁona@example.org"), "fiona@example.org");
+        // a decomposed é ends up as the precomposed one
+        assert_eq!(canonical_email("e\u{301}mile@example.org"), canonical_email("émile@example.org"));
+    }
+
+    #[test]
+    fn valid_email_checks_the_shape() {
+        assert!(valid_email("bob@example.org"));
+        assert!(!valid_email("bob"));
+        assert!(!valid_email("@example.org"));
+        assert!(!valid_email("bob@example"));
+        assert!(!valid_email("bob@.example.org"));
+        assert!(!valid_email("bob@example.org."));
+        assert!(!valid_email("bob smith@example.org"));
+        assert!(!valid_email(&format!("{}@example.org", "b".repeat(MAX_EMAIL_LEN))));
+    }
+}
@@ -0,0 +1,199 @@
+use std::fmt;
+use caseless::default_case_fold_str;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::{skeleton, RestrictionLevel, RestrictionLevelDetection};
+use crate::config::UsernameConfig;
+use crate::model::user::canonical_email;
+
+// Usernames are stored normalized: NFKC, case folded, NFKC again (the
+// NFKC_Casefold recipe from UAX #31), so "Bob", "BOB" and "ｂｏｂ" are the same
+// name. Next to it goes the UTS #39 skeleton, which maps lookalikes onto one
+// form ("paypal" with a Cyrillic "а", "rn" for "m"). The skeleton has its own
+// unique index, so a name that merely looks like a taken one is refused too.
+
+const SEPARATORS: [char; 3] = ['.', '_', '-'];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UsernameError {
+    TooShort(usize),
+    TooLong(usize),
+    InvalidCharacter(char),
+    MisplacedSeparator,
+    MixedScript,
+    Reserved,
+}
+
+impl fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsernameError::TooShort(min) => write!(f, "username must be at least {} characters", min),
+            UsernameError::TooLong(max) => write!(f, "username must be at most {} characters", max),
+            UsernameError::InvalidCharacter(c) => write!(f, "username can't contain {:?}", c),
+            UsernameError::MisplacedSeparator => write!(f, "username can't start or end with . _ - or repeat them"),
+            UsernameError::MixedScript => write!(f, "username mixes scripts or uses restricted characters"),
+            UsernameError::Reserved => write!(f, "username is reserved"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Username {
+    pub normalized: String,
+    pub skeleton: String,
+}
+
+pub fn normalize(raw: &str) -> String {
+
+    let compatible: String = raw.trim().nfkc().collect();
+
+    return default_case_fold_str(&compatible).nfkc().collect();
+}
+
+fn skeleton_of(normalized: &str) -> String {
+    return skeleton(normalized).collect();
+}
+
+impl Username {
+    pub fn parse (raw: &str, config: &UsernameConfig) -> Result<Username, UsernameError> {
+
+        let normalized = normalize(raw);
+        let length = normalized.chars().count();
+
+        if length < config.min_length {
+            return Err(UsernameError::TooShort(config.min_length));
+        }
+
+        if length > config.max_length {
+            return Err(UsernameError::TooLong(config.max_length));
+        }
+
+        if let Some(c) = normalized.chars().find(|c| !c.is_alphanumeric() && !SEPARATORS.contains(c)) {
+            return Err(UsernameError::InvalidCharacter(c));
+        }
+
+        let misplaced = normalized.starts_with(SEPARATORS)
+            || normalized.ends_with(SEPARATORS)
+            || normalized.chars().zip(normalized.chars().skip(1)).any(|(a, b)| SEPARATORS.contains(&a) && SEPARATORS.contains(&b));
+
+        if misplaced {
+            return Err(UsernameError::MisplacedSeparator);
+        }
+
+        // One script, or the usual CJK combinations with Latin. The separators
+        // are left out since they count as Common.
+        let letters: String = normalized.chars().filter(|c| !SEPARATORS.contains(c)).collect();
+
+        if !letters.as_str().check_restriction_level(RestrictionLevel::HighlyRestrictive) {
+            return Err(UsernameError::MixedScript);
+        }
+
+        let skeleton = skeleton_of(&normalized);
+
+        let reserved = config.reserved.iter().any(|name| {
+            let name = normalize(name);
+            return name == normalized || skeleton_of(&name) == skeleton;
+        });
+
+        if reserved {
+            return Err(UsernameError::Reserved);
+        }
+
+        return Ok(Username { normalized, skeleton });
+    }
+}
+
+// What someone typed into the login form, see Database::get_user_by_login_name.
+// Usernames can't have an '@', so anything with one is an email. Without one
+// it is a username first, then an email: accounts from before signup checked
+// the address may have one without an '@'.
+#[derive(Debug, PartialEq, Eq)]
+pub enum LoginName {
+    Email(String),
+    Username {
+        username: String,
+        email: String,
+    },
+}
+
+impl LoginName {
+    pub fn parse (raw: &str) -> LoginName {
+
+        if raw.contains('@') {
+            return LoginName::Email(canonical_email(raw));
+        }
+
+        return LoginName::Username { username: normalize(raw), email: canonical_email(raw) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Username, UsernameError> {
+        return Username::parse(raw, &UsernameConfig::default());
+    }
+
+    #[test]
+    fn names_are_normalized_and_case_folded() {
+        assert_eq!(parse("Bob").unwrap().normalized, "bob");
+        assert_eq!(parse(" ＢＯＢ ").unwrap().normalized, "bob");
+        assert_eq!(parse("Straße").unwrap().normalized, "strasse");
+        assert_eq!(parse("Bob").unwrap(), parse("bOB").unwrap());
+    }
+
+    #[test]
+    fn reserved_names_are_refused_in_any_form() {
+        assert_eq!(parse("Admin"), Err(UsernameError::Reserved));
+        assert_eq!(parse("ａｄｍｉｎ"), Err(UsernameError::Reserved));
+        // "rn" looks like "m"
+        assert_eq!(parse("adrnin"), Err(UsernameError::Reserved));
+        assert!(parse("admins").is_ok());
+    }
+
+    #[test]
+    fn lookalikes_share_a_skeleton() {
+        assert_eq!(parse("modern").unwrap().skeleton, parse("rnodern").unwrap().skeleton);
+        assert_ne!(parse("modern").unwrap().normalized, parse("rnodern").unwrap().normalized);
+    }
+
+    #[test]
+    fn mixed_scripts_are_refused() {
+        // Cyrillic "а" in an otherwise Latin name
+        assert_eq!(parse("p\u{430}ypal"), Err(UsernameError::MixedScript));
+        assert_eq!(parse("bob\u{3b1}"), Err(UsernameError::MixedScript));
+        // Han with Latin is a usual combination
+        assert!(parse("山田taro").is_ok());
+        assert!(parse("Σωκράτης").is_ok());
+    }
+
+    #[test]
+    fn separators_go_between_characters() {
+        assert!(parse("bob.smith_jr-2").is_ok());
+        assert_eq!(parse(".bob"), Err(UsernameError::MisplacedSeparator));
+        assert_eq!(parse("bob-"), Err(UsernameError::MisplacedSeparator));
+        assert_eq!(parse("bob..smith"), Err(UsernameError::MisplacedSeparator));
+        assert_eq!(parse("bob._smith"), Err(UsernameError::MisplacedSeparator));
+        assert_eq!(parse("bob smith"), Err(UsernameError::InvalidCharacter(' ')));
+        assert_eq!(parse("bob@example.org"), Err(UsernameError::InvalidCharacter('@')));
+    }
+
+    #[test]
+    fn length_is_counted_in_characters_after_normalization() {
+        assert_eq!(parse("bo"), Err(UsernameError::TooShort(3)));
+        assert_eq!(parse("  bo  "), Err(UsernameError::TooShort(3)));
+        assert!(parse("bob").is_ok());
+        assert!(parse(&"b".repeat(32)).is_ok());
+        assert_eq!(parse(&"b".repeat(33)), Err(UsernameError::TooLong(32)));
+        assert!(parse(&"é".repeat(32)).is_ok());
+    }
+
+    #[test]
+    fn login_names_with_an_at_are_emails() {
+        assert_eq!(LoginName::parse(" Bob@Example.org "), LoginName::Email("bob@example.org".to_owned()));
+        assert_eq!(
+            LoginName::parse("ＢＯＢ"),
+            LoginName::Username { username: "bob".to_owned(), email: "bob".to_owned() },
+        );
+    }
+}
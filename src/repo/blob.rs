@@ -0,0 +1,113 @@
+use crate::config::ProfileConfig;
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use async_trait::async_trait;
+
+// Storage for binary blobs such as avatars, kept out of the user database.
+// Keys are generated by the service, never taken from a request.
+
+#[derive(Debug)]
+pub enum BlobError {
+    InvalidKey,
+    Io(io::Error),
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlobError::InvalidKey => write!(f, "invalid blob key"),
+            BlobError::Io(error) => write!(f, "blob store failure: {}", error),
+        }
+    }
+}
+
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+
+    async fn put(
+        &self,
+        key: &str,
+        data: Vec<u8>
+    ) -> Result<(), BlobError>;
+
+    async fn get(
+        &self,
+        key: &str
+    ) -> Result<Option<Vec<u8>>, BlobError>;
+
+    // Deleting a missing key is not an error.
+    async fn delete(
+        &self,
+        key: &str
+    ) -> Result<(), BlobError>;
+
+}
+
+// One file per key under a directory.
+pub struct LocalDiskStore {
+    root: PathBuf,
+}
+
+impl LocalDiskStore {
+
+    pub fn new(root: PathBuf) -> LocalDiskStore {
+        return LocalDiskStore { root };
+    }
+
+    // Keys are flat names, anything that could step outside root is refused.
+    fn path(&self, key: &str) -> Result<PathBuf, BlobError> {
+
+        let valid = !key.is_empty()
+            && !key.starts_with('.')
+            && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+
+        if !valid {
+            return Err(BlobError::InvalidKey);
+        }
+
+        return Ok(self.root.join(key));
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalDiskStore {
+
+    // Written to a temporary file first so readers never see half an image.
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), BlobError> {
+
+        let path = self.path(key)?;
+        let partial = self.root.join(format!(".{}.partial", key));
+
+        tokio::fs::create_dir_all(&self.root).await.map_err(BlobError::Io)?;
+        tokio::fs::write(&partial, data).await.map_err(BlobError::Io)?;
+        tokio::fs::rename(&partial, &path).await.map_err(BlobError::Io)?;
+
+        return Ok(());
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, BlobError> {
+
+        match tokio::fs::read(self.path(key)?).await {
+            Ok(data) => return Ok(Some(data)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(BlobError::Io(error)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), BlobError> {
+
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(BlobError::Io(error)),
+            _ => return Ok(()),
+        }
+    }
+
+}
+
+// Only local disk for now, other stores plug in here.
+pub fn open(config: &ProfileConfig) -> Arc<dyn BlobStore> {
+    return Arc::new(LocalDiskStore::new(PathBuf::from(&config.avatar_dir)));
+}
@@ -0,0 +1,679 @@
+use crate::model::user::{canonical_phone_number, valid_phone_number, User, UserState};
+use crate::model::username::Username;
+use crate::config::{MfaConfig, UsernameConfig};
+use crate::model::claims::ClaimsUserType;
+use crate::model::credentail::{UserCredentail, UserMfaState};
+use crate::model::mfa_factor::{valid_label, MfaFactor};
+use crate::model::totp::{TotpAlgorithm, TotpSettings};
+use crate::repo::database::base::{Database, DatabaseError};
+use crate::secrets::{SecretError, SecretKeys};
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use bson::DateTime;
+use serde::{Serialize, Deserialize};
+use strum_macros::{EnumString, Display};
+
+// Moving users in and out in bulk, one record per user with its credentail
+// flattened in. Both directions stream: import handles a row at a time and
+// export pages through Database::list_users, so file size doesn't matter.
+//
+// Exports contain password hashes and MFA secrets, treat the files as such.
+
+const EXPORT_PAGE_SIZE: u64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum BulkFormat {
+    Jsonl,
+    Csv,
+}
+
+#[derive(Debug)]
+pub enum BulkError {
+    Read(io::Error),
+    Write(io::Error),
+    Database(DatabaseError),
+    // an MFA secret that can't be decrypted with [secrets], ends the export
+    Secret { user_uuid: String, error: SecretError },
+    // more MFA factors than a CSV row holds, ends the export
+    TooManyFactors { user_uuid: String, factors: usize },
+}
+
+impl fmt::Display for BulkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BulkError::Read(error) => write!(f, "could not read input: {}", error),
+            BulkError::Write(error) => write!(f, "could not write output: {}", error),
+            BulkError::Database(error) => write!(f, "database failure: {:?}", error),
+            BulkError::Secret { user_uuid, error } => write!(f, "user {}: {}", user_uuid, error),
+            BulkError::TooManyFactors { user_uuid, factors } => write!(f, "user {} has {} MFA factors, CSV holds one, export as jsonl", user_uuid, factors),
+        }
+    }
+}
+
+impl From<DatabaseError> for BulkError {
+    fn from(error: DatabaseError) -> BulkError {
+        return BulkError::Database(error);
+    }
+}
+
+// Only user_email and password_hash are required on import. password_hash
+// can be bcrypt or one of the legacy formats in model::password. Emails and
+// usernames are normalized on the way in, exports carry the stored forms.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct UserRecord {
+    #[serde(default)]
+    pub user_uuid: Option<String>,
+    pub user_email: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    // taken as verified, E.164
+    #[serde(default)]
+    pub phone_number: Option<String>,
+    #[serde(default)]
+    pub user_state: Option<UserState>,
+    #[serde(default)]
+    pub user_type: Option<ClaimsUserType>,
+    #[serde(default)]
+    pub group_uuid: Vec<String>,
+    // rfc3339
+    #[serde(default)]
+    pub last_login: Option<String>,
+    pub password_hash: String,
+    // the preferred factor, the others are in mfa_factors
+    #[serde(default)]
+    pub mfa_state: Option<UserMfaState>,
+    // base32, with the authenticator defaults (SHA1, 6, 30) for any of the
+    // totp_ settings left out
+    #[serde(default)]
+    pub mfa_secret: Option<String>,
+    #[serde(default)]
+    pub totp_algorithm: Option<TotpAlgorithm>,
+    #[serde(default)]
+    pub totp_digits: Option<usize>,
+    #[serde(default)]
+    pub totp_period: Option<u64>,
+    // JSONL only, CSV exports refuse users that have any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mfa_factors: Vec<FactorRecord>,
+}
+
+// A factor besides the preferred one, the same fields as on UserRecord.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FactorRecord {
+    pub mfa_state: UserMfaState,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub mfa_secret: Option<String>,
+    #[serde(default)]
+    pub totp_algorithm: Option<TotpAlgorithm>,
+    #[serde(default)]
+    pub totp_digits: Option<usize>,
+    #[serde(default)]
+    pub totp_period: Option<u64>,
+}
+
+impl FactorRecord {
+    fn from_factor (factor: &MfaFactor, user_uuid: &str, mfa_config: &MfaConfig, keys: &SecretKeys) -> Result<FactorRecord, BulkError> {
+
+        let totp = factor.totp_export(keys, user_uuid, mfa_config)
+            .map_err(|error| BulkError::Secret { user_uuid: user_uuid.to_owned(), error })?;
+
+        return Ok(FactorRecord {
+            mfa_state: factor.factor_type.clone(),
+            label: Some(factor.label.clone()),
+            mfa_secret: totp.as_ref().map(|(secret, _)| secret.clone()),
+            totp_algorithm: totp.as_ref().map(|(_, settings)| settings.algorithm),
+            totp_digits: totp.as_ref().map(|(_, settings)| settings.digits),
+            totp_period: totp.as_ref().map(|(_, settings)| settings.period),
+        });
+    }
+
+    fn add_to (self, credentail: &mut UserCredentail, user: &User, keys: &SecretKeys) -> Result<(), String> {
+
+        let label = match self.label.filter(|label| !label.is_empty()) {
+            Some(label) => Some(valid_label(&label).ok_or("label must be 1 to 64 characters without control characters")?),
+            None => None,
+        };
+
+        match (self.mfa_state, self.mfa_secret.filter(|secret| !secret.is_empty())) {
+            (UserMfaState::None, _) => {},
+            (UserMfaState::OTP, Some(secret)) => {
+                let defaults = TotpSettings::default();
+
+                let settings = TotpSettings {
+                    algorithm: self.totp_algorithm.unwrap_or(defaults.algorithm),
+                    digits: self.totp_digits.unwrap_or(defaults.digits),
+                    period: self.totp_period.unwrap_or(defaults.period),
+                };
+
+                if !(6..=8).contains(&settings.digits) || settings.period == 0 {
+                    return Err("totp_digits must be 6 to 8 and totp_period positive".to_owned());
+                }
+
+                credentail.import_totp(&secret, settings, label, keys)
+                    .map_err(|_| "mfa_secret is not a base32 secret of at least 128 bits".to_owned())?;
+            },
+            (UserMfaState::OTP, None) => return Err("mfa_state OTP needs an mfa_secret".to_owned()),
+            (UserMfaState::SMS, _) if user.phone_number.is_none() => return Err("mfa_state SMS needs a phone_number".to_owned()),
+            // codes go to user_email or phone_number, nothing to carry over
+            (mfa_state, _) => {
+                credentail.add_mfa(mfa_state.clone(), label)
+                    .map_err(|_| format!("mfa_state {} could not be added, Email and SMS can only be there once", mfa_state))?;
+            },
+        }
+
+        return Ok(());
+    }
+}
+
+// CSV can't hold a list in a column, so groups are joined with ';'.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CsvUserRecord {
+    user_uuid: Option<String>,
+    user_email: String,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    phone_number: Option<String>,
+    user_state: Option<UserState>,
+    user_type: Option<ClaimsUserType>,
+    #[serde(default)]
+    group_uuid: String,
+    last_login: Option<String>,
+    password_hash: String,
+    mfa_state: Option<UserMfaState>,
+    mfa_secret: Option<String>,
+    #[serde(default)]
+    totp_algorithm: Option<TotpAlgorithm>,
+    #[serde(default)]
+    totp_digits: Option<usize>,
+    #[serde(default)]
+    totp_period: Option<u64>,
+}
+
+impl From<CsvUserRecord> for UserRecord {
+    fn from(record: CsvUserRecord) -> UserRecord {
+        return UserRecord {
+            user_uuid: record.user_uuid,
+            user_email: record.user_email,
+            username: record.username,
+            phone_number: record.phone_number,
+            user_state: record.user_state,
+            user_type: record.user_type,
+            group_uuid: record.group_uuid.split(';').filter(|group| !group.is_empty()).map(str::to_owned).collect(),
+            last_login: record.last_login,
+            password_hash: record.password_hash,
+            mfa_state: record.mfa_state,
+            mfa_secret: record.mfa_secret,
+            totp_algorithm: record.totp_algorithm,
+            totp_digits: record.totp_digits,
+            totp_period: record.totp_period,
+            mfa_factors: Vec::new(),
+        };
+    }
+}
+
+// mfa_factors has no column, export_users doesn't get here with any.
+impl From<UserRecord> for CsvUserRecord {
+    fn from(record: UserRecord) -> CsvUserRecord {
+        return CsvUserRecord {
+            user_uuid: record.user_uuid,
+            user_email: record.user_email,
+            username: record.username,
+            phone_number: record.phone_number,
+            user_state: record.user_state,
+            user_type: record.user_type,
+            group_uuid: record.group_uuid.join(";"),
+            last_login: record.last_login,
+            password_hash: record.password_hash,
+            mfa_state: record.mfa_state,
+            mfa_secret: record.mfa_secret,
+            totp_algorithm: record.totp_algorithm,
+            totp_digits: record.totp_digits,
+            totp_period: record.totp_period,
+        };
+    }
+}
+
+impl UserRecord {
+    pub fn from_user (user: User, credentail: UserCredentail, mfa_config: &MfaConfig, keys: &SecretKeys) -> Result<UserRecord, BulkError> {
+
+        let preferred = credentail.preferred();
+
+        let totp = match preferred {
+            Some(factor) => factor.totp_export(keys, &credentail.user_uuid, mfa_config)
+                .map_err(|error| BulkError::Secret { user_uuid: user.user_uuid.clone(), error })?,
+            None => None,
+        };
+        let mfa_state = preferred.map(|factor| factor.factor_type.clone()).unwrap_or(UserMfaState::None);
+
+        let mfa_factors = credentail.mfa_factors.iter()
+            .filter(|factor| Some(factor.factor_id.as_str()) != preferred.map(|preferred| preferred.factor_id.as_str()))
+            .map(|factor| FactorRecord::from_factor(factor, &credentail.user_uuid, mfa_config, keys))
+            .collect::<Result<Vec<FactorRecord>, BulkError>>()?;
+
+        return Ok(UserRecord {
+            user_uuid: Some(user.user_uuid),
+            user_email: user.user_email,
+            username: user.username,
+            phone_number: user.phone_number,
+            user_state: Some(user.user_state),
+            user_type: Some(user.user_claims.user_type),
+            group_uuid: user.user_claims.group_uuid,
+            last_login: user.last_login.try_to_rfc3339_string().ok(),
+            password_hash: credentail.password_hash().to_owned(),
+            mfa_state: Some(mfa_state),
+            mfa_secret: totp.as_ref().map(|(secret, _)| secret.clone()),
+            totp_algorithm: totp.as_ref().map(|(_, settings)| settings.algorithm),
+            totp_digits: totp.as_ref().map(|(_, settings)| settings.digits),
+            totp_period: totp.as_ref().map(|(_, settings)| settings.period),
+            mfa_factors,
+        });
+    }
+
+    // Imported users are Active unless the file says otherwise, they already
+    // went through activation in the old system.
+    pub fn into_user (self, usernames: &UsernameConfig, keys: &SecretKeys) -> Result<(User, UserCredentail), String> {
+
+        let user_email = self.user_email.trim().to_owned();
+
+        if user_email.is_empty() {
+            return Err("user_email is empty".to_owned());
+        }
+
+        let mut user = User::new(user_email);
+
+        if let Some(username) = self.username.filter(|username| !username.is_empty()) {
+            user.set_username(Username::parse(&username, usernames).map_err(|error| error.to_string())?);
+        }
+
+        if let Some(phone_number) = self.phone_number.filter(|phone_number| !phone_number.is_empty()) {
+            let phone_number = canonical_phone_number(&phone_number);
+
+            if !valid_phone_number(&phone_number) {
+                return Err(format!("phone_number {:?} is not an E.164 number", phone_number));
+            }
+
+            user.phone_number = Some(phone_number);
+        }
+
+        if let Some(user_uuid) = self.user_uuid.filter(|user_uuid| !user_uuid.is_empty()) {
+            user.user_uuid = user_uuid.clone();
+            user.user_claims.user_uuid = user_uuid;
+        }
+
+        user.user_state = self.user_state.unwrap_or(UserState::Active);
+        user.user_claims.user_type = self.user_type.unwrap_or(ClaimsUserType::User);
+        user.user_claims.group_uuid = self.group_uuid;
+
+        if let Some(last_login) = self.last_login.filter(|last_login| !last_login.is_empty()) {
+            user.last_login = DateTime::parse_rfc3339_str(&last_login)
+                .map_err(|_| format!("last_login {:?} is not an rfc3339 date", last_login))?;
+        }
+
+        let mut credentail = UserCredentail::from_password_hash(user.user_uuid.clone(), self.password_hash)
+            .map_err(|error| error.to_string())?;
+
+        // the preferred factor goes first, the first one added is preferred
+        let preferred = FactorRecord {
+            mfa_state: self.mfa_state.unwrap_or(UserMfaState::None),
+            label: None,
+            mfa_secret: self.mfa_secret,
+            totp_algorithm: self.totp_algorithm,
+            totp_digits: self.totp_digits,
+            totp_period: self.totp_period,
+        };
+
+        for factor in std::iter::once(preferred).chain(self.mfa_factors) {
+            factor.add_to(&mut credentail, &user, keys)?;
+        }
+
+        return Ok((user, credentail));
+    }
+}
+
+// A row that couldn't be parsed is reported and skipped, a failing reader
+// ends the import.
+pub enum RowError {
+    Invalid(String),
+    Read(io::Error),
+}
+
+pub type Rows<'a> = Box<dyn Iterator<Item = (u64, Result<UserRecord, RowError>)> + 'a>;
+
+// Rows are numbered from 1, for CSV the header isn't counted.
+pub fn read_records<'a, R: Read + 'a>(reader: R, format: BulkFormat) -> Rows<'a> {
+
+    match format {
+        BulkFormat::Jsonl => {
+            let rows = BufReader::new(reader).lines()
+                .zip(1..)
+                .filter(|(line, _)| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .map(|(line, row)| {
+                    let record = match line {
+                        Ok(line) => serde_json::from_str::<UserRecord>(&line).map_err(|error| RowError::Invalid(error.to_string())),
+                        Err(error) => Err(RowError::Read(error)),
+                    };
+                    return (row, record);
+                });
+
+            return Box::new(rows);
+        },
+        BulkFormat::Csv => {
+            let rows = csv::Reader::from_reader(reader)
+                .into_deserialize::<CsvUserRecord>()
+                .zip(1..)
+                .map(|(record, row)| {
+                    let record = match record {
+                        Ok(record) => Ok(UserRecord::from(record)),
+                        Err(error) => {
+                            let message = error.to_string();
+                            match error.into_kind() {
+                                csv::ErrorKind::Io(error) => Err(RowError::Read(error)),
+                                _ => Err(RowError::Invalid(message)),
+                            }
+                        },
+                    };
+                    return (row, record);
+                });
+
+            return Box::new(rows);
+        },
+    }
+}
+
+pub enum RecordWriter<W: Write> {
+    Jsonl(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub fn new (writer: W, format: BulkFormat) -> RecordWriter<W> {
+        match format {
+            BulkFormat::Jsonl => return RecordWriter::Jsonl(writer),
+            BulkFormat::Csv => return RecordWriter::Csv(Box::new(csv::Writer::from_writer(writer))),
+        }
+    }
+
+    pub fn write (&mut self, record: UserRecord) -> io::Result<()> {
+        match self {
+            RecordWriter::Jsonl(writer) => {
+                serde_json::to_writer(&mut *writer, &record)?;
+                return writer.write_all(b"\n");
+            },
+            RecordWriter::Csv(writer) => {
+                return writer.serialize(CsvUserRecord::from(record)).map_err(io::Error::other);
+            },
+        }
+    }
+
+    pub fn flush (&mut self) -> io::Result<()> {
+        match self {
+            RecordWriter::Jsonl(writer) => return writer.flush(),
+            RecordWriter::Csv(writer) => return writer.flush(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub imported: u64,
+    pub failed: u64,
+}
+
+fn insert_error_message(error: DatabaseError) -> String {
+    match error {
+        DatabaseError::UserNameExists => return "user_email already exists".to_owned(),
+        DatabaseError::UsernameTaken => return "username or a lookalike already exists".to_owned(),
+        DatabaseError::PhoneNumberTaken => return "phone_number already exists".to_owned(),
+        DatabaseError::UserUuidExists => return "user_uuid already exists".to_owned(),
+        error => return format!("database failure: {:?}", error),
+    }
+}
+
+// Checks a row against what's already stored without writing anything. The
+// seen sets catch duplicates within the file, which the database would
+// otherwise reject on a real run.
+async fn check_row(
+    database: &dyn Database,
+    user: &User,
+    seen_emails: &mut HashSet<String>,
+    seen_usernames: &mut HashSet<String>,
+    seen_phone_numbers: &mut HashSet<String>,
+    seen_uuids: &mut HashSet<String>,
+) -> Result<(), String> {
+
+    if !seen_emails.insert(user.user_email.clone()) {
+        return Err("user_email appears earlier in the file".to_owned());
+    }
+
+    if let Some(skeleton) = &user.username_skeleton {
+        if !seen_usernames.insert(skeleton.clone()) {
+            return Err("username or a lookalike appears earlier in the file".to_owned());
+        }
+    }
+
+    if let Some(phone_number) = &user.phone_number {
+        if !seen_phone_numbers.insert(phone_number.clone()) {
+            return Err("phone_number appears earlier in the file".to_owned());
+        }
+    }
+
+    if !seen_uuids.insert(user.user_uuid.clone()) {
+        return Err("user_uuid appears earlier in the file".to_owned());
+    }
+
+    if database.get_user_by_email(user.user_email.clone()).await.map_err(insert_error_message)?.is_some() {
+        return Err(insert_error_message(DatabaseError::UserNameExists));
+    }
+
+    // only an exact clash can be checked here, lookalikes show up on a real run
+    if let Some(username) = &user.username {
+        if database.get_user_by_username(username.clone()).await.map_err(insert_error_message)?.is_some() {
+            return Err(insert_error_message(DatabaseError::UsernameTaken));
+        }
+    }
+
+    if let Some(phone_number) = &user.phone_number {
+        if database.get_user_by_phone_number(phone_number.clone()).await.map_err(insert_error_message)?.is_some() {
+            return Err(insert_error_message(DatabaseError::PhoneNumberTaken));
+        }
+    }
+
+    if database.get_user(user.user_uuid.clone()).await.map_err(insert_error_message)?.is_some() {
+        return Err(insert_error_message(DatabaseError::UserUuidExists));
+    }
+
+    return Ok(());
+}
+
+// Each row is inserted on its own (user and credentail together), a bad row
+// is passed to on_error and the import carries on with the next one.
+pub async fn import_users(
+    database: &dyn Database,
+    rows: Rows<'_>,
+    usernames: &UsernameConfig,
+    keys: &SecretKeys,
+    dry_run: bool,
+    mut on_error: impl FnMut(u64, &str),
+) -> Result<ImportReport, BulkError> {
+
+    let mut report = ImportReport::default();
+    let mut seen_emails = HashSet::new();
+    let mut seen_usernames = HashSet::new();
+    let mut seen_phone_numbers = HashSet::new();
+    let mut seen_uuids = HashSet::new();
+
+    for (row, record) in rows {
+
+        let result = match record {
+            Ok(record) => record.into_user(usernames, keys),
+            Err(RowError::Invalid(message)) => Err(message),
+            Err(RowError::Read(error)) => return Err(BulkError::Read(error)),
+        };
+
+        let result = match result {
+            Ok((user, _)) if dry_run => check_row(database, &user, &mut seen_emails, &mut seen_usernames, &mut seen_phone_numbers, &mut seen_uuids).await,
+            Ok((user, credentail)) => database.insert_user_with_credentail(user, credentail).await
+                .map(|_| ())
+                .map_err(insert_error_message),
+            Err(message) => Err(message),
+        };
+
+        match result {
+            Ok(_) => report.imported += 1,
+            Err(message) => {
+                report.failed += 1;
+                on_error(row, &message);
+            },
+        }
+    }
+
+    return Ok(report);
+}
+
+#[derive(Debug, Default)]
+pub struct ExportReport {
+    pub exported: u64,
+    pub skipped: u64,
+}
+
+// Users without a credentail can't be imported again, so they're left out
+// and counted in skipped.
+pub async fn export_users<W: Write>(
+    database: &dyn Database,
+    writer: &mut RecordWriter<W>,
+    mfa_config: &MfaConfig,
+    keys: &SecretKeys,
+) -> Result<ExportReport, BulkError> {
+
+    let mut report = ExportReport::default();
+    let mut skip = 0;
+
+    loop {
+        let users = database.list_users(skip, EXPORT_PAGE_SIZE).await?;
+        let page_len = users.len() as u64;
+
+        for user in users {
+            match database.get_credentail(user.user_uuid.clone()).await? {
+                Some(credentail) => {
+                    let record = UserRecord::from_user(user, credentail, mfa_config, keys)?;
+
+                    // leaving factors out would lock users out of them after
+                    // an import, so CSV stops instead
+                    if matches!(writer, RecordWriter::Csv(_)) && !record.mfa_factors.is_empty() {
+                        return Err(BulkError::TooManyFactors {
+                            user_uuid: record.user_uuid.unwrap_or_default(),
+                            factors: record.mfa_factors.len() + 1,
+                        });
+                    }
+
+                    writer.write(record).map_err(BulkError::Write)?;
+                    report.exported += 1;
+                },
+                None => {
+                    tracing::warn!(user_uuid = %user.user_uuid, "user has no credentail, not exported");
+                    report.skipped += 1;
+                },
+            }
+        }
+
+        if page_len < EXPORT_PAGE_SIZE {
+            break;
+        }
+
+        skip += page_len;
+    }
+
+    writer.flush().map_err(BulkError::Write)?;
+
+    return Ok(report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SecretsConfig;
+    use crate::model::totp;
+    use crate::repo::database::sql::SqlRepo;
+
+    fn keys() -> SecretKeys {
+        return SecretKeys::from_config(&SecretsConfig::default());
+    }
+
+    // a user with Email preferred, an SMS factor and a labelled app
+    async fn repo_with_factors() -> (SqlRepo, String) {
+        let path = std::env::temp_dir().join(format!("userauth-{}.db", uuid::Uuid::new_v4()));
+        let repo = SqlRepo::init(format!("sqlite://{}?mode=rwc", path.display()), String::new()).await.unwrap();
+        repo.migrate().await.unwrap();
+
+        let mut user = User::new("bob@example.org".to_owned());
+        user.phone_number = Some("+15550109999".to_owned());
+        let mut credentail = UserCredentail::new(user.clone(), "correct horse".to_owned(), 4);
+        credentail.add_mfa(UserMfaState::Email, None).ok().unwrap();
+        credentail.add_mfa(UserMfaState::SMS, None).ok().unwrap();
+        credentail.import_totp(&totp::encode_secret(b"12345678901234567890"), TotpSettings::default(), Some("Phone".to_owned()), &keys()).ok().unwrap();
+        repo.insert_user_with_credentail(user.clone(), credentail).await.unwrap();
+
+        return (repo, user.user_uuid);
+    }
+
+    #[actix_web::test]
+    async fn jsonl_exports_every_factor() {
+        let (repo, _) = repo_with_factors().await;
+
+        let mut writer = RecordWriter::new(Vec::new(), BulkFormat::Jsonl);
+        let report = export_users(&repo, &mut writer, &MfaConfig::default(), &keys()).await.unwrap();
+        assert_eq!(report.exported, 1);
+
+        let RecordWriter::Jsonl(output) = writer else { unreachable!() };
+        let (_, record) = read_records(output.as_slice(), BulkFormat::Jsonl).next().unwrap();
+        let Ok(record) = record else { panic!("export didn't read back") };
+
+        assert_eq!(record.mfa_state, Some(UserMfaState::Email));
+        assert_eq!(record.mfa_factors.len(), 2);
+
+        let (_, credentail) = record.into_user(&UsernameConfig::default(), &keys()).unwrap();
+        let factors: Vec<(UserMfaState, String)> = credentail.mfa_factors.iter()
+            .map(|factor| (factor.factor_type.clone(), factor.label.clone()))
+            .collect();
+
+        assert_eq!(factors, vec![
+            (UserMfaState::Email, "Email".to_owned()),
+            (UserMfaState::SMS, "SMS".to_owned()),
+            (UserMfaState::OTP, "Phone".to_owned()),
+        ]);
+        assert_eq!(credentail.preferred().unwrap().factor_type, UserMfaState::Email);
+        let (secret, _) = credentail.mfa_factors[2].totp_export(&keys(), &credentail.user_uuid, &MfaConfig::default()).unwrap().unwrap();
+        assert_eq!(secret, totp::encode_secret(b"12345678901234567890"));
+    }
+
+    #[actix_web::test]
+    async fn csv_refuses_users_with_several_factors() {
+        let (repo, user_uuid) = repo_with_factors().await;
+
+        let mut writer = RecordWriter::new(Vec::new(), BulkFormat::Csv);
+        let error = export_users(&repo, &mut writer, &MfaConfig::default(), &keys()).await.unwrap_err();
+
+        assert!(matches!(error, BulkError::TooManyFactors { user_uuid: ref failed, factors: 3 } if *failed == user_uuid));
+    }
+
+    #[test]
+    fn a_second_email_factor_is_refused_on_import() {
+        let record: UserRecord = serde_json::from_str(r#"{
+            "user_email": "bob@example.org",
+            "password_hash": "sha1$seasalt$55eef163bf2e349b9946e3183eef620dde0247f5",
+            "mfa_state": "Email",
+            "mfa_factors": [{"mfa_state": "Email"}]
+        }"#).unwrap();
+
+        assert!(record.into_user(&UsernameConfig::default(), &keys()).is_err());
+    }
+}
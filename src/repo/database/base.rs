@@ -1,34 +1,119 @@
-use crate::model::{user::User, credentail::UserCredentail};
+use crate::model::{user::User, username::LoginName, credentail::{CodeSlot, SecretSlot, UserCredentail}};
+use crate::secrets::StoredSecret;
+use std::error::Error;
+use bson::DateTime;
 use strum_macros::Display;
+use async_trait::async_trait;
 
+// Transient failures (lost connection, no reachable server, pool exhausted)
+// are expected to clear up on retry, permanent ones need someone to look.
+#[derive(Display, Eq, PartialEq, Debug, Clone, Copy)]
+pub enum FailureKind {
+    Transient,
+    Permanent,
+}
 
-#[derive(Display, Eq, PartialEq, Debug)]
+#[derive(Display, Debug)]
 pub enum DatabaseError {
+    // user_email is taken, the name predates usernames
     UserNameExists,
+    UsernameTaken,
+    PhoneNumberTaken,
     UserUuidExists,
     UserDoesntExist,
-    DBFailure,
+    DBFailure {
+        kind: FailureKind,
+        source: Box<dyn Error + Send + Sync>,
+    },
+}
+
+impl DatabaseError {
+    pub fn failure(kind: FailureKind, source: impl Into<Box<dyn Error + Send + Sync>>) -> DatabaseError {
+        return DatabaseError::DBFailure { kind, source: source.into() };
+    }
+
+    pub fn is_transient(&self) -> bool {
+        return matches!(self, DatabaseError::DBFailure { kind: FailureKind::Transient, .. });
+    }
+}
+
+impl Error for DatabaseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DatabaseError::DBFailure { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
 }
-pub trait Database {
+
+// Handlers hold this as Data<dyn Database>, so any backend (or a test double)
+// can be plugged in from main.rs without touching them.
+#[async_trait]
+pub trait Database: Send + Sync {
     async fn init (
         connection_url: String,
         database: String
-    ) -> Self;
+    ) -> Result<Self, DatabaseError> where Self: Sized;
+
+    // Cheap round trip to the server, used by the readiness probe.
+    async fn health_check(
+        &self
+    ) -> Result<(), DatabaseError>;
+
+    // Brings the stored layout up to date, returning the migrations that were
+    // applied by this call. Safe to call on every start.
+    async fn migrate(
+        &self
+    ) -> Result<Vec<String>, DatabaseError>;
 
     async fn get_user(
         &self, 
         user_uudi: String
-    ) -> Option<User>;
+    ) -> Result<Option<User>, DatabaseError>;
+
+    // Users with any of the uuids in one round trip, in no particular order.
+    // Uuids without a user are left out.
+    async fn get_users(
+        &self, 
+        user_uuids: Vec<String>
+    ) -> Result<Vec<User>, DatabaseError>;
 
     async fn get_credentail(
         &self, 
         user_uudi: String
-    ) -> Option<UserCredentail>;
+    ) -> Result<Option<UserCredentail>, DatabaseError>;
 
-    async fn get_user_by_user_name(
+    // Exact match, callers pass user::canonical_email.
+    async fn get_user_by_email(
         &self, 
-        user_name: String
-    ) -> Option<User>;
+        user_email: String
+    ) -> Result<Option<User>, DatabaseError>;
+
+    // Exact match on the normalized form from username::normalize.
+    async fn get_user_by_username(
+        &self, 
+        username: String
+    ) -> Result<Option<User>, DatabaseError>;
+
+    // The user someone means by what they typed into a login form.
+    async fn get_user_by_login_name(
+        &self,
+        login_name: LoginName
+    ) -> Result<Option<User>, DatabaseError> {
+        match login_name {
+            LoginName::Email(user_email) => return self.get_user_by_email(user_email).await,
+            LoginName::Username { username, email } => match self.get_user_by_username(username).await? {
+                Some(user) => return Ok(Some(user)),
+                None => return self.get_user_by_email(email).await,
+            },
+        }
+    }
+
+    // Exact match on a verified E.164 number from user::canonical_phone_number.
+    async fn get_user_by_phone_number(
+        &self, 
+        phone_number: String
+    ) -> Result<Option<User>, DatabaseError>;
 
     async fn insert_user(
         &self, 
@@ -40,6 +125,15 @@ pub trait Database {
         credentail: UserCredentail
     ) -> Result<bool, DatabaseError>;
 
+    // Inserts both documents or neither, so a failed signup can't leave a
+    // user behind without a credentail.
+    async fn insert_user_with_credentail(
+        &self, 
+        user: User,
+        credentail: UserCredentail
+    ) -> Result<User, DatabaseError>;
+
+    // Removes the user together with their credentail.
     async fn delete_user(
         &self, 
         user: User
@@ -49,5 +143,96 @@ pub trait Database {
         &self, 
         user: User
     ) -> Result<User, DatabaseError>;
+
+    // Just last_login, so a login doesn't write back a user it read earlier
+    // over changes made since.
+    async fn set_last_login(
+        &self,
+        user_uuid: String,
+        last_login: DateTime
+    ) -> Result<(), DatabaseError>;
+
+    // Both writes or neither, e.g. an email change and the credentail state
+    // that tracks it.
+    async fn update_user_with_credentail(
+        &self, 
+        user: User,
+        credentail: UserCredentail
+    ) -> Result<User, DatabaseError>;
+
+    async fn update_credentail(
+        &self, 
+        credentail: UserCredentail
+    ) -> Result<UserCredentail, DatabaseError>;
+
+    // Counts one attempt against the code in slot as a single conditional
+    // write, so parallel guesses can't share a count. False when
+    // max_attempts are used up or the code there isn't the one issued at
+    // issued_at any more. The code is only checked after this said yes.
+    async fn take_code_attempt(
+        &self,
+        user_uuid: String,
+        slot: CodeSlot,
+        issued_at: DateTime,
+        max_attempts: u32
+    ) -> Result<bool, DatabaseError>;
+
+    // Removes the code in slot, with whatever it protects, if it is still
+    // the one issued at issued_at. Of several requests holding the right
+    // code only one gets true.
+    async fn spend_code(
+        &self,
+        user_uuid: String,
+        slot: CodeSlot,
+        issued_at: DateTime
+    ) -> Result<bool, DatabaseError>;
+
+    // Counts a /reauth try as a single conditional write, starting a new
+    // window when the stored one began before window_start. False once
+    // max_attempts are used up in the current window.
+    async fn take_reauth_attempt(
+        &self,
+        user_uuid: String,
+        window_start: DateTime,
+        max_attempts: u32
+    ) -> Result<bool, DatabaseError>;
+
+    // The same for an authenticator app code tried against the OTP factor or
+    // the enrolment in slot. False as well when there is nothing in slot.
+    async fn take_totp_attempt(
+        &self,
+        user_uuid: String,
+        slot: SecretSlot,
+        window_start: DateTime,
+        max_attempts: u32
+    ) -> Result<bool, DatabaseError>;
+
+    // Moves the last used time step of the OTP factor factor_id up to step,
+    // if it is still below it. Of several requests holding a code for the
+    // same step only one gets true, the rest are replays.
+    async fn claim_totp_step(
+        &self,
+        user_uuid: String,
+        factor_id: String,
+        step: u64
+    ) -> Result<bool, DatabaseError>;
+
+    // Puts new in slot if the secret there is still old, so re-encrypting
+    // doesn't write over anything a login changed meanwhile. False when it
+    // changed, read the credentail again and retry.
+    async fn swap_secret(
+        &self,
+        user_uuid: String,
+        slot: SecretSlot,
+        old: StoredSecret,
+        new: StoredSecret
+    ) -> Result<bool, DatabaseError>;
+
+    // Ordered by user_email so pages are stable between calls.
+    async fn list_users(
+        &self, 
+        skip: u64,
+        limit: u64
+    ) -> Result<Vec<User>, DatabaseError>;
     
 }
\ No newline at end of file
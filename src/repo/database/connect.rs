@@ -0,0 +1,58 @@
+use crate::config::{DatabaseBackend, DatabaseConfig};
+use crate::repo::database::base::{Database, DatabaseError};
+use crate::repo::database::metered::Metered;
+use crate::repo::database::mongodb::MongoRepo;
+use crate::repo::database::sql::SqlRepo;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+// Opens the backend named in the config, wrapped in Metered, for the server
+// and the admin tool alike. Pending migrations are applied unless
+// database.migrate_on_start is off.
+pub async fn open(config: &DatabaseConfig) -> Result<Arc<dyn Database>, DatabaseError> {
+
+    let database = match config.backend {
+        DatabaseBackend::Mongodb => connect::<Metered<MongoRepo>>(config).await?,
+        DatabaseBackend::Sql => connect::<Metered<SqlRepo>>(config).await?,
+    };
+
+    if config.migrate_on_start {
+        for migration in database.migrate().await? {
+            tracing::info!("applied migration {}", migration);
+        }
+    }
+
+    return Ok(database);
+}
+
+// Keeps retrying transient failures with exponential backoff so the service
+// can be started alongside its database instead of crashing until it is up.
+async fn connect<D: Database + 'static>(config: &DatabaseConfig) -> Result<Arc<dyn Database>, DatabaseError> {
+
+    let mut backoff = Duration::from_millis(config.connect_backoff_ms);
+    let mut attempt = 1;
+
+    loop {
+        let error = match D::init(config.url.clone(), config.name.clone()).await {
+            Ok(database) => return Ok(Arc::new(database)),
+            Err(error) => error,
+        };
+
+        if !error.is_transient() || attempt >= config.connect_attempts {
+            return Err(error);
+        }
+
+        tracing::warn!(
+            "database connection attempt {} of {} failed, retrying in {:?}: {:?}",
+            attempt, config.connect_attempts, backoff, error
+        );
+
+        actix_web::rt::time::sleep(backoff).await;
+
+        backoff = (backoff * 2).min(MAX_CONNECT_BACKOFF);
+        attempt += 1;
+    }
+}
@@ -0,0 +1,139 @@
+use crate::model::{user::User, credentail::{CodeSlot, SecretSlot, UserCredentail}};
+use crate::secrets::StoredSecret;
+use crate::repo::database::base::{Database, DatabaseError};
+use crate::metrics::DATABASE_DURATION;
+
+use std::future::Future;
+use std::time::Instant;
+use async_trait::async_trait;
+use bson::DateTime;
+use tracing::Instrument;
+
+// Wraps any backend, runs each Database call in its own tracing span and
+// records how long it took, labelled with the method name and whether it
+// returned Ok or Err.
+pub struct Metered<D> {
+    inner: D,
+}
+
+async fn timed<T>(
+    method: &str,
+    call: impl Future<Output = Result<T, DatabaseError>>
+) -> Result<T, DatabaseError> {
+
+    let span = tracing::info_span!("database", db.operation = method);
+
+    let start = Instant::now();
+    let result = call.instrument(span).await;
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+
+    DATABASE_DURATION.with_label_values(&[method, outcome]).observe(start.elapsed().as_secs_f64());
+
+    return result;
+}
+
+#[async_trait]
+impl<D: Database> Database for Metered<D> {
+
+    async fn init (
+        connection_url: String,
+        database: String
+    ) -> Result<Metered<D>, DatabaseError> {
+        let inner = timed("init", D::init(connection_url, database)).await?;
+
+        return Ok(Metered { inner });
+    }
+
+    async fn health_check(&self) -> Result<(), DatabaseError> {
+        return timed("health_check", self.inner.health_check()).await;
+    }
+
+    async fn migrate(&self) -> Result<Vec<String>, DatabaseError> {
+        return timed("migrate", self.inner.migrate()).await;
+    }
+
+    async fn get_user(&self, user_uudi: String) -> Result<Option<User>, DatabaseError> {
+        return timed("get_user", self.inner.get_user(user_uudi)).await;
+    }
+
+    async fn get_users(&self, user_uuids: Vec<String>) -> Result<Vec<User>, DatabaseError> {
+        return timed("get_users", self.inner.get_users(user_uuids)).await;
+    }
+
+    async fn get_credentail(&self, user_uudi: String) -> Result<Option<UserCredentail>, DatabaseError> {
+        return timed("get_credentail", self.inner.get_credentail(user_uudi)).await;
+    }
+
+    async fn get_user_by_email(&self, user_email: String) -> Result<Option<User>, DatabaseError> {
+        return timed("get_user_by_email", self.inner.get_user_by_email(user_email)).await;
+    }
+
+    async fn get_user_by_username(&self, username: String) -> Result<Option<User>, DatabaseError> {
+        return timed("get_user_by_username", self.inner.get_user_by_username(username)).await;
+    }
+
+    async fn get_user_by_phone_number(&self, phone_number: String) -> Result<Option<User>, DatabaseError> {
+        return timed("get_user_by_phone_number", self.inner.get_user_by_phone_number(phone_number)).await;
+    }
+
+    async fn insert_user(&self, user: User) -> Result<User, DatabaseError> {
+        return timed("insert_user", self.inner.insert_user(user)).await;
+    }
+
+    async fn insert_credentail(&self, credentail: UserCredentail) -> Result<bool, DatabaseError> {
+        return timed("insert_credentail", self.inner.insert_credentail(credentail)).await;
+    }
+
+    async fn insert_user_with_credentail(&self, user: User, credentail: UserCredentail) -> Result<User, DatabaseError> {
+        return timed("insert_user_with_credentail", self.inner.insert_user_with_credentail(user, credentail)).await;
+    }
+
+    async fn delete_user(&self, user: User) -> Result<User, DatabaseError> {
+        return timed("delete_user", self.inner.delete_user(user)).await;
+    }
+
+    async fn update_user(&self, user: User) -> Result<User, DatabaseError> {
+        return timed("update_user", self.inner.update_user(user)).await;
+    }
+
+    async fn set_last_login(&self, user_uuid: String, last_login: DateTime) -> Result<(), DatabaseError> {
+        return timed("set_last_login", self.inner.set_last_login(user_uuid, last_login)).await;
+    }
+
+    async fn update_user_with_credentail(&self, user: User, credentail: UserCredentail) -> Result<User, DatabaseError> {
+        return timed("update_user_with_credentail", self.inner.update_user_with_credentail(user, credentail)).await;
+    }
+
+    async fn update_credentail(&self, credentail: UserCredentail) -> Result<UserCredentail, DatabaseError> {
+        return timed("update_credentail", self.inner.update_credentail(credentail)).await;
+    }
+
+    async fn take_code_attempt(&self, user_uuid: String, slot: CodeSlot, issued_at: DateTime, max_attempts: u32) -> Result<bool, DatabaseError> {
+        return timed("take_code_attempt", self.inner.take_code_attempt(user_uuid, slot, issued_at, max_attempts)).await;
+    }
+
+    async fn spend_code(&self, user_uuid: String, slot: CodeSlot, issued_at: DateTime) -> Result<bool, DatabaseError> {
+        return timed("spend_code", self.inner.spend_code(user_uuid, slot, issued_at)).await;
+    }
+
+    async fn take_reauth_attempt(&self, user_uuid: String, window_start: DateTime, max_attempts: u32) -> Result<bool, DatabaseError> {
+        return timed("take_reauth_attempt", self.inner.take_reauth_attempt(user_uuid, window_start, max_attempts)).await;
+    }
+
+    async fn take_totp_attempt(&self, user_uuid: String, slot: SecretSlot, window_start: DateTime, max_attempts: u32) -> Result<bool, DatabaseError> {
+        return timed("take_totp_attempt", self.inner.take_totp_attempt(user_uuid, slot, window_start, max_attempts)).await;
+    }
+
+    async fn claim_totp_step(&self, user_uuid: String, factor_id: String, step: u64) -> Result<bool, DatabaseError> {
+        return timed("claim_totp_step", self.inner.claim_totp_step(user_uuid, factor_id, step)).await;
+    }
+
+    async fn swap_secret(&self, user_uuid: String, slot: SecretSlot, old: StoredSecret, new: StoredSecret) -> Result<bool, DatabaseError> {
+        return timed("swap_secret", self.inner.swap_secret(user_uuid, slot, old, new)).await;
+    }
+
+    async fn list_users(&self, skip: u64, limit: u64) -> Result<Vec<User>, DatabaseError> {
+        return timed("list_users", self.inner.list_users(skip, limit)).await;
+    }
+
+}
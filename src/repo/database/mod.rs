@@ -1,2 +1,6 @@
 pub mod mongodb;
-pub mod base;
\ No newline at end of file
+pub mod mongodb_migrations;
+pub mod sql;
+pub mod metered;
+pub mod connect;
+pub mod base;
@@ -1,119 +1,270 @@
-use crate::model::{user::User, credentail::UserCredentail};
-use crate::repo::database::base::DatabaseError;
+use crate::model::{user::User, credentail::{CodeSlot, SecretSlot, UserCredentail}};
+use crate::secrets::StoredSecret;
+use crate::repo::database::base::{DatabaseError, FailureKind};
 use crate::repo::database::base::Database as BaseDatabase;
-
-use mongodb::{Client, options::ClientOptions, Database, bson::doc};
+use crate::repo::database::mongodb_migrations;
+
+use async_trait::async_trait;
+use bson::{Bson, DateTime, Document};
+use futures_util::{FutureExt, TryStreamExt};
+use mongodb::{
+    Client,
+    Database,
+    IndexModel,
+    bson::doc,
+    options::{ClientOptions, FindOptions, IndexOptions},
+    error::{Error, ErrorKind, WriteFailure},
+};
+
+const DUPLICATE_KEY: i32 = 11000;
+
+// Raised inside a transaction to abort it when the credentail is gone.
+#[derive(Debug)]
+struct MissingCredentail;
 
 #[derive(Clone)]
 pub struct MongoRepo {
+    client: Client,
     client_database: Database,
 }
 
-impl BaseDatabase for MongoRepo {
+impl MongoRepo {
 
-    async fn init (
-        connection_url: String,
-        database: String
-    ) -> MongoRepo {
-        let client_options = ClientOptions::parse(&connection_url).await.unwrap();
-        let client = Client::with_options(client_options).unwrap();
+    // Sparse indexes skip documents without the field, for optional fields
+    // that would otherwise all collide on null.
+    async fn create_unique_index(&self, collection: &str, field: &str, sparse: bool) -> Result<(), DatabaseError> {
+
+        let index = IndexModel::builder()
+            .keys(doc! {field: 1})
+            .options(IndexOptions::builder().unique(true).sparse(sparse).name(format!("{}_unique", field)).build())
+            .build();
+
+        self.client_database
+            .collection::<bson::Document>(collection)
+            .create_index(index, None)
+            .await
+            .map_err(MongoRepo::failure)?;
 
-        return MongoRepo{
-            client_database: client.database(database.as_str())
+        return Ok(());
+
+    }
+
+    // Where the code sits in a credentail document, and the fields spending
+    // it clears.
+    fn code_slot_paths(slot: CodeSlot) -> (&'static str, &'static [&'static str]) {
+        match slot {
+            CodeSlot::MfaCode => return ("mfa_code", &["mfa_code", "mfa_code_factor"]),
+            CodeSlot::EmailChange => return ("email_change.code", &["email_change"]),
+            CodeSlot::EmailRevert => return ("email_revert.token", &["email_revert"]),
+            CodeSlot::MagicLink => return ("magic_link.token", &["magic_link"]),
+            CodeSlot::PhoneChange => return ("phone_change.code", &["phone_change"]),
+            CodeSlot::PhoneLogin => return ("phone_login", &["phone_login"]),
         }
     }
 
-    async fn get_user(&self, user_uudi: String) -> Option<User> {
+    // Counts a try in the AttemptWindow at path, see AttemptWindow::take.
+    // open selects the credentail while that window has tries left, stale
+    // while it is missing or began before the current one.
+    async fn take_attempt_window(&self, open: Document, stale: Document, path: &str) -> Result<bool, DatabaseError> {
 
-        let collection = self.client_database.collection::<User>("users");
+        let collection = self.client_database.collection::<UserCredentail>("credentails");
 
-        let user = collection.find_one(doc! {"user_email": user_uudi.clone()}, None).await;
+        let count = || collection.update_one(
+            open.clone(),
+            doc! {"$inc": {format!("{}.attempts", path): 1}},
+            None
+        );
 
-        if user.is_err() {
-            return None;
+        if count().await.map_err(MongoRepo::failure)?.modified_count == 1 {
+            return Ok(true);
         }
 
-        if user.as_ref().ok().is_none(){
-            return None;
+        let restart = collection.update_one(
+            stale,
+            doc! {"$set": {path: {"started_at": DateTime::now(), "attempts": 1}}},
+            None
+        ).await.map_err(MongoRepo::failure)?;
+
+        if restart.modified_count == 1 {
+            return Ok(true);
         }
 
-        if user.as_ref().unwrap().is_none() {
-            return None;
+        // another request may just have started the window
+        return Ok(count().await.map_err(MongoRepo::failure)?.modified_count == 1);
+    }
+
+    pub(crate) fn is_duplicate_key(error: &Error) -> bool {
+        return MongoRepo::duplicate_key_message(error).is_some();
+    }
+
+    // Returns the server message when the write was rejected by a unique index,
+    // the message names the index that was hit.
+    fn duplicate_key_message(error: &Error) -> Option<String> {
+        match error.kind.as_ref() {
+            ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == DUPLICATE_KEY => {
+                return Some(write_error.message.clone());
+            },
+            _ => return None,
         }
+    }
+
+    // Transactions need a replica set or a sharded cluster, a standalone
+    // mongod rejects them so every signup and delete would fail.
+    async fn supports_transactions(&self) -> Result<bool, DatabaseError> {
 
-        return Some(user.unwrap().unwrap());
+        let hello = self.client_database
+            .run_command(doc! {"hello": 1}, None)
+            .await
+            .map_err(MongoRepo::failure)?;
+
+        return Ok(hello.contains_key("setName") || hello.get_str("msg") == Ok("isdbgrid"));
 
     }
 
+    pub(crate) fn failure(error: Error) -> DatabaseError {
 
-    async fn get_credentail(&self, user_uudi: String) -> Option<UserCredentail> {
+        let transient = matches!(
+            error.kind.as_ref(),
+            ErrorKind::Io(_) | ErrorKind::ServerSelection { .. } | ErrorKind::ConnectionPoolCleared { .. }
+        ) || error.contains_label("TransientTransactionError")
+            || error.contains_label("RetryableWriteError");
 
-        let collection = self.client_database.collection::<UserCredentail>("credentails");
+        if transient {
+            return DatabaseError::failure(FailureKind::Transient, error);
+        }
+
+        return DatabaseError::failure(FailureKind::Permanent, error);
 
-        let credentail = collection.find_one(doc! {"user_uuid": &user_uudi}, None).await;
+    }
+
+    fn user_write_error(error: Error) -> DatabaseError {
+        match MongoRepo::duplicate_key_message(&error) {
+            Some(message) if message.contains("user_email") => return DatabaseError::UserNameExists,
+            Some(message) if message.contains("username") => return DatabaseError::UsernameTaken,
+            Some(message) if message.contains("phone_number") => return DatabaseError::PhoneNumberTaken,
+            Some(_) => return DatabaseError::UserUuidExists,
+            None => return MongoRepo::failure(error),
+        }
+    }
 
-        if credentail.is_err() {
-            return None;
+    fn credentail_write_error(error: Error) -> DatabaseError {
+        match MongoRepo::duplicate_key_message(&error) {
+            Some(_) => return DatabaseError::UserUuidExists,
+            None => return MongoRepo::failure(error),
         }
+    }
+}
 
-        if credentail.as_ref().ok().is_none(){
-            return None;
+#[async_trait]
+impl BaseDatabase for MongoRepo {
+
+    async fn init (
+        connection_url: String,
+        database: String
+    ) -> Result<MongoRepo, DatabaseError> {
+        let client_options = ClientOptions::parse(&connection_url).await.map_err(MongoRepo::failure)?;
+        let client = Client::with_options(client_options).map_err(MongoRepo::failure)?;
+
+        let repo = MongoRepo{
+            client_database: client.database(database.as_str()),
+            client,
+        };
+
+        // uniqueness is enforced by the server so concurrent inserts can't race past a lookup
+        repo.create_unique_index("users", "user_uuid", false).await?;
+        repo.create_unique_index("users", "user_email", false).await?;
+        repo.create_unique_index("users", "username", true).await?;
+        repo.create_unique_index("users", "username_skeleton", true).await?;
+        repo.create_unique_index("users", "phone_number", true).await?;
+        repo.create_unique_index("credentails", "user_uuid", false).await?;
+
+        if !repo.supports_transactions().await? {
+            tracing::warn!("mongodb is a standalone server, writes that need a transaction will fail until it runs as a replica set");
         }
 
-        if credentail.as_ref().unwrap().is_none() {
-            return None;
+        return Ok(repo);
+    }
+
+    async fn health_check(&self) -> Result<(), DatabaseError> {
+
+        if !self.supports_transactions().await? {
+            return Err(DatabaseError::failure(FailureKind::Permanent, "transactions need a replica set or sharded cluster"));
         }
 
-        return Some(credentail.unwrap().unwrap());
+        return Ok(());
 
     }
 
+    async fn migrate(&self) -> Result<Vec<String>, DatabaseError> {
+        return mongodb_migrations::run(&self.client, &self.client_database).await;
+    }
 
-    async fn get_user_by_user_name(&self, user_name: String) -> Option<User> {
+    async fn get_user(&self, user_uudi: String) -> Result<Option<User>, DatabaseError> {
 
         let collection = self.client_database.collection::<User>("users");
 
-        let user = collection.find_one(doc! {"user_email": &user_name}, None).await;
+        return collection.find_one(doc! {"user_uuid": user_uudi}, None).await.map_err(MongoRepo::failure);
 
-        if user.is_err() {
-            return None;
-        }
+    }
 
-        if user.as_ref().ok().is_none(){
-            return None;
-        }
 
-        if user.as_ref().unwrap().is_none() {
-            return None;
+    async fn get_users(&self, user_uuids: Vec<String>) -> Result<Vec<User>, DatabaseError> {
+
+        if user_uuids.is_empty() {
+            return Ok(Vec::new());
         }
 
-        return Some(user.unwrap().unwrap());
+        let collection = self.client_database.collection::<User>("users");
+
+        let cursor = collection.find(doc! {"user_uuid": {"$in": user_uuids}}, None).await.map_err(MongoRepo::failure)?;
+
+        return cursor.try_collect().await.map_err(MongoRepo::failure);
 
     }
 
-    async fn insert_user(&self, user: User) -> Result<User, DatabaseError> {
+
+    async fn get_credentail(&self, user_uudi: String) -> Result<Option<UserCredentail>, DatabaseError> {
+
+        let collection = self.client_database.collection::<UserCredentail>("credentails");
+
+        return collection.find_one(doc! {"user_uuid": &user_uudi}, None).await.map_err(MongoRepo::failure);
+
+    }
+
+
+    async fn get_user_by_email(&self, user_email: String) -> Result<Option<User>, DatabaseError> {
 
         let collection = self.client_database.collection::<User>("users");
 
-        let mut db_find = self.get_user(user.user_uuid.clone()).await;
+        return collection.find_one(doc! {"user_email": &user_email}, None).await.map_err(MongoRepo::failure);
 
-        if db_find.is_some(){
-            return Err(DatabaseError::UserUuidExists);
-        }
+    }
 
-        
-        
-        db_find = self.get_user_by_user_name(user.user_email.clone()).await;
+    async fn get_user_by_username(&self, username: String) -> Result<Option<User>, DatabaseError> {
 
-        if db_find.is_some(){
-            return Err(DatabaseError::UserNameExists);
-        }
+        let collection = self.client_database.collection::<User>("users");
+
+        return collection.find_one(doc! {"username": &username}, None).await.map_err(MongoRepo::failure);
+
+    }
+
+    async fn get_user_by_phone_number(&self, phone_number: String) -> Result<Option<User>, DatabaseError> {
+
+        let collection = self.client_database.collection::<User>("users");
+
+        return collection.find_one(doc! {"phone_number": &phone_number}, None).await.map_err(MongoRepo::failure);
+
+    }
+
+    async fn insert_user(&self, user: User) -> Result<User, DatabaseError> {
+
+        let collection = self.client_database.collection::<User>("users");
 
         let insert = collection.insert_one(user.clone(), None).await;
 
-        if insert.is_err() {
-            return Err(DatabaseError::DBFailure);
-        } 
+        if let Err(error) = insert {
+            return Err(MongoRepo::user_write_error(error));
+        }
             
         return Ok(user);
 
@@ -124,36 +275,62 @@ impl BaseDatabase for MongoRepo {
 
         let collection = self.client_database.collection::<UserCredentail>("credentails");
 
-        let db_find = self.get_credentail(credentail.user_uuid.clone()).await;
-
-        if db_find.is_some(){
-            return Err(DatabaseError::UserUuidExists);
-        }
-
         let insert = collection.insert_one(credentail.clone(), None).await;
 
-        if insert.is_err() {
-            return Err(DatabaseError::DBFailure);
-        } 
+        if let Err(error) = insert {
+            return Err(MongoRepo::credentail_write_error(error));
+        }
             
         return Ok(true);
 
     }
 
+    async fn insert_user_with_credentail(&self, user: User, credentail: UserCredentail) -> Result<User, DatabaseError> {
+
+        let users = self.client_database.collection::<User>("users");
+        let credentails = self.client_database.collection::<UserCredentail>("credentails");
+
+        let mut session = self.client.start_session(None).await.map_err(MongoRepo::failure)?;
+
+        session.with_transaction(
+            (&users, &credentails, &user, &credentail),
+            |session, (users, credentails, user, credentail)| async move {
+                users.insert_one_with_session(*user, None, session).await?;
+                credentails.insert_one_with_session(*credentail, None, session).await?;
+                return Ok(());
+            }.boxed(),
+            None,
+        ).await.map_err(MongoRepo::user_write_error)?;
+
+        return Ok(user);
+
+    }
+
+    // Removes the credentail in the same transaction, there is no cascade
+    // between collections.
     async fn delete_user(&self, user: User) -> Result<User, DatabaseError> {
 
-        let collection = self.client_database.collection::<User>("users");
+        let users = self.client_database.collection::<User>("users");
+        let credentails = self.client_database.collection::<UserCredentail>("credentails");
 
-        let user_find = self.get_user(user.user_uuid.clone()).await;
+        let mut session = self.client.start_session(None).await.map_err(MongoRepo::failure)?;
 
-        if user_find.is_none() {
-            return Err(DatabaseError::UserDoesntExist);
-        }
+        let deleted_count = session.with_transaction(
+            (&users, &credentails, &user.user_uuid),
+            |session, (users, credentails, user_uuid)| async move {
+                let delete = users.delete_one_with_session(doc! {"user_uuid": *user_uuid}, None, session).await?;
+
+                if delete.deleted_count > 0 {
+                    credentails.delete_one_with_session(doc! {"user_uuid": *user_uuid}, None, session).await?;
+                }
 
-        let delete = collection.delete_one(doc! {"user_uuid": user.user_uuid.clone()}, None).await;
+                return Ok(delete.deleted_count);
+            }.boxed(),
+            None,
+        ).await.map_err(MongoRepo::failure)?;
 
-        if delete.as_ref().is_err() {
-            return Err(DatabaseError::DBFailure)
+        if deleted_count == 0 {
+            return Err(DatabaseError::UserDoesntExist);
         }
 
         return Ok(user)
@@ -164,19 +341,455 @@ impl BaseDatabase for MongoRepo {
 
         let collection = self.client_database.collection::<User>("users");
 
-        let user_insert = collection.update_one(
-            doc!{"user_uuid": user.user_uuid.clone()}, 
-            doc! {"$set": bson::to_bson(&user).unwrap()}, 
+        // replaced rather than $set, optional fields that became None have
+        // to disappear from the document
+        let replace = collection
+            .replace_one(doc! {"user_uuid": user.user_uuid.clone()}, user.clone(), None)
+            .await
+            .map_err(MongoRepo::user_write_error)?;
+
+        if replace.matched_count == 0 {
+            return Err(DatabaseError::UserDoesntExist);
+        }
+
+        return Ok(user);
+    }
+
+    async fn set_last_login(&self, user_uuid: String, last_login: DateTime) -> Result<(), DatabaseError> {
+
+        let collection = self.client_database.collection::<User>("users");
+
+        let update = collection.update_one(
+            doc! {"user_uuid": user_uuid},
+            doc! {"$set": {"last_login": last_login}},
             None
+        ).await.map_err(MongoRepo::failure)?;
+
+        if update.matched_count == 0 {
+            return Err(DatabaseError::UserDoesntExist);
+        }
+
+        return Ok(());
+    }
+
+    async fn update_user_with_credentail(&self, user: User, credentail: UserCredentail) -> Result<User, DatabaseError> {
+
+        let users = self.client_database.collection::<User>("users");
+        let credentails = self.client_database.collection::<UserCredentail>("credentails");
+
+        let mut session = self.client.start_session(None).await.map_err(MongoRepo::failure)?;
+
+        let update = session.with_transaction(
+            (&users, &credentails, &user, &credentail),
+            |session, (users, credentails, user, credentail)| async move {
+                let user_uuid = &user.user_uuid;
+
+                users.replace_one_with_session(
+                    doc! {"user_uuid": user_uuid},
+                    *user,
+                    None,
+                    session
+                ).await?;
+
+                let replace = credentails.replace_one_with_session(
+                    doc! {"user_uuid": user_uuid},
+                    *credentail,
+                    None,
+                    session
+                ).await?;
+
+                // aborts the user update as well
+                if replace.matched_count == 0 {
+                    return Err(Error::custom(MissingCredentail));
+                }
+
+                return Ok(());
+            }.boxed(),
+            None,
         ).await;
 
-        if user_insert.is_err() {
-            return Err(DatabaseError::DBFailure);
+        match update {
+            Ok(_) => return Ok(user),
+            Err(error) if error.get_custom::<MissingCredentail>().is_some() => return Err(DatabaseError::UserDoesntExist),
+            Err(error) => return Err(MongoRepo::user_write_error(error)),
         }
 
-        return Ok(user);
     }
 
+    async fn update_credentail(&self, credentail: UserCredentail) -> Result<UserCredentail, DatabaseError> {
+
+        let collection = self.client_database.collection::<UserCredentail>("credentails");
+
+        let replace = collection
+            .replace_one(doc! {"user_uuid": credentail.user_uuid.clone()}, credentail.clone(), None)
+            .await
+            .map_err(MongoRepo::failure)?;
+
+        if replace.matched_count == 0 {
+            return Err(DatabaseError::UserDoesntExist);
+        }
+
+        return Ok(credentail);
+    }
+
+    async fn take_code_attempt(&self, user_uuid: String, slot: CodeSlot, issued_at: DateTime, max_attempts: u32) -> Result<bool, DatabaseError> {
+
+        let collection = self.client_database.collection::<UserCredentail>("credentails");
+
+        let (path, _) = MongoRepo::code_slot_paths(slot);
+        let attempts = format!("{}.attempts", path);
+
+        let update = collection.update_one(
+            doc! {
+                "user_uuid": user_uuid,
+                format!("{}.issued_at", path): issued_at,
+                &attempts: {"$lt": max_attempts as i64},
+            },
+            doc! {"$inc": {&attempts: 1}},
+            None
+        ).await.map_err(MongoRepo::failure)?;
+
+        return Ok(update.modified_count == 1);
+    }
+
+    async fn spend_code(&self, user_uuid: String, slot: CodeSlot, issued_at: DateTime) -> Result<bool, DatabaseError> {
+
+        let collection = self.client_database.collection::<UserCredentail>("credentails");
+
+        let (path, fields) = MongoRepo::code_slot_paths(slot);
+
+        let cleared: Document = fields.iter().map(|field| (field.to_string(), Bson::Null)).collect();
+
+        let update = collection.update_one(
+            doc! {"user_uuid": user_uuid, format!("{}.issued_at", path): issued_at},
+            doc! {"$set": cleared},
+            None
+        ).await.map_err(MongoRepo::failure)?;
+
+        return Ok(update.modified_count == 1);
+    }
+
+    async fn take_reauth_attempt(&self, user_uuid: String, window_start: DateTime, max_attempts: u32) -> Result<bool, DatabaseError> {
+        return self.take_attempt_window(
+            doc! {
+                "user_uuid": &user_uuid,
+                "reauth_attempts.started_at": {"$gte": window_start},
+                "reauth_attempts.attempts": {"$lt": max_attempts as i64},
+            },
+            doc! {
+                "user_uuid": &user_uuid,
+                "$or": [{"reauth_attempts": null}, {"reauth_attempts.started_at": {"$lt": window_start}}],
+            },
+            "reauth_attempts",
+        ).await;
+    }
+
+    async fn take_totp_attempt(&self, user_uuid: String, slot: SecretSlot, window_start: DateTime, max_attempts: u32) -> Result<bool, DatabaseError> {
+
+        let open = doc! {"attempts.started_at": {"$gte": window_start}, "attempts.attempts": {"$lt": max_attempts as i64}};
+        let stale = doc! {"$or": [{"attempts": null}, {"attempts.started_at": {"$lt": window_start}}]};
+
+        let factor_id = match slot {
+            SecretSlot::Factor(factor_id) => factor_id,
+            SecretSlot::TotpEnrollment => return self.take_attempt_window(
+                doc! {
+                    "user_uuid": &user_uuid,
+                    "totp_enrollment.attempts.started_at": {"$gte": window_start},
+                    "totp_enrollment.attempts.attempts": {"$lt": max_attempts as i64},
+                },
+                doc! {
+                    "user_uuid": &user_uuid,
+                    "totp_enrollment": {"$ne": null},
+                    "$or": [{"totp_enrollment.attempts": null}, {"totp_enrollment.attempts.started_at": {"$lt": window_start}}],
+                },
+                "totp_enrollment.attempts",
+            ).await,
+        };
+
+        let mut open_factor = doc! {"factor_id": &factor_id, "factor_type": "OTP"};
+        open_factor.extend(open);
+        let mut stale_factor = doc! {"factor_id": &factor_id, "factor_type": "OTP"};
+        stale_factor.extend(stale);
+
+        let taken = self.take_attempt_window(
+            doc! {"user_uuid": &user_uuid, "mfa_factors": {"$elemMatch": open_factor}},
+            doc! {"user_uuid": &user_uuid, "mfa_factors": {"$elemMatch": stale_factor}},
+            "mfa_factors.$.attempts",
+        ).await?;
+
+        if taken {
+            return Ok(true);
+        }
+
+        // the single factor layout counts at the top until it is saved again,
+        // see UserCredentail
+        let legacy = doc! {
+            "user_uuid": &user_uuid,
+            "$or": [{"mfa_factors": {"$exists": false}}, {"mfa_factors": {"$size": 0}}],
+            "user_mfa_state": "OTP",
+        };
+
+        let mut open_legacy = legacy.clone();
+        open_legacy.extend(doc! {
+            "totp_attempts.started_at": {"$gte": window_start},
+            "totp_attempts.attempts": {"$lt": max_attempts as i64},
+        });
+        let mut stale_legacy = legacy;
+        stale_legacy.extend(doc! {
+            "$and": [{"$or": [{"totp_attempts": null}, {"totp_attempts.started_at": {"$lt": window_start}}]}],
+        });
+
+        return self.take_attempt_window(open_legacy, stale_legacy, "totp_attempts").await;
+    }
+
+    async fn claim_totp_step(&self, user_uuid: String, factor_id: String, step: u64) -> Result<bool, DatabaseError> {
+
+        let collection = self.client_database.collection::<UserCredentail>("credentails");
+
+        let step = step as i64;
+        let unused = doc! {"$or": [{"totp_last_step": null}, {"totp_last_step": {"$lt": step}}]};
+
+        let mut factor = doc! {"factor_id": &factor_id};
+        factor.extend(unused.clone());
+
+        let update = collection.update_one(
+            doc! {"user_uuid": &user_uuid, "mfa_factors": {"$elemMatch": factor}},
+            doc! {"$set": {"mfa_factors.$.totp_last_step": step}},
+            None
+        ).await.map_err(MongoRepo::failure)?;
+
+        if update.modified_count == 1 {
+            return Ok(true);
+        }
+
+        // a credentail still in the single factor layout keeps its step at
+        // the top until it is saved again, see UserCredentail
+        let update = collection.update_one(
+            doc! {
+                "user_uuid": &user_uuid,
+                "$and": [
+                    {"$or": [{"mfa_factors": {"$exists": false}}, {"mfa_factors": {"$size": 0}}]},
+                    unused,
+                ],
+            },
+            doc! {"$set": {"totp_last_step": step}},
+            None
+        ).await.map_err(MongoRepo::failure)?;
+
+        return Ok(update.modified_count == 1);
+    }
+
+    async fn swap_secret(&self, user_uuid: String, slot: SecretSlot, old: StoredSecret, new: StoredSecret) -> Result<bool, DatabaseError> {
+
+        let collection = self.client_database.collection::<UserCredentail>("credentails");
+
+        let old = bson::to_bson(&old).map_err(|error| MongoRepo::failure(error.into()))?;
+        let new = bson::to_bson(&new).map_err(|error| MongoRepo::failure(error.into()))?;
+
+        let factor_id = match slot {
+            SecretSlot::Factor(factor_id) => factor_id,
+            SecretSlot::TotpEnrollment => {
+                let update = collection.update_one(
+                    doc! {"user_uuid": &user_uuid, "totp_enrollment.secret": old},
+                    doc! {"$set": {"totp_enrollment.secret": new}},
+                    None
+                ).await.map_err(MongoRepo::failure)?;
+
+                return Ok(update.modified_count == 1);
+            },
+        };
+
+        let update = collection.update_one(
+            doc! {"user_uuid": &user_uuid, "mfa_factors": {"$elemMatch": {"factor_id": &factor_id, "secret": &old}}},
+            doc! {"$set": {"mfa_factors.$.secret": &new}},
+            None
+        ).await.map_err(MongoRepo::failure)?;
+
+        if update.modified_count == 1 {
+            return Ok(true);
+        }
+
+        // the single factor layout keeps its secret at the top
+        let update = collection.update_one(
+            doc! {
+                "user_uuid": &user_uuid,
+                "$or": [{"mfa_factors": {"$exists": false}}, {"mfa_factors": {"$size": 0}}],
+                "user_mfa_store": old,
+            },
+            doc! {"$set": {"user_mfa_store": new}},
+            None
+        ).await.map_err(MongoRepo::failure)?;
+
+        return Ok(update.modified_count == 1);
+    }
+
+    async fn list_users(&self, skip: u64, limit: u64) -> Result<Vec<User>, DatabaseError> {
+
+        let collection = self.client_database.collection::<User>("users");
+
+        let options = FindOptions::builder()
+            .sort(doc! {"user_email": 1})
+            .skip(skip)
+            .limit(limit as i64)
+            .build();
+
+        let cursor = collection.find(doc! {}, options).await.map_err(MongoRepo::failure)?;
+
+        return cursor.try_collect().await.map_err(MongoRepo::failure);
+    }
+
+
+
+}
+// Run against a real server when USERAUTH_TEST_MONGODB_URL points at one,
+// a replica set since some writes use transactions. Skipped otherwise.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn repo() -> Option<MongoRepo> {
+        let url = std::env::var("USERAUTH_TEST_MONGODB_URL").ok()?;
+        let database = format!("userauth_test_{}", uuid::Uuid::new_v4().to_simple());
+        let repo = MongoRepo::init(url, database).await.unwrap();
+        repo.migrate().await.unwrap();
+        return Some(repo);
+    }
+
+    async fn user_with_phone(repo: &MongoRepo) -> User {
+        let mut user = User::new("bob@example.org".to_owned());
+        user.phone_number = Some("+15550109999".to_owned());
+        let credentail = UserCredentail::new(user.clone(), "correct horse".to_owned(), 4);
+        return repo.insert_user_with_credentail(user, credentail).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn update_user_removes_a_deleted_phone_number() {
+        let Some(repo) = repo().await else { return };
+        let mut user = user_with_phone(&repo).await;
+
+        user.phone_number = None;
+        repo.update_user(user.clone()).await.unwrap();
+
+        let stored = repo.get_user(user.user_uuid.clone()).await.unwrap().unwrap();
+        assert_eq!(stored.phone_number, None);
+        assert!(repo.get_user_by_phone_number("+15550109999".to_owned()).await.unwrap().is_none());
+
+        repo.client_database.drop(None).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn update_user_with_credentail_removes_a_deleted_phone_number() {
+        let Some(repo) = repo().await else { return };
+        let mut user = user_with_phone(&repo).await;
+        let credentail = repo.get_credentail(user.user_uuid.clone()).await.unwrap().unwrap();
 
+        user.phone_number = None;
+        repo.update_user_with_credentail(user.clone(), credentail).await.unwrap();
+
+        let stored = repo.get_user(user.user_uuid.clone()).await.unwrap().unwrap();
+        assert_eq!(stored.phone_number, None);
+
+        repo.client_database.drop(None).await.unwrap();
+    }
 
-}
\ No newline at end of file
+    #[actix_web::test]
+    async fn a_totp_step_is_claimed_once() {
+        let Some(repo) = repo().await else { return };
+        let user = user_with_phone(&repo).await;
+        let mut credentail = repo.get_credentail(user.user_uuid.clone()).await.unwrap().unwrap();
+
+        let keys = crate::secrets::SecretKeys::from_config(&crate::config::SecretsConfig::default());
+        let factor_id = credentail.import_totp(&crate::model::totp::generate_secret(), Default::default(), None, &keys).ok().unwrap();
+        repo.update_credentail(credentail).await.unwrap();
+
+        assert!(repo.claim_totp_step(user.user_uuid.clone(), factor_id.clone(), 10).await.unwrap());
+        assert!(!repo.claim_totp_step(user.user_uuid.clone(), factor_id.clone(), 10).await.unwrap());
+        assert!(!repo.claim_totp_step(user.user_uuid.clone(), factor_id.clone(), 9).await.unwrap());
+        assert!(repo.claim_totp_step(user.user_uuid.clone(), factor_id.clone(), 11).await.unwrap());
+
+        repo.client_database.drop(None).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn reauth_attempts_stop_at_the_limit() {
+        let Some(repo) = repo().await else { return };
+        let user = user_with_phone(&repo).await;
+        let window_start = DateTime::from_millis(DateTime::now().timestamp_millis() - 60 * 1000);
+
+        assert!(repo.take_reauth_attempt(user.user_uuid.clone(), window_start, 2).await.unwrap());
+        assert!(repo.take_reauth_attempt(user.user_uuid.clone(), window_start, 2).await.unwrap());
+        assert!(!repo.take_reauth_attempt(user.user_uuid.clone(), window_start, 2).await.unwrap());
+
+        // the window runs from the first try, one starting later is new
+        let later = DateTime::from_millis(DateTime::now().timestamp_millis() + 1000);
+        assert!(repo.take_reauth_attempt(user.user_uuid.clone(), later, 2).await.unwrap());
+
+        repo.client_database.drop(None).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn set_last_login_leaves_the_rest_of_the_user() {
+        let Some(repo) = repo().await else { return };
+        let user = user_with_phone(&repo).await;
+
+        let mut changed = user.clone();
+        changed.phone_number = None;
+        repo.update_user(changed).await.unwrap();
+
+        let last_login = DateTime::from_millis(user.last_login.timestamp_millis() + 60 * 1000);
+        repo.set_last_login(user.user_uuid.clone(), last_login).await.unwrap();
+
+        let stored = repo.get_user(user.user_uuid.clone()).await.unwrap().unwrap();
+        assert_eq!(stored.last_login, last_login);
+        assert!(stored.phone_number.is_none());
+
+        assert!(matches!(repo.set_last_login("no-such-user".to_owned(), last_login).await, Err(DatabaseError::UserDoesntExist)));
+        assert!(matches!(repo.update_user(User::new("alice@example.org".to_owned())).await, Err(DatabaseError::UserDoesntExist)));
+
+        repo.client_database.drop(None).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn totp_attempts_stop_at_the_limit() {
+        let Some(repo) = repo().await else { return };
+        let user = user_with_phone(&repo).await;
+        let mut credentail = repo.get_credentail(user.user_uuid.clone()).await.unwrap().unwrap();
+        let window_start = DateTime::from_millis(DateTime::now().timestamp_millis() - 60 * 1000);
+
+        let keys = crate::secrets::SecretKeys::from_config(&crate::config::SecretsConfig::default());
+        let factor_id = credentail.import_totp(&crate::model::totp::generate_secret(), Default::default(), None, &keys).ok().unwrap();
+        credentail.begin_totp(&crate::config::MfaConfig::default(), &keys);
+        repo.update_credentail(credentail).await.unwrap();
+
+        for slot in [SecretSlot::Factor(factor_id.clone()), SecretSlot::TotpEnrollment] {
+            assert!(repo.take_totp_attempt(user.user_uuid.clone(), slot.clone(), window_start, 2).await.unwrap());
+            assert!(repo.take_totp_attempt(user.user_uuid.clone(), slot.clone(), window_start, 2).await.unwrap());
+            assert!(!repo.take_totp_attempt(user.user_uuid.clone(), slot, window_start, 2).await.unwrap());
+        }
+
+        repo.client_database.drop(None).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn a_secret_is_only_swapped_if_unchanged() {
+        let Some(repo) = repo().await else { return };
+        let user = user_with_phone(&repo).await;
+        let mut credentail = repo.get_credentail(user.user_uuid.clone()).await.unwrap().unwrap();
+
+        let plaintext = crate::secrets::SecretKeys::from_config(&crate::config::SecretsConfig::default());
+        credentail.import_totp(&crate::model::totp::generate_secret(), Default::default(), None, &plaintext).ok().unwrap();
+        repo.update_credentail(credentail.clone()).await.unwrap();
+
+        let keys = crate::secrets::SecretKeys::from_config(&crate::config::SecretsConfig {
+            keys: [("2026-10".to_owned(), "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=".to_owned())].into(),
+            active_key: "2026-10".to_owned(),
+            allow_plaintext: false,
+        });
+
+        let (slot, old, new) = credentail.resealed_secrets(&keys).unwrap().remove(0);
+        assert!(repo.swap_secret(user.user_uuid.clone(), slot.clone(), old.clone(), new.clone()).await.unwrap());
+        assert!(!repo.swap_secret(user.user_uuid.clone(), slot, old, new).await.unwrap());
+
+        repo.client_database.drop(None).await.unwrap();
+    }
+}
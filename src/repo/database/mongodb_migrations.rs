@@ -0,0 +1,237 @@
+use crate::model::{SCHEMA_VERSION, user::canonical_email};
+use crate::repo::database::base::{DatabaseError, FailureKind};
+use crate::repo::database::mongodb::MongoRepo;
+
+use futures_util::TryStreamExt;
+use mongodb::{
+    Client,
+    Database,
+    bson::{doc, Bson, DateTime, Document},
+    options::UpdateOptions,
+};
+
+// MongoDB has no schema of its own, so layout changes to the stored documents
+// go through the migrations below. Applied ones are recorded in the
+// "migrations" collection and each document carries the schema_version of
+// the last migration that touched it.
+//
+// Steps have to be safe to run twice: a run that died halfway, or two
+// instances starting at once, just redo them. The built in steps only touch
+// documents that still need the change.
+
+const MIGRATIONS_COLLECTION: &str = "migrations";
+
+// Collections holding versioned documents, stamped after every migration.
+// A RenameCollection step has to update this list as well.
+const VERSIONED_COLLECTIONS: [&str; 2] = ["users", "credentails"];
+
+pub enum Step {
+    RenameField { collection: &'static str, from: &'static str, to: &'static str },
+    RenameCollection { from: &'static str, to: &'static str },
+    // Sets the field on documents that don't have it yet
+    Backfill { collection: &'static str, field: &'static str, value: Bson },
+    // Rewrites a string field with a Rust function, for normalisations the
+    // aggregation operators can't express. Dotted paths reach into
+    // subdocuments. Documents the unique indexes reject are all reported
+    // before the step fails.
+    Normalize { collection: &'static str, field: &'static str, normalize: fn(&str) -> String },
+}
+
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub steps: Vec<Step>,
+}
+
+// Ordered by version, the last one has to match model::SCHEMA_VERSION.
+pub fn migrations() -> Vec<Migration> {
+    return vec![
+        // Only introduces schema_version, which the runner stamps on every
+        // existing document.
+        Migration { version: 1, name: "schema_version", steps: vec![] },
+        Migration {
+            version: 2,
+            name: "user_profile",
+            steps: vec![
+                Step::Backfill { collection: "users", field: "profile", value: Bson::Document(doc! {}) },
+            ],
+        },
+        // Emails are compared in canonical_email form from here on. Fails on
+        // the user_email index if two accounts only differ by case or Unicode
+        // form, the log names them and they have to be merged by hand first.
+        // The username indexes are made by init.
+        Migration {
+            version: 3,
+            name: "canonical_email",
+            steps: vec![
+                Step::Normalize { collection: "users", field: "user_email", normalize: canonical_email },
+                Step::Normalize { collection: "users", field: "user_claims.user_name", normalize: canonical_email },
+            ],
+        },
+    ];
+}
+
+async fn apply_step(client: &Client, database: &Database, step: &Step) -> Result<(), DatabaseError> {
+
+    match step {
+        Step::RenameField { collection, from, to } => {
+            let (from, to) = (*from, *to);
+
+            database.collection::<Document>(collection).update_many(
+                doc! {from: {"$exists": true}},
+                doc! {"$rename": {from: to}},
+                None
+            ).await.map_err(MongoRepo::failure)?;
+        },
+        Step::RenameCollection { from, to } => {
+            let (from, to) = (*from, *to);
+
+            let existing = database.list_collection_names(doc! {"name": from}).await.map_err(MongoRepo::failure)?;
+
+            if existing.is_empty() {
+                return Ok(());
+            }
+
+            client.database("admin").run_command(doc! {
+                "renameCollection": format!("{}.{}", database.name(), from),
+                "to": format!("{}.{}", database.name(), to),
+            }, None).await.map_err(MongoRepo::failure)?;
+        },
+        Step::Backfill { collection, field, value } => {
+            let field = *field;
+
+            database.collection::<Document>(collection).update_many(
+                doc! {field: {"$exists": false}},
+                doc! {"$set": {field: value.clone()}},
+                None
+            ).await.map_err(MongoRepo::failure)?;
+        },
+        Step::Normalize { collection, field, normalize } => {
+            let field = *field;
+            let documents = database.collection::<Document>(collection);
+
+            let mut cursor = documents.find(doc! {field: {"$type": "string"}}, None).await.map_err(MongoRepo::failure)?;
+            let mut rejected = Vec::new();
+
+            while let Some(document) = cursor.try_next().await.map_err(MongoRepo::failure)? {
+
+                let value = match string_at(&document, field) {
+                    Some(value) => value,
+                    None => continue,
+                };
+
+                let normalized = normalize(value);
+
+                if normalized == value {
+                    continue;
+                }
+
+                let update = documents.update_one(
+                    doc! {"_id": document.get("_id").cloned().unwrap_or(Bson::Null), field: value},
+                    doc! {"$set": {field: &normalized}},
+                    None
+                ).await;
+
+                if let Err(error) = update {
+                    if MongoRepo::is_duplicate_key(&error) {
+                        let owner = document.get_str("user_uuid").map(str::to_owned).unwrap_or(format!("{:?}", document.get("_id")));
+                        tracing::error!(document = %owner, field, value = %normalized, "normalised value is already taken");
+                        rejected.push(owner);
+                        continue;
+                    }
+                    return Err(MongoRepo::failure(error));
+                }
+            }
+
+            if !rejected.is_empty() {
+                return Err(DatabaseError::failure(FailureKind::Permanent, format!(
+                    "{}.{} could not be normalised for {}, merge or fix them by hand and run the migration again",
+                    collection, field, rejected.join(", ")
+                )));
+            }
+        },
+    }
+
+    return Ok(());
+}
+
+fn string_at<'a>(document: &'a Document, path: &str) -> Option<&'a str> {
+
+    let mut current = document;
+    let mut parts = path.split('.').peekable();
+
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return current.get_str(part).ok();
+        }
+        current = current.get_document(part).ok()?;
+    }
+
+    return None;
+}
+
+async fn stamp_version(database: &Database, version: u32) -> Result<(), mongodb::error::Error> {
+
+    for collection in VERSIONED_COLLECTIONS {
+        database.collection::<Document>(collection).update_many(
+            doc! {"$or": [{"schema_version": {"$exists": false}}, {"schema_version": {"$lt": version as i64}}]},
+            doc! {"$set": {"schema_version": version as i64}},
+            None
+        ).await?;
+    }
+
+    return Ok(());
+}
+
+async fn applied_versions(database: &Database) -> Result<Vec<u32>, mongodb::error::Error> {
+
+    let applied: Vec<Document> = database.collection::<Document>(MIGRATIONS_COLLECTION)
+        .find(doc! {}, None)
+        .await?
+        .try_collect()
+        .await?;
+
+    let versions = applied.iter()
+        .filter_map(|migration| match migration.get("version") {
+            Some(Bson::Int32(version)) => Some(*version as u32),
+            Some(Bson::Int64(version)) => Some(*version as u32),
+            _ => None,
+        })
+        .collect();
+
+    return Ok(versions);
+}
+
+// Runs the migrations that aren't recorded yet, in order, and returns their
+// names. Stops at the first one that fails so later ones never run against
+// a half migrated database.
+pub async fn run(client: &Client, database: &Database) -> Result<Vec<String>, DatabaseError> {
+
+    let migrations = migrations();
+
+    debug_assert_eq!(migrations.last().map(|migration| migration.version), Some(SCHEMA_VERSION));
+
+    let applied = applied_versions(database).await.map_err(MongoRepo::failure)?;
+    let mut ran = Vec::new();
+
+    for migration in migrations.iter().filter(|migration| !applied.contains(&migration.version)) {
+
+        tracing::info!(version = migration.version, name = migration.name, "applying migration");
+
+        for step in &migration.steps {
+            apply_step(client, database, step).await?;
+        }
+
+        stamp_version(database, migration.version).await.map_err(MongoRepo::failure)?;
+
+        database.collection::<Document>(MIGRATIONS_COLLECTION).update_one(
+            doc! {"version": migration.version as i64},
+            doc! {"$setOnInsert": {"name": migration.name, "applied_at": DateTime::now()}},
+            UpdateOptions::builder().upsert(true).build()
+        ).await.map_err(MongoRepo::failure)?;
+
+        ran.push(format!("{} {}", migration.version, migration.name));
+    }
+
+    return Ok(ran);
+}
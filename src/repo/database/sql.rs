@@ -0,0 +1,859 @@
+use crate::model::{SCHEMA_VERSION, user::{User, UserState, canonical_email}, credentail::{CodeSlot, SecretSlot, UserCredentail}, claims::Claims, profile::UserProfile};
+use crate::repo::database::base::{DatabaseError, FailureKind};
+use crate::repo::database::base::Database as BaseDatabase;
+use crate::secrets::StoredSecret;
+
+use std::{collections::BTreeMap, str::FromStr};
+use async_trait::async_trait;
+use bson::DateTime;
+use sqlx::{Any, AnyPool, Executor, migrate::{Migrate, Migrator, MigrateError}};
+
+// Schema lives in /migrations and is applied by migrate(). The DDL is kept
+// portable so the same files work for both SQLite and PostgreSQL.
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+// Conditional credentail writes give up after this many lost races.
+const SWAP_ATTEMPTS: u32 = 10;
+
+// Emails are canonicalised in Rust before this migration runs, SQL LOWER
+// only folds ASCII and knows nothing of NFKC.
+const CANONICAL_EMAIL_MIGRATION: i64 = 20261020000000;
+
+// last_login comes back as text, the Any driver decodes SQLite integers as
+// i32 and would truncate the millisecond timestamp. It also can't decode a
+// NULL into an Option, so missing usernames and phone numbers come back as ''.
+const USER_COLUMNS: &str = "user_uuid, user_email, COALESCE(username, ''), COALESCE(username_skeleton, ''), COALESCE(phone_number, ''), user_state, CAST(last_login AS TEXT), user_claims, profile";
+
+type UserRow = (String, String, String, String, String, String, String, String, String);
+
+#[derive(Clone)]
+pub struct SqlRepo {
+    pool: AnyPool,
+}
+
+impl SqlRepo {
+
+    fn failure(error: sqlx::Error) -> DatabaseError {
+        match error {
+            sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::WorkerCrashed => {
+                return DatabaseError::failure(FailureKind::Transient, error);
+            },
+            _ => return DatabaseError::failure(FailureKind::Permanent, error),
+        }
+    }
+
+    fn migrate_failure(error: MigrateError) -> DatabaseError {
+        match error {
+            MigrateError::Execute(error) => return SqlRepo::failure(error),
+            error => return DatabaseError::failure(FailureKind::Permanent, error),
+        }
+    }
+
+    // SQLite only reports the column in the message, PostgreSQL names the
+    // index, either way the column name is in there.
+    fn user_unique_violation(error: &dyn sqlx::error::DatabaseError) -> DatabaseError {
+
+        let hit = |column: &str| error.constraint().is_some_and(|c| c.contains(column)) || error.message().contains(column);
+
+        if hit("user_email") {
+            return DatabaseError::UserNameExists;
+        }
+
+        if hit("username") {
+            return DatabaseError::UsernameTaken;
+        }
+
+        if hit("phone_number") {
+            return DatabaseError::PhoneNumberTaken;
+        }
+
+        return DatabaseError::UserUuidExists;
+    }
+
+    fn corrupt(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> DatabaseError {
+        return DatabaseError::failure(FailureKind::Permanent, error);
+    }
+
+    fn user_from_row(row: UserRow) -> Result<User, DatabaseError> {
+
+        let (user_uuid, user_email, username, username_skeleton, phone_number, user_state, last_login, user_claims, profile) = row;
+
+        let user_state = UserState::from_str(&user_state).map_err(SqlRepo::corrupt)?;
+        let last_login = last_login.parse::<i64>().map_err(SqlRepo::corrupt)?;
+        let user_claims = serde_json::from_str::<Claims>(&user_claims).map_err(SqlRepo::corrupt)?;
+        let profile = serde_json::from_str::<UserProfile>(&profile).map_err(SqlRepo::corrupt)?;
+
+        return Ok(User {
+            user_uuid,
+            user_email,
+            username: Some(username).filter(|username| !username.is_empty()),
+            username_skeleton: Some(username_skeleton).filter(|skeleton| !skeleton.is_empty()),
+            phone_number: Some(phone_number).filter(|phone_number| !phone_number.is_empty()),
+            user_state,
+            last_login: DateTime::from_millis(last_login),
+            user_claims,
+            profile,
+            // the table layout is versioned by the sqlx migrations instead
+            schema_version: SCHEMA_VERSION,
+        });
+
+    }
+
+    // Rewrites user_email and the user_name claim in canonical_email form.
+    // Accounts that would end up on the same address are all reported and
+    // nothing is written, they have to be merged by hand first.
+    async fn canonicalise_emails(&self) -> Result<(), DatabaseError> {
+
+        let mut transaction = self.pool.begin().await.map_err(SqlRepo::failure)?;
+
+        let rows = sqlx::query_as::<_, (String, String, String)>("SELECT user_uuid, user_email, user_claims FROM users")
+            .fetch_all(&mut *transaction)
+            .await
+            .map_err(SqlRepo::failure)?;
+
+        let mut owners: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+
+        for (user_uuid, user_email, _) in &rows {
+            owners.entry(canonical_email(user_email)).or_default().push(user_uuid);
+        }
+
+        let clashes: Vec<String> = owners.iter()
+            .filter(|(_, user_uuids)| user_uuids.len() > 1)
+            .map(|(email, user_uuids)| format!("{} ({})", email, user_uuids.join(", ")))
+            .collect();
+
+        if !clashes.is_empty() {
+            for clash in &clashes {
+                tracing::error!(%clash, "accounts share a canonical email");
+            }
+            return Err(SqlRepo::corrupt(format!(
+                "accounts share a canonical email, merge them by hand and migrate again: {}",
+                clashes.join("; ")
+            )));
+        }
+
+        for (user_uuid, user_email, user_claims) in &rows {
+
+            let mut claims = serde_json::from_str::<serde_json::Value>(user_claims).map_err(SqlRepo::corrupt)?;
+
+            if let Some(user_name) = claims.get_mut("user_name") {
+                if let Some(name) = user_name.as_str() {
+                    *user_name = serde_json::Value::String(canonical_email(name));
+                }
+            }
+
+            let canonical = canonical_email(user_email);
+            let canonical_claims = serde_json::to_string(&claims).map_err(SqlRepo::corrupt)?;
+
+            if canonical == *user_email && canonical_claims == *user_claims {
+                continue;
+            }
+
+            sqlx::query("UPDATE users SET user_email = $1, user_claims = $2 WHERE user_uuid = $3")
+                .bind(canonical)
+                .bind(canonical_claims)
+                .bind(user_uuid.clone())
+                .execute(&mut *transaction)
+                .await
+                .map_err(SqlRepo::failure)?;
+        }
+
+        transaction.commit().await.map_err(SqlRepo::failure)?;
+
+        return Ok(());
+
+    }
+
+    async fn find_user(&self, column: &str, value: String) -> Result<Option<User>, DatabaseError> {
+
+        let query = format!("SELECT {} FROM users WHERE {} = $1", USER_COLUMNS, column);
+
+        let row = sqlx::query_as::<_, UserRow>(&query)
+            .bind(value)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(SqlRepo::failure)?;
+
+        return row.map(SqlRepo::user_from_row).transpose();
+
+    }
+
+    async fn insert_user_row<'e, E>(executor: E, user: &User) -> Result<(), DatabaseError>
+    where
+        E: Executor<'e, Database = Any>,
+    {
+
+        let user_claims = serde_json::to_string(&user.user_claims).map_err(SqlRepo::corrupt)?;
+        let profile = serde_json::to_string(&user.profile).map_err(SqlRepo::corrupt)?;
+
+        let insert = sqlx::query(
+            "INSERT INTO users (user_uuid, user_email, username, username_skeleton, phone_number, user_state, last_login, user_claims, profile) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)"
+        )
+            .bind(user.user_uuid.clone())
+            .bind(user.user_email.clone())
+            .bind(user.username.clone())
+            .bind(user.username_skeleton.clone())
+            .bind(user.phone_number.clone())
+            .bind(user.user_state.to_string())
+            .bind(user.last_login.timestamp_millis())
+            .bind(user_claims)
+            .bind(profile)
+            .execute(executor)
+            .await;
+
+        match insert {
+            Ok(_) => return Ok(()),
+            Err(sqlx::Error::Database(err)) if err.is_unique_violation() => return Err(SqlRepo::user_unique_violation(err.as_ref())),
+            Err(error) => return Err(SqlRepo::failure(error)),
+        }
+
+    }
+
+    async fn insert_credentail_row<'e, E>(executor: E, credentail: &UserCredentail) -> Result<(), DatabaseError>
+    where
+        E: Executor<'e, Database = Any>,
+    {
+
+        let credentail_json = serde_json::to_string(credentail).map_err(SqlRepo::corrupt)?;
+
+        let insert = sqlx::query("INSERT INTO credentails (user_uuid, credentail) VALUES ($1, $2)")
+            .bind(credentail.user_uuid.clone())
+            .bind(credentail_json)
+            .execute(executor)
+            .await;
+
+        match insert {
+            Ok(_) => return Ok(()),
+            Err(sqlx::Error::Database(err)) if err.is_unique_violation() => return Err(DatabaseError::UserUuidExists),
+            Err(sqlx::Error::Database(err)) if err.is_foreign_key_violation() => return Err(DatabaseError::UserDoesntExist),
+            Err(error) => return Err(SqlRepo::failure(error)),
+        }
+
+    }
+
+    async fn update_user_row<'e, E>(executor: E, user: &User) -> Result<(), DatabaseError>
+    where
+        E: Executor<'e, Database = Any>,
+    {
+
+        let user_claims = serde_json::to_string(&user.user_claims).map_err(SqlRepo::corrupt)?;
+        let profile = serde_json::to_string(&user.profile).map_err(SqlRepo::corrupt)?;
+
+        let update = sqlx::query(
+            "UPDATE users SET user_email = $2, username = $3, username_skeleton = $4, phone_number = $5, user_state = $6, last_login = $7, user_claims = $8, profile = $9 WHERE user_uuid = $1"
+        )
+            .bind(user.user_uuid.clone())
+            .bind(user.user_email.clone())
+            .bind(user.username.clone())
+            .bind(user.username_skeleton.clone())
+            .bind(user.phone_number.clone())
+            .bind(user.user_state.to_string())
+            .bind(user.last_login.timestamp_millis())
+            .bind(user_claims)
+            .bind(profile)
+            .execute(executor)
+            .await;
+
+        match update {
+            Ok(result) if result.rows_affected() == 0 => return Err(DatabaseError::UserDoesntExist),
+            Ok(_) => return Ok(()),
+            Err(sqlx::Error::Database(err)) if err.is_unique_violation() => return Err(SqlRepo::user_unique_violation(err.as_ref())),
+            Err(error) => return Err(SqlRepo::failure(error)),
+        }
+
+    }
+
+    // Read, change and write back the credentail, but only if nobody wrote
+    // it in between, otherwise start over. The credentail is a JSON column so
+    // there is no field to put a condition on, the whole text is compared.
+    // change returns None to leave the credentail as it is.
+    async fn swap_credentail<T>(
+        &self,
+        user_uuid: &str,
+        change: impl Fn(&mut UserCredentail) -> Option<T> + Send,
+    ) -> Result<Option<T>, DatabaseError> {
+
+        for _ in 0..SWAP_ATTEMPTS {
+
+            let row = sqlx::query_as::<_, (String,)>("SELECT credentail FROM credentails WHERE user_uuid = $1")
+                .bind(user_uuid.to_owned())
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(SqlRepo::failure)?;
+
+            let old = match row {
+                Some((old,)) => old,
+                None => return Ok(None),
+            };
+
+            let mut credentail = serde_json::from_str::<UserCredentail>(&old).map_err(SqlRepo::corrupt)?;
+
+            let result = match change(&mut credentail) {
+                Some(result) => result,
+                None => return Ok(None),
+            };
+
+            let new = serde_json::to_string(&credentail).map_err(SqlRepo::corrupt)?;
+
+            let update = sqlx::query("UPDATE credentails SET credentail = $1 WHERE user_uuid = $2 AND credentail = $3")
+                .bind(new)
+                .bind(user_uuid.to_owned())
+                .bind(old)
+                .execute(&self.pool)
+                .await
+                .map_err(SqlRepo::failure)?;
+
+            if update.rows_affected() == 1 {
+                return Ok(Some(result));
+            }
+        }
+
+        return Err(DatabaseError::failure(FailureKind::Transient, "credentail kept changing under a conditional write"));
+
+    }
+
+    async fn update_credentail_row<'e, E>(executor: E, credentail: &UserCredentail) -> Result<(), DatabaseError>
+    where
+        E: Executor<'e, Database = Any>,
+    {
+
+        let credentail_json = serde_json::to_string(credentail).map_err(SqlRepo::corrupt)?;
+
+        let update = sqlx::query("UPDATE credentails SET credentail = $2 WHERE user_uuid = $1")
+            .bind(credentail.user_uuid.clone())
+            .bind(credentail_json)
+            .execute(executor)
+            .await
+            .map_err(SqlRepo::failure)?;
+
+        if update.rows_affected() == 0 {
+            return Err(DatabaseError::UserDoesntExist);
+        }
+
+        return Ok(());
+
+    }
+}
+
+#[async_trait]
+impl BaseDatabase for SqlRepo {
+
+    async fn init (
+        connection_url: String,
+        _database: String
+    ) -> Result<SqlRepo, DatabaseError> {
+        sqlx::any::install_default_drivers();
+
+        let pool = AnyPool::connect(&connection_url).await.map_err(SqlRepo::failure)?;
+
+        return Ok(SqlRepo{
+            pool
+        });
+    }
+
+    async fn health_check(&self) -> Result<(), DatabaseError> {
+
+        sqlx::query("SELECT 1").execute(&self.pool).await.map_err(SqlRepo::failure)?;
+
+        return Ok(());
+
+    }
+
+    async fn migrate(&self) -> Result<Vec<String>, DatabaseError> {
+
+        let mut connection = self.pool.acquire().await.map_err(SqlRepo::failure)?;
+
+        connection.ensure_migrations_table().await.map_err(SqlRepo::migrate_failure)?;
+
+        let applied = connection.list_applied_migrations().await.map_err(SqlRepo::migrate_failure)?;
+
+        drop(connection);
+
+        // on an empty database the users table only appears with the first migration
+        let canonical_email_pending = !applied.iter().any(|done| done.version == CANONICAL_EMAIL_MIGRATION);
+
+        if canonical_email_pending && !applied.is_empty() {
+            self.canonicalise_emails().await?;
+        }
+
+        MIGRATOR.run(&self.pool).await.map_err(SqlRepo::migrate_failure)?;
+
+        let ran = MIGRATOR.iter()
+            .filter(|migration| !applied.iter().any(|done| done.version == migration.version))
+            .map(|migration| format!("{} {}", migration.version, migration.description))
+            .collect();
+
+        return Ok(ran);
+
+    }
+
+    async fn get_user(&self, user_uudi: String) -> Result<Option<User>, DatabaseError> {
+        return self.find_user("user_uuid", user_uudi).await;
+    }
+
+    async fn get_users(&self, user_uuids: Vec<String>) -> Result<Vec<User>, DatabaseError> {
+
+        if user_uuids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let placeholders: Vec<String> = (1..=user_uuids.len()).map(|n| format!("${}", n)).collect();
+        let query = format!("SELECT {} FROM users WHERE user_uuid IN ({})", USER_COLUMNS, placeholders.join(", "));
+
+        let mut select = sqlx::query_as::<_, UserRow>(&query);
+
+        for user_uuid in user_uuids {
+            select = select.bind(user_uuid);
+        }
+
+        let rows = select.fetch_all(&self.pool).await.map_err(SqlRepo::failure)?;
+
+        return rows.into_iter().map(SqlRepo::user_from_row).collect();
+
+    }
+
+    async fn get_credentail(&self, user_uudi: String) -> Result<Option<UserCredentail>, DatabaseError> {
+
+        let row = sqlx::query_as::<_, (String,)>("SELECT credentail FROM credentails WHERE user_uuid = $1")
+            .bind(user_uudi)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(SqlRepo::failure)?;
+
+        match row {
+            Some((credentail,)) => return serde_json::from_str(&credentail).map(Some).map_err(SqlRepo::corrupt),
+            None => return Ok(None),
+        }
+
+    }
+
+    async fn get_user_by_email(&self, user_email: String) -> Result<Option<User>, DatabaseError> {
+        return self.find_user("user_email", user_email).await;
+    }
+
+    async fn get_user_by_username(&self, username: String) -> Result<Option<User>, DatabaseError> {
+        return self.find_user("username", username).await;
+    }
+
+    async fn get_user_by_phone_number(&self, phone_number: String) -> Result<Option<User>, DatabaseError> {
+        return self.find_user("phone_number", phone_number).await;
+    }
+
+    async fn insert_user(&self, user: User) -> Result<User, DatabaseError> {
+
+        SqlRepo::insert_user_row(&self.pool, &user).await?;
+
+        return Ok(user);
+
+    }
+
+    async fn insert_credentail(&self, credentail: UserCredentail) -> Result<bool, DatabaseError> {
+
+        SqlRepo::insert_credentail_row(&self.pool, &credentail).await?;
+
+        return Ok(true);
+
+    }
+
+    async fn insert_user_with_credentail(&self, user: User, credentail: UserCredentail) -> Result<User, DatabaseError> {
+
+        let mut transaction = self.pool.begin().await.map_err(SqlRepo::failure)?;
+
+        // dropping the transaction on an early return rolls it back
+        SqlRepo::insert_user_row(&mut *transaction, &user).await?;
+        SqlRepo::insert_credentail_row(&mut *transaction, &credentail).await?;
+
+        transaction.commit().await.map_err(SqlRepo::failure)?;
+
+        return Ok(user);
+
+    }
+
+    async fn delete_user(&self, user: User) -> Result<User, DatabaseError> {
+
+        // credentails are removed by the ON DELETE CASCADE on their foreign key
+        let delete = sqlx::query("DELETE FROM users WHERE user_uuid = $1")
+            .bind(user.user_uuid.clone())
+            .execute(&self.pool)
+            .await;
+
+        match delete {
+            Ok(result) if result.rows_affected() == 0 => return Err(DatabaseError::UserDoesntExist),
+            Ok(_) => return Ok(user),
+            Err(error) => return Err(SqlRepo::failure(error)),
+        }
+
+    }
+
+    async fn update_user(&self, user: User) -> Result<User, DatabaseError> {
+
+        SqlRepo::update_user_row(&self.pool, &user).await?;
+
+        return Ok(user);
+
+    }
+
+    async fn set_last_login(&self, user_uuid: String, last_login: DateTime) -> Result<(), DatabaseError> {
+
+        let update = sqlx::query("UPDATE users SET last_login = $2 WHERE user_uuid = $1")
+            .bind(user_uuid)
+            .bind(last_login.timestamp_millis())
+            .execute(&self.pool)
+            .await;
+
+        match update {
+            Ok(result) if result.rows_affected() == 0 => return Err(DatabaseError::UserDoesntExist),
+            Ok(_) => return Ok(()),
+            Err(error) => return Err(SqlRepo::failure(error)),
+        }
+
+    }
+
+    async fn update_user_with_credentail(&self, user: User, credentail: UserCredentail) -> Result<User, DatabaseError> {
+
+        let mut transaction = self.pool.begin().await.map_err(SqlRepo::failure)?;
+
+        SqlRepo::update_user_row(&mut *transaction, &user).await?;
+        SqlRepo::update_credentail_row(&mut *transaction, &credentail).await?;
+
+        transaction.commit().await.map_err(SqlRepo::failure)?;
+
+        return Ok(user);
+
+    }
+
+    async fn update_credentail(&self, credentail: UserCredentail) -> Result<UserCredentail, DatabaseError> {
+
+        SqlRepo::update_credentail_row(&self.pool, &credentail).await?;
+
+        return Ok(credentail);
+
+    }
+
+    async fn take_code_attempt(&self, user_uuid: String, slot: CodeSlot, issued_at: DateTime, max_attempts: u32) -> Result<bool, DatabaseError> {
+
+        let taken = self.swap_credentail(&user_uuid, |credentail| {
+            let code = slot.code_mut(credentail).filter(|code| code.issued_at == issued_at && code.attempts < max_attempts)?;
+            code.attempts += 1;
+            return Some(());
+        }).await?;
+
+        return Ok(taken.is_some());
+
+    }
+
+    async fn spend_code(&self, user_uuid: String, slot: CodeSlot, issued_at: DateTime) -> Result<bool, DatabaseError> {
+
+        let spent = self.swap_credentail(&user_uuid, |credentail| {
+            slot.code_mut(credentail).filter(|code| code.issued_at == issued_at)?;
+            slot.clear(credentail);
+            return Some(());
+        }).await?;
+
+        return Ok(spent.is_some());
+
+    }
+
+    async fn take_reauth_attempt(&self, user_uuid: String, window_start: DateTime, max_attempts: u32) -> Result<bool, DatabaseError> {
+
+        let taken = self.swap_credentail(&user_uuid, |credentail| {
+            return credentail.take_reauth_attempt(window_start, max_attempts).then_some(());
+        }).await?;
+
+        return Ok(taken.is_some());
+
+    }
+
+    async fn take_totp_attempt(&self, user_uuid: String, slot: SecretSlot, window_start: DateTime, max_attempts: u32) -> Result<bool, DatabaseError> {
+
+        let taken = self.swap_credentail(&user_uuid, |credentail| {
+            return credentail.take_totp_attempt(&slot, window_start, max_attempts).then_some(());
+        }).await?;
+
+        return Ok(taken.is_some());
+
+    }
+
+    async fn claim_totp_step(&self, user_uuid: String, factor_id: String, step: u64) -> Result<bool, DatabaseError> {
+
+        let claimed = self.swap_credentail(&user_uuid, |credentail| {
+            let factor = credentail.mfa_factors.iter_mut().find(|factor| factor.factor_id == factor_id)?;
+            return factor.claim_totp_step(step).then_some(());
+        }).await?;
+
+        return Ok(claimed.is_some());
+
+    }
+
+    async fn swap_secret(&self, user_uuid: String, slot: SecretSlot, old: StoredSecret, new: StoredSecret) -> Result<bool, DatabaseError> {
+
+        let swapped = self.swap_credentail(&user_uuid, |credentail| {
+            return credentail.swap_secret(&slot, &old, new.clone()).then_some(());
+        }).await?;
+
+        return Ok(swapped.is_some());
+
+    }
+
+    async fn list_users(&self, skip: u64, limit: u64) -> Result<Vec<User>, DatabaseError> {
+
+        let query = format!("SELECT {} FROM users ORDER BY user_email LIMIT $1 OFFSET $2", USER_COLUMNS);
+
+        let rows = sqlx::query_as::<_, UserRow>(&query)
+            .bind(limit as i64)
+            .bind(skip as i64)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(SqlRepo::failure)?;
+
+        return rows.into_iter().map(SqlRepo::user_from_row).collect();
+
+    }
+
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::username::LoginName;
+    use std::borrow::Cow;
+
+    async fn repo() -> SqlRepo {
+        let path = std::env::temp_dir().join(format!("userauth-{}.db", uuid::Uuid::new_v4()));
+        return SqlRepo::init(format!("sqlite://{}?mode=rwc", path.display()), String::new()).await.unwrap();
+    }
+
+    // the schema as it was before the canonical_email migration
+    async fn before_canonical_email(repo: &SqlRepo) {
+        let migrator = Migrator {
+            migrations: Cow::Owned(MIGRATOR.iter().filter(|migration| migration.version < CANONICAL_EMAIL_MIGRATION).cloned().collect()),
+            ..Migrator::DEFAULT
+        };
+        migrator.run(&repo.pool).await.unwrap();
+    }
+
+    async fn insert_raw(repo: &SqlRepo, user_uuid: &str, user_email: &str) {
+        let user_claims = format!(r#"{{"user_type":"User","user_uuid":"{}","user_name":"{}","group_uuid":[]}}"#, user_uuid, user_email);
+        sqlx::query("INSERT INTO users (user_uuid, user_email, user_state, last_login, user_claims) VALUES ($1, $2, 'Active', 0, $3)")
+            .bind(user_uuid)
+            .bind(user_email)
+            .bind(user_claims)
+            .execute(&repo.pool)
+            .await
+            .unwrap();
+    }
+
+    async fn migrated_user(repo: &SqlRepo) -> (User, UserCredentail) {
+        repo.migrate().await.unwrap();
+        let user = User::new("bob@example.org".to_owned());
+        let credentail = UserCredentail::new(user.clone(), "correct horse".to_owned(), 4);
+        repo.insert_user_with_credentail(user.clone(), credentail.clone()).await.unwrap();
+        return (user, credentail);
+    }
+
+    #[actix_web::test]
+    async fn a_login_name_without_an_at_falls_back_to_the_email() {
+        let repo = repo().await;
+        repo.migrate().await.unwrap();
+
+        // signed up before emails were checked
+        let user = User::new("Bob".to_owned());
+        let credentail = UserCredentail::new(user.clone(), "correct horse".to_owned(), 4);
+        repo.insert_user_with_credentail(user.clone(), credentail).await.unwrap();
+
+        let found = repo.get_user_by_login_name(LoginName::parse("bob")).await.unwrap().unwrap();
+        assert_eq!(found.user_uuid, user.user_uuid);
+
+        assert!(repo.get_user_by_login_name(LoginName::parse("alice")).await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn set_last_login_leaves_the_rest_of_the_user() {
+        let repo = repo().await;
+        let (user, _) = migrated_user(&repo).await;
+
+        // a change made after the login read the user
+        let mut changed = user.clone();
+        changed.phone_number = Some("+15550109999".to_owned());
+        repo.update_user(changed).await.unwrap();
+
+        let last_login = DateTime::from_millis(user.last_login.timestamp_millis() + 60 * 1000);
+        repo.set_last_login(user.user_uuid.clone(), last_login).await.unwrap();
+
+        let stored = repo.get_user(user.user_uuid.clone()).await.unwrap().unwrap();
+        assert_eq!(stored.last_login, last_login);
+        assert_eq!(stored.phone_number.as_deref(), Some("+15550109999"));
+
+        assert!(matches!(repo.set_last_login("no-such-user".to_owned(), last_login).await, Err(DatabaseError::UserDoesntExist)));
+        assert!(matches!(repo.update_user(User::new("alice@example.org".to_owned())).await, Err(DatabaseError::UserDoesntExist)));
+    }
+
+    #[actix_web::test]
+    async fn update_user_removes_a_deleted_phone_number() {
+        let repo = repo().await;
+        let (mut user, credentail) = migrated_user(&repo).await;
+
+        user.phone_number = Some("+15550109999".to_owned());
+        repo.update_user(user.clone()).await.unwrap();
+
+        user.phone_number = None;
+        repo.update_user_with_credentail(user.clone(), credentail).await.unwrap();
+
+        let stored = repo.get_user(user.user_uuid.clone()).await.unwrap().unwrap();
+        assert_eq!(stored.phone_number, None);
+        assert!(repo.get_user_by_phone_number("+15550109999".to_owned()).await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn reauth_attempts_stop_at_the_limit() {
+        let repo = repo().await;
+        let (user, _) = migrated_user(&repo).await;
+        let window_start = DateTime::from_millis(DateTime::now().timestamp_millis() - 60 * 1000);
+
+        assert!(repo.take_reauth_attempt(user.user_uuid.clone(), window_start, 2).await.unwrap());
+        assert!(repo.take_reauth_attempt(user.user_uuid.clone(), window_start, 2).await.unwrap());
+        assert!(!repo.take_reauth_attempt(user.user_uuid.clone(), window_start, 2).await.unwrap());
+
+        let stored = repo.get_credentail(user.user_uuid.clone()).await.unwrap().unwrap();
+        assert_eq!(stored.reauth_attempts.unwrap().attempts, 2);
+    }
+
+    #[actix_web::test]
+    async fn a_secret_is_only_swapped_if_unchanged() {
+        let repo = repo().await;
+        let (user, mut credentail) = migrated_user(&repo).await;
+
+        let plaintext = crate::secrets::SecretKeys::from_config(&crate::config::SecretsConfig::default());
+        credentail.import_totp(&crate::model::totp::generate_secret(), Default::default(), None, &plaintext).ok().unwrap();
+        repo.update_credentail(credentail.clone()).await.unwrap();
+
+        let keys = crate::secrets::SecretKeys::from_config(&crate::config::SecretsConfig {
+            keys: [("2026-10".to_owned(), "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=".to_owned())].into(),
+            active_key: "2026-10".to_owned(),
+            allow_plaintext: false,
+        });
+
+        let (slot, old, new) = credentail.resealed_secrets(&keys).unwrap().remove(0);
+        assert!(repo.swap_secret(user.user_uuid.clone(), slot.clone(), old.clone(), new.clone()).await.unwrap());
+        // a second run read the same old secret, it's gone now
+        assert!(!repo.swap_secret(user.user_uuid.clone(), slot, old, new).await.unwrap());
+
+        let stored = repo.get_credentail(user.user_uuid.clone()).await.unwrap().unwrap();
+        assert!(stored.resealed_secrets(&keys).unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn totp_attempts_stop_at_the_limit() {
+        let repo = repo().await;
+        let (user, mut credentail) = migrated_user(&repo).await;
+        let window_start = DateTime::from_millis(DateTime::now().timestamp_millis() - 60 * 1000);
+
+        let keys = crate::secrets::SecretKeys::from_config(&crate::config::SecretsConfig::default());
+        let factor_id = credentail.import_totp(&crate::model::totp::generate_secret(), Default::default(), None, &keys).ok().unwrap();
+        credentail.begin_totp(&crate::config::MfaConfig::default(), &keys);
+        repo.update_credentail(credentail).await.unwrap();
+
+        for slot in [SecretSlot::Factor(factor_id.clone()), SecretSlot::TotpEnrollment] {
+            assert!(repo.take_totp_attempt(user.user_uuid.clone(), slot.clone(), window_start, 2).await.unwrap());
+            assert!(repo.take_totp_attempt(user.user_uuid.clone(), slot.clone(), window_start, 2).await.unwrap());
+            assert!(!repo.take_totp_attempt(user.user_uuid.clone(), slot, window_start, 2).await.unwrap());
+        }
+
+        assert!(!repo.take_totp_attempt(user.user_uuid.clone(), SecretSlot::Factor("no-such-factor".to_owned()), window_start, 2).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn a_totp_step_is_claimed_once() {
+        let repo = repo().await;
+        let (user, mut credentail) = migrated_user(&repo).await;
+
+        let keys = crate::secrets::SecretKeys::from_config(&crate::config::SecretsConfig::default());
+        let factor_id = credentail.import_totp(&crate::model::totp::generate_secret(), Default::default(), None, &keys).ok().unwrap();
+        repo.update_credentail(credentail).await.unwrap();
+
+        assert!(repo.claim_totp_step(user.user_uuid.clone(), factor_id.clone(), 10).await.unwrap());
+        assert!(!repo.claim_totp_step(user.user_uuid.clone(), factor_id.clone(), 10).await.unwrap());
+        assert!(!repo.claim_totp_step(user.user_uuid.clone(), factor_id.clone(), 9).await.unwrap());
+        assert!(repo.claim_totp_step(user.user_uuid.clone(), factor_id.clone(), 11).await.unwrap());
+        assert!(!repo.claim_totp_step(user.user_uuid.clone(), "no-such-factor".to_owned(), 12).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn code_attempts_stop_at_the_limit() {
+        let repo = repo().await;
+        let (user, mut credentail) = migrated_user(&repo).await;
+
+        credentail.issue_mfa_code(None, &crate::config::MfaConfig::default()).ok().unwrap();
+        let issued_at = credentail.mfa_code.as_ref().unwrap().issued_at;
+        repo.update_credentail(credentail).await.unwrap();
+
+        for _ in 0..3 {
+            assert!(repo.take_code_attempt(user.user_uuid.clone(), CodeSlot::MfaCode, issued_at, 3).await.unwrap());
+        }
+        assert!(!repo.take_code_attempt(user.user_uuid.clone(), CodeSlot::MfaCode, issued_at, 3).await.unwrap());
+
+        let stored = repo.get_credentail(user.user_uuid.clone()).await.unwrap().unwrap();
+        assert_eq!(stored.mfa_code.unwrap().attempts, 3);
+    }
+
+    #[actix_web::test]
+    async fn code_attempts_need_the_same_code() {
+        let repo = repo().await;
+        let (user, _) = migrated_user(&repo).await;
+
+        // nothing issued yet
+        assert!(!repo.take_code_attempt(user.user_uuid.clone(), CodeSlot::MfaCode, DateTime::now(), 3).await.unwrap());
+        assert!(!repo.spend_code(user.user_uuid.clone(), CodeSlot::MfaCode, DateTime::now()).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn a_code_is_spent_once() {
+        let repo = repo().await;
+        let (user, mut credentail) = migrated_user(&repo).await;
+
+        credentail.issue_mfa_code(Some("factor".to_owned()), &crate::config::MfaConfig::default()).ok().unwrap();
+        let issued_at = credentail.mfa_code.as_ref().unwrap().issued_at;
+        repo.update_credentail(credentail).await.unwrap();
+
+        assert!(repo.spend_code(user.user_uuid.clone(), CodeSlot::MfaCode, issued_at).await.unwrap());
+        assert!(!repo.spend_code(user.user_uuid.clone(), CodeSlot::MfaCode, issued_at).await.unwrap());
+
+        let stored = repo.get_credentail(user.user_uuid.clone()).await.unwrap().unwrap();
+        assert!(stored.mfa_code.is_none());
+        assert!(stored.mfa_code_factor.is_none());
+    }
+
+    #[actix_web::test]
+    async fn migrate_canonicalises_existing_emails() {
+        let repo = repo().await;
+        before_canonical_email(&repo).await;
+        insert_raw(&repo, "a", "Ｂob@Example.org").await;
+        insert_raw(&repo, "b", "carol@example.org").await;
+
+        repo.migrate().await.unwrap();
+
+        let user = repo.get_user("a".to_owned()).await.unwrap().unwrap();
+        assert_eq!(user.user_email, "bob@example.org");
+        assert_eq!(user.user_claims.user_name, "bob@example.org");
+        assert_eq!(repo.get_user("b".to_owned()).await.unwrap().unwrap().user_email, "carol@example.org");
+    }
+
+    #[actix_web::test]
+    async fn migrate_refuses_emails_that_collide_once_canonical() {
+        let repo = repo().await;
+        before_canonical_email(&repo).await;
+        insert_raw(&repo, "a", "ﬁona@example.org").await;
+        insert_raw(&repo, "b", "FIONA@example.org").await;
+
+        let error = repo.migrate().await.unwrap_err();
+
+        assert!(format!("{:?}", error).contains("fiona@example.org (a, b)"));
+        // nothing was rewritten and the migration is still pending
+        let (user_email,) = sqlx::query_as::<_, (String,)>("SELECT user_email FROM users WHERE user_uuid = 'b'")
+            .fetch_one(&repo.pool)
+            .await
+            .unwrap();
+        assert_eq!(user_email, "FIONA@example.org");
+    }
+}
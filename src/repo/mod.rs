@@ -1 +1,3 @@
-pub mod database;
\ No newline at end of file
+pub mod database;
+pub mod bulk;
+pub mod blob;
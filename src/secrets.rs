@@ -0,0 +1,218 @@
+use crate::config::SecretsConfig;
+
+use std::collections::HashMap;
+use std::fmt;
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::{Serialize, Deserialize};
+
+// Encryption of the MFA secrets stored with credentails, AES-256-GCM with
+// the data keys in [secrets]. Every ciphertext names the key it was made
+// with so keys can be rotated while running: secrets under an older key are
+// re-encrypted with the active one whenever they are used, and
+// `userauth-admin reencrypt-secrets` does the rest.
+//
+// The user_uuid is bound in as associated data, a ciphertext copied onto
+// another credentail doesn't decrypt.
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum StoredSecret {
+    Encrypted {
+        key_id: String,
+        // base64
+        nonce: String,
+        ciphertext: String,
+    },
+    // written without an active key, or before there were keys
+    Plain(String),
+}
+
+#[derive(Debug)]
+pub enum SecretError {
+    // the key was removed from [secrets] while something still used it
+    UnknownKey(String),
+    Decrypt,
+}
+
+impl fmt::Display for SecretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretError::UnknownKey(key_id) => write!(f, "secret is encrypted with unknown key {}", key_id),
+            SecretError::Decrypt => write!(f, "secret could not be decrypted"),
+        }
+    }
+}
+
+pub struct SecretKeys {
+    keys: HashMap<String, Aes256Gcm>,
+    active_key: Option<String>,
+}
+
+impl SecretKeys {
+
+    // The config is validated, every key is 32 bytes of base64.
+    pub fn from_config(config: &SecretsConfig) -> SecretKeys {
+
+        let keys = config.keys.iter()
+            .filter_map(|(key_id, key)| {
+                let bytes = STANDARD.decode(key).ok()?;
+                return Some((key_id.clone(), Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&bytes))));
+            })
+            .collect();
+
+        return SecretKeys {
+            keys,
+            active_key: Some(config.active_key.clone()).filter(|active_key| !active_key.is_empty()),
+        };
+    }
+
+    pub fn active_key(&self) -> Option<&str> {
+        return self.active_key.as_deref();
+    }
+
+    // context is the user_uuid the secret belongs to.
+    pub fn seal(&self, plaintext: &str, context: &str) -> StoredSecret {
+
+        let (key_id, cipher) = match self.active_key.as_ref().and_then(|key_id| Some((key_id, self.keys.get(key_id)?))) {
+            Some(active) => active,
+            None => return StoredSecret::Plain(plaintext.to_owned()),
+        };
+
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+        let ciphertext = cipher.encrypt(&nonce, Payload { msg: plaintext.as_bytes(), aad: context.as_bytes() })
+            .expect("encrypting a short secret can't fail");
+
+        return StoredSecret::Encrypted {
+            key_id: key_id.clone(),
+            nonce: STANDARD.encode(nonce),
+            ciphertext: STANDARD.encode(ciphertext),
+        };
+    }
+
+    pub fn open(&self, stored: &StoredSecret, context: &str) -> Result<String, SecretError> {
+
+        let (key_id, nonce, ciphertext) = match stored {
+            StoredSecret::Encrypted { key_id, nonce, ciphertext } => (key_id, nonce, ciphertext),
+            StoredSecret::Plain(plaintext) => return Ok(plaintext.clone()),
+        };
+
+        let cipher = match self.keys.get(key_id) {
+            Some(cipher) => cipher,
+            None => return Err(SecretError::UnknownKey(key_id.clone())),
+        };
+
+        let nonce = STANDARD.decode(nonce).map_err(|_| SecretError::Decrypt)?;
+        let ciphertext = STANDARD.decode(ciphertext).map_err(|_| SecretError::Decrypt)?;
+
+        if nonce.len() != 12 {
+            return Err(SecretError::Decrypt);
+        }
+
+        let plaintext = cipher.decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: context.as_bytes() })
+            .map_err(|_| SecretError::Decrypt)?;
+
+        return String::from_utf8(plaintext).map_err(|_| SecretError::Decrypt);
+    }
+
+    // True when seal would store it differently: in plaintext while there is
+    // an active key, or under a key that is no longer the active one.
+    pub fn needs_reseal(&self, stored: &StoredSecret) -> bool {
+        match (stored, &self.active_key) {
+            (_, None) => return false,
+            (StoredSecret::Plain(_), Some(_)) => return true,
+            (StoredSecret::Encrypted { key_id, .. }, Some(active_key)) => return key_id != active_key,
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const USER_UUID: &str = "5f0c7e52-3c1b-4a8e-9d55-0b8c1f1e2a10";
+
+    fn keys(key_ids: &[&str], active_key: &str) -> SecretKeys {
+        let keys = key_ids.iter()
+            .enumerate()
+            .map(|(index, key_id)| (key_id.to_string(), STANDARD.encode([index as u8 + 1; 32])))
+            .collect();
+
+        return SecretKeys::from_config(&SecretsConfig { keys, active_key: active_key.to_owned(), allow_plaintext: false });
+    }
+
+    #[test]
+    fn seal_and_open_round_trip() {
+        let keys = keys(&["2026-10"], "2026-10");
+
+        let sealed = keys.seal("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", USER_UUID);
+
+        assert!(matches!(&sealed, StoredSecret::Encrypted { key_id, .. } if key_id == "2026-10"));
+        assert_eq!(keys.open(&sealed, USER_UUID).unwrap(), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        // a fresh nonce every time
+        assert_ne!(keys.seal("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", USER_UUID), sealed);
+    }
+
+    #[test]
+    fn a_secret_only_opens_for_its_user() {
+        let keys = keys(&["2026-10"], "2026-10");
+
+        let sealed = keys.seal("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", USER_UUID);
+
+        assert!(matches!(keys.open(&sealed, "8d1b3f5e-0a2c-4e6f-b8d0-1c3e5a7f9b2d"), Err(SecretError::Decrypt)));
+    }
+
+    #[test]
+    fn a_removed_key_is_unknown() {
+        let sealed = keys(&["2026-04", "2026-10"], "2026-04").seal("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", USER_UUID);
+
+        let rotated = keys(&["2026-10"], "2026-10");
+
+        assert!(matches!(rotated.open(&sealed, USER_UUID), Err(SecretError::UnknownKey(key_id)) if key_id == "2026-04"));
+    }
+
+    #[test]
+    fn needs_reseal_follows_the_active_key() {
+        let before = keys(&["2026-04", "2026-10"], "2026-04");
+        let after = keys(&["2026-04", "2026-10"], "2026-10");
+        let plaintext = keys(&[], "");
+
+        let sealed = before.seal("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", USER_UUID);
+        let plain = StoredSecret::Plain("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ".to_owned());
+
+        assert!(!before.needs_reseal(&sealed));
+        assert!(after.needs_reseal(&sealed));
+        assert!(after.needs_reseal(&plain));
+        // nothing to move to without an active key
+        assert!(!plaintext.needs_reseal(&plain));
+        assert!(!plaintext.needs_reseal(&sealed));
+        // the old key still opens it until it is resealed
+        assert_eq!(after.open(&sealed, USER_UUID).unwrap(), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+
+    #[test]
+    fn without_an_active_key_secrets_stay_plain() {
+        let keys = keys(&["2026-10"], "");
+
+        assert_eq!(keys.seal("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", USER_UUID), StoredSecret::Plain("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ".to_owned()));
+    }
+
+    #[test]
+    fn stored_secrets_are_untagged() {
+        let plain: StoredSecret = serde_json::from_value(json!("GEZDGNBVGY3TQOJQ")).unwrap();
+        assert_eq!(plain, StoredSecret::Plain("GEZDGNBVGY3TQOJQ".to_owned()));
+
+        let encrypted: StoredSecret = serde_json::from_value(json!({"key_id": "2026-10", "nonce": "bm9uY2U=", "ciphertext": "Y2lwaGVy"})).unwrap();
+        assert!(matches!(encrypted, StoredSecret::Encrypted { ref key_id, .. } if key_id == "2026-10"));
+
+        let sealed = keys(&["2026-10"], "2026-10").seal("GEZDGNBVGY3TQOJQ", USER_UUID);
+        let stored = serde_json::to_value(&sealed).unwrap();
+        assert!(stored.get("key_id").is_some());
+        assert_eq!(serde_json::from_value::<StoredSecret>(stored).unwrap(), sealed);
+        assert_eq!(serde_json::to_value(&plain).unwrap(), json!("GEZDGNBVGY3TQOJQ"));
+    }
+}
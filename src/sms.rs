@@ -0,0 +1,151 @@
+use crate::config::{SmsConfig, SmsSink};
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use bson::DateTime;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+
+// Outgoing text messages, the SMS counterpart of mailer.rs. Handlers build an
+// SmsMessage and hand it to whichever SmsProvider sms.sink selects.
+
+#[derive(Serialize)]
+pub struct SmsMessage {
+    // E.164, see user::canonical_phone_number
+    pub to: String,
+    pub body: String,
+}
+
+impl SmsMessage {
+
+    // The one text every one time code goes out with, whether it confirms a
+    // number, logs in or answers an sms factor.
+    pub fn code(to: String, code: &str, ttl_minutes: i64) -> SmsMessage {
+        return SmsMessage {
+            to,
+            body: format!("Your verification code is {}. It expires in {} minutes.", code, ttl_minutes),
+        };
+    }
+
+}
+
+#[derive(Debug)]
+pub enum SmsError {
+    Io(io::Error),
+    Http(reqwest::Error),
+    // the gateway answered with something other than 2xx
+    Rejected(u16),
+}
+
+impl fmt::Display for SmsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmsError::Io(error) => write!(f, "could not send sms: {}", error),
+            SmsError::Http(error) => write!(f, "could not reach sms gateway: {}", error),
+            SmsError::Rejected(status) => write!(f, "sms gateway answered {}", status),
+        }
+    }
+}
+
+#[async_trait]
+pub trait SmsProvider: Send + Sync {
+
+    async fn send(
+        &self,
+        message: SmsMessage
+    ) -> Result<(), SmsError>;
+
+}
+
+// Appends each message to a file, for development. Bodies hold codes, keep
+// the file as private as the phones would be.
+pub struct FileSmsProvider {
+    from: String,
+    path: PathBuf,
+}
+
+#[async_trait]
+impl SmsProvider for FileSmsProvider {
+
+    async fn send(&self, message: SmsMessage) -> Result<(), SmsError> {
+
+        let date = DateTime::now().try_to_rfc3339_string().unwrap_or_default();
+
+        let rendered = format!("From: {}\nTo: {}\nDate: {}\n\n{}\n\n", self.from, message.to, date, message.body);
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(SmsError::Io)?;
+
+        file.write_all(rendered.as_bytes()).await.map_err(SmsError::Io)?;
+
+        return Ok(());
+    }
+
+}
+
+// Posts to a JSON gateway. Most providers either take this shape directly or
+// sit behind a small relay that translates it.
+pub struct HttpSmsProvider {
+    client: reqwest::Client,
+    gateway_url: String,
+    api_key: String,
+    from: String,
+}
+
+#[derive(Serialize)]
+struct GatewayRequest<'a> {
+    from: &'a str,
+    to: &'a str,
+    body: &'a str,
+}
+
+#[async_trait]
+impl SmsProvider for HttpSmsProvider {
+
+    async fn send(&self, message: SmsMessage) -> Result<(), SmsError> {
+
+        let mut request = self.client
+            .post(&self.gateway_url)
+            .json(&GatewayRequest { from: &self.from, to: &message.to, body: &message.body });
+
+        if !self.api_key.is_empty() {
+            request = request.bearer_auth(&self.api_key);
+        }
+
+        let response = request.send().await.map_err(SmsError::Http)?;
+
+        if !response.status().is_success() {
+            return Err(SmsError::Rejected(response.status().as_u16()));
+        }
+
+        return Ok(());
+    }
+
+}
+
+pub fn open(config: &SmsConfig) -> Arc<dyn SmsProvider> {
+    match config.sink {
+        SmsSink::File => return Arc::new(FileSmsProvider { from: config.from.clone(), path: PathBuf::from(&config.file_path) }),
+        SmsSink::Http => {
+            let client = reqwest::Client::builder()
+                .timeout(Duration::from_secs(config.timeout_seconds))
+                .build()
+                .expect("tls backend is compiled in");
+
+            return Arc::new(HttpSmsProvider {
+                client,
+                gateway_url: config.gateway_url.clone(),
+                api_key: config.api_key.clone(),
+                from: config.from.clone(),
+            });
+        },
+    }
+}
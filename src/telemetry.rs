@@ -0,0 +1,129 @@
+use crate::config::{ServerConfig, TracingConfig};
+
+use std::future::Future;
+use actix_web::{
+    Error,
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::header::{HeaderMap, HeaderName, HeaderValue},
+};
+use opentelemetry::{KeyValue, global, propagation::Extractor, trace::TraceError};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{Resource, propagation::TraceContextPropagator, runtime, trace};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Longer or non printable ids from clients are replaced rather than trusted,
+// they end up in logs and in the response header.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        return self.0.get(key).and_then(|value| value.to_str().ok());
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        return self.0.keys().map(|key| key.as_str()).collect();
+    }
+}
+
+// Sets up the tracing subscriber: formatted output filtered by RUST_LOG (or
+// server.log_level), and when tracing.otlp_endpoint is set an OpenTelemetry
+// layer exporting spans over OTLP/gRPC. log records from dependencies are
+// forwarded into tracing.
+pub fn init(server: &ServerConfig, tracing_config: &TracingConfig) -> Result<(), TraceError> {
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or(EnvFilter::new(&server.log_level));
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer());
+
+    if tracing_config.otlp_endpoint.is_empty() {
+        registry.init();
+        return Ok(());
+    }
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(tracing_config.otlp_endpoint.clone())
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![
+                KeyValue::new("service.name", tracing_config.service_name.clone()),
+            ]))
+        )
+        .install_batch(runtime::Tokio)?;
+
+    registry.with(tracing_opentelemetry::layer().with_tracer(tracer)).init();
+
+    return Ok(());
+}
+
+// Flushes spans still waiting in the batch exporter.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+fn request_id_from(req: &ServiceRequest) -> String {
+
+    let incoming = req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= MAX_REQUEST_ID_LEN)
+        .filter(|value| value.chars().all(|c| c.is_ascii_graphic()));
+
+    match incoming {
+        Some(request_id) => return request_id.to_owned(),
+        None => return Uuid::new_v4().to_string(),
+    }
+}
+
+// Used with App::wrap_fn, opens the root span for the request carrying its
+// request id, continues a W3C traceparent sent by the caller, and returns
+// the id in the X-Request-Id response header.
+pub fn trace_request<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let request_id = request_id_from(&req);
+    let route = req.match_pattern().unwrap_or("unmatched".to_owned());
+
+    let span = tracing::info_span!(
+        "http_request",
+        request_id = %request_id,
+        http.method = %req.method(),
+        http.route = %route,
+        http.status_code = tracing::field::Empty,
+    );
+
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(req.headers())));
+    span.set_parent(parent);
+
+    let response = span.in_scope(|| srv.call(req));
+    let request_span = span.clone();
+
+    async move {
+        let mut response = response.await?;
+
+        request_span.record("http.status_code", response.status().as_u16());
+
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            response.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+        }
+
+        return Ok(response);
+    }.instrument(span)
+}